@@ -0,0 +1,269 @@
+// Measures how pulls from the in-memory store scale with the number of
+// clients pulling in parallel, and how much a large upload in progress slows
+// them down. The registry is run from its binary and pulled from over HTTP.
+//
+// The benchmark fails if pulls do not scale with the CPUs available, or if
+// the upload slows them down by more than half. A store behind a single lock
+// fails both: parallel pulls wait on each other, and every pull waits for
+// the upload to finish, which only happens once pulls have been measured.
+//
+// Run with: cargo bench --bench parallel_pulls
+
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use sha2::{Digest, Sha256};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const REPO: &str = "bench/pull";
+// Pulls made at each level of parallelism, half manifests and half blobs.
+const PULLS: usize = 4000;
+const CLIENTS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+const BLOB_SIZE: usize = 64 * 1024;
+// Written to an upload every millisecond while pulls are measured.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+type HttpClient = Client<HttpConnector>;
+
+// Kills the registry when the benchmark exits, even if it panics.
+struct Registry(Child);
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_registry() -> (Registry, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("could not find a free port");
+    let child = Command::new(env!("CARGO_BIN_EXE_eocker-registry"))
+        .args(["--listen", &addr.to_string(), "--storage-driver", "memory"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("could not start the registry");
+    let registry = Registry(child);
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "registry did not start listening on {}",
+            addr
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    (registry, addr)
+}
+
+fn digest(content: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(content))
+}
+
+async fn request(
+    client: &HttpClient,
+    method: Method,
+    uri: String,
+    content_type: Option<&str>,
+    body: Body,
+) -> (StatusCode, Option<String>) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(t) = content_type {
+        req = req.header("Content-Type", t);
+    }
+    let mut res = client
+        .request(req.body(body).unwrap())
+        .await
+        .expect("request failed");
+    let location = res
+        .headers()
+        .get("Location")
+        .and_then(|l| l.to_str().ok())
+        .map(|l| l.to_string());
+    while let Some(chunk) = res.body_mut().data().await {
+        chunk.expect("could not read response");
+    }
+    (res.status(), location)
+}
+
+async fn start_upload(client: &HttpClient, base: &str) -> String {
+    let (status, location) = request(
+        client,
+        Method::POST,
+        format!("{}/v2/{}/blobs/uploads/", base, REPO),
+        None,
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    format!("{}{}", base, location.expect("upload has no location"))
+}
+
+async fn push_blob(client: &HttpClient, base: &str, content: Vec<u8>) -> String {
+    let d = digest(&content);
+    let location = start_upload(client, base).await;
+    let (status, _) = request(
+        client,
+        Method::PUT,
+        format!("{}?digest={}", location, d),
+        None,
+        Body::from(content),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    d
+}
+
+// Pushes an image and returns the paths of its manifest and layer.
+async fn push_image(client: &HttpClient, base: &str) -> (String, String) {
+    let config = b"{}".to_vec();
+    let layer: Vec<u8> = (0..BLOB_SIZE).map(|i| i as u8).collect();
+    let config_digest = push_blob(client, base, config.clone()).await;
+    let layer_digest = push_blob(client, base, layer).await;
+    let manifest = format!(
+        r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":{},"digest":"{}"}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar","size":{},"digest":"{}"}}]}}"#,
+        config.len(),
+        config_digest,
+        BLOB_SIZE,
+        layer_digest
+    );
+    let (status, _) = request(
+        client,
+        Method::PUT,
+        format!("{}/v2/{}/manifests/latest", base, REPO),
+        Some("application/vnd.oci.image.manifest.v1+json"),
+        Body::from(manifest),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    (
+        format!("{}/v2/{}/manifests/latest", base, REPO),
+        format!("{}/v2/{}/blobs/{}", base, REPO, layer_digest),
+    )
+}
+
+// Pulls the manifest and layer PULLS times in total from the given number of
+// clients. Returns how long that took.
+async fn pull(clients: usize, manifest: &str, layer: &str) -> Duration {
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| {
+            let (manifest, layer) = (manifest.to_string(), layer.to_string());
+            tokio::spawn(async move {
+                let client = Client::new();
+                for i in 0..PULLS / clients {
+                    let uri = if i % 2 == 0 { &manifest } else { &layer };
+                    let (status, _) =
+                        request(&client, Method::GET, uri.clone(), None, Body::empty()).await;
+                    assert_eq!(status, StatusCode::OK);
+                }
+            })
+        })
+        .collect();
+    for t in tasks {
+        t.await.unwrap();
+    }
+    started.elapsed()
+}
+
+// Prints the throughput of pulls and returns their speedup over the
+// baseline.
+fn report(label: &str, clients: usize, elapsed: Duration, baseline: Duration) -> f64 {
+    let pulled = PULLS / clients * clients;
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64() * pulled as f64 / PULLS as f64;
+    println!(
+        "{:<16} {:>3} clients {:>10.0} pulls/s {:>6.2}x",
+        label,
+        clients,
+        pulled as f64 / elapsed.as_secs_f64(),
+        speedup
+    );
+    speedup
+}
+
+fn main() {
+    // Only run when benchmarks are, not when cargo test builds them.
+    if !std::env::args().any(|a| a == "--bench") {
+        return;
+    }
+    let (_registry, addr) = start_registry();
+    let base = format!("http://{}", addr);
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = Client::new();
+        let (manifest, layer) = push_image(&client, &base).await;
+        // Warm up connections and allocations.
+        pull(CLIENTS[CLIENTS.len() - 1], &manifest, &layer).await;
+
+        let baseline = pull(1, &manifest, &layer).await;
+        let mut idle = vec![];
+        for clients in CLIENTS.iter() {
+            idle.push(report(
+                "pulls",
+                *clients,
+                pull(*clients, &manifest, &layer).await,
+                baseline,
+            ));
+        }
+
+        // Pulls should not wait on an upload being written to the store.
+        let location = start_upload(&client, &base).await;
+        let (mut sender, body) = Body::channel();
+        let upload = tokio::spawn({
+            let client = client.clone();
+            async move { request(&client, Method::PATCH, location, None, body).await }
+        });
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = tokio::spawn({
+            let done = done.clone();
+            async move {
+                let chunk = hyper::body::Bytes::from(vec![0u8; UPLOAD_CHUNK]);
+                while !done.load(Ordering::Relaxed) {
+                    if sender.send_data(chunk.clone()).await.is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        let mut uploading = vec![];
+        for clients in CLIENTS.iter() {
+            uploading.push(report(
+                "during upload",
+                *clients,
+                pull(*clients, &manifest, &layer).await,
+                baseline,
+            ));
+        }
+        done.store(true, Ordering::Relaxed);
+        writer.await.unwrap();
+        let (status, _) = upload.await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // The registry and the clients share the CPUs, so pulls are expected
+        // to scale with at least half of them.
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        for (i, clients) in CLIENTS.iter().enumerate() {
+            let expected = (*clients).min(cpus) as f64 / 2.0;
+            assert!(
+                idle[i] >= expected,
+                "{} clients pulled {:.2}x as fast as one, expected at least {:.2}x",
+                clients,
+                idle[i],
+                expected
+            );
+            assert!(
+                uploading[i] >= idle[i] / 2.0,
+                "{} clients pulled {:.2}x as fast during an upload as without, expected at least 0.5x",
+                clients,
+                uploading[i] / idle[i]
+            );
+        }
+    });
+}
@@ -0,0 +1,474 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::config::{AuthConfig, AuthMode};
+
+mod basic;
+mod token;
+
+pub use basic::{BasicAuth, Htpasswd};
+pub use token::{Token, TokenAuth, TokenIssuer};
+
+// Realm of Basic challenges.
+pub const REALM: &str = "eocker-registry";
+
+// Access is a set of actions on a resource, written as a scope such as
+// repository:<name>:pull,push.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl Access {
+    pub fn repository(name: &str, actions: &[&str]) -> Access {
+        Access {
+            resource_type: "repository".to_string(),
+            name: name.to_string(),
+            actions: actions.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    // Listing the catalog requires the registry:catalog:* scope.
+    pub fn catalog() -> Access {
+        Access {
+            resource_type: "registry".to_string(),
+            name: "catalog".to_string(),
+            actions: vec!["*".to_string()],
+        }
+    }
+
+    // Administration, such as garbage collection and metrics, requires the
+    // registry:admin:* scope.
+    pub fn admin() -> Access {
+        Access {
+            resource_type: "registry".to_string(),
+            name: "admin".to_string(),
+            actions: vec!["*".to_string()],
+        }
+    }
+
+    // Parses a scope. Repository names cannot contain colons, so the type and
+    // the actions are split off at the first and last colon.
+    pub fn parse(scope: &str) -> Option<Access> {
+        let (resource_type, rest) = scope.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+        Some(Access {
+            resource_type: resource_type.to_string(),
+            name: name.to_string(),
+            actions: actions
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    pub fn scope(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.resource_type,
+            self.name,
+            self.actions.join(",")
+        )
+    }
+
+    // Reports whether the access includes every action of required.
+    fn covers(&self, required: &Access) -> bool {
+        self.resource_type == required.resource_type
+            && self.name == required.name
+            && required
+                .actions
+                .iter()
+                .all(|a| self.actions.iter().any(|g| g == a || g == "*"))
+    }
+}
+
+// Reports whether the granted access covers all of the required access.
+pub fn allowed(granted: &[Access], required: &[Access]) -> bool {
+    required.iter().all(|r| granted.iter().any(|g| g.covers(r)))
+}
+
+fn scopes(access: &[Access]) -> String {
+    access
+        .iter()
+        .map(Access::scope)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Denial explains why a request was not authorized.
+#[derive(Debug)]
+pub enum Denial {
+    // The client did not provide valid credentials, or has to authenticate
+    // to be granted the access.
+    Unauthorized(String),
+    // The client authenticated but is not allowed the access.
+    Denied(String),
+}
+
+// Policy decides which access a client may be granted. Authenticated users
+// may list the catalog, and are granted the actions their rules allow in each
+// repository. Without rules, they may pull, push and delete in every
+// repository. Only users whose rules say so may administer the registry.
+// Anonymous clients may only pull and list the catalog, and only if anonymous
+// pulls are enabled.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub anonymous_pull: bool,
+    // Rules of each user, where those of * apply to every authenticated
+    // user.
+    pub rules: Option<HashMap<String, Rules>>,
+}
+
+// Rules are the access granted to a user, as read from a policy file such as
+// {"alice": {"repositories": {"team/*": ["pull", "push"]}, "admin": true}}.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    // Actions granted in the repositories matching each pattern. A pattern
+    // is a repository name, a prefix followed by /* for every repository
+    // below it, or * for every repository.
+    #[serde(default)]
+    pub repositories: BTreeMap<String, Vec<String>>,
+    // Whether the user may administer the registry.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+const REPOSITORY_ACTIONS: &[&str] = &["pull", "push", "delete"];
+
+impl Policy {
+    // Reads the rules of a policy file, which maps users to their rules.
+    pub fn open(path: &Path, anonymous_pull: bool) -> Result<Policy, String> {
+        let content =
+            std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let rules: HashMap<String, Rules> = serde_json::from_slice(&content)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        for (user, r) in &rules {
+            for (pattern, actions) in &r.repositories {
+                if let Some(a) = actions
+                    .iter()
+                    .find(|a| *a != "*" && !REPOSITORY_ACTIONS.contains(&a.as_str()))
+                {
+                    return Err(format!(
+                        "invalid {}: unknown action {} granted to {} in {}",
+                        path.display(),
+                        a,
+                        user,
+                        pattern
+                    ));
+                }
+            }
+        }
+        Ok(Policy {
+            anonymous_pull,
+            rules: Some(rules),
+        })
+    }
+
+    // Returns the rules that apply to a user.
+    fn rules_of(&self, user: &str) -> Vec<&Rules> {
+        match &self.rules {
+            None => vec![],
+            Some(rules) => [user, "*"].iter().filter_map(|u| rules.get(*u)).collect(),
+        }
+    }
+
+    // Returns the actions a user may take in a repository.
+    fn repository_actions(&self, user: &str, repo: &str) -> Vec<&str> {
+        if self.rules.is_none() {
+            return REPOSITORY_ACTIONS.to_vec();
+        }
+        let mut permitted = vec![];
+        for rules in self.rules_of(user) {
+            for (pattern, actions) in &rules.repositories {
+                if matches_repository(pattern, repo) {
+                    permitted.extend(actions.iter().map(String::as_str));
+                }
+            }
+        }
+        if permitted.contains(&"*") {
+            return REPOSITORY_ACTIONS.to_vec();
+        }
+        permitted
+    }
+
+    fn is_admin(&self, user: &str) -> bool {
+        self.rules_of(user).iter().any(|r| r.admin)
+    }
+
+    // Returns the part of the requested access that a user, or an anonymous
+    // client, may be granted.
+    pub fn grant(&self, user: Option<&str>, requested: &Access) -> Access {
+        let permitted: Vec<&str> = match (requested.resource_type.as_str(), user) {
+            ("repository", Some(u)) => self.repository_actions(u, &requested.name),
+            ("repository", None) if self.anonymous_pull => vec!["pull"],
+            ("registry", Some(_)) if requested.name == "catalog" => vec!["*"],
+            ("registry", Some(u)) if requested.name == "admin" && self.is_admin(u) => vec!["*"],
+            ("registry", None) if requested.name == "catalog" && self.anonymous_pull => {
+                vec!["*"]
+            }
+            _ => vec![],
+        };
+        Access {
+            actions: requested
+                .actions
+                .iter()
+                .filter(|a| permitted.contains(&a.as_str()))
+                .cloned()
+                .collect(),
+            ..requested.clone()
+        }
+    }
+
+    // Checks that a client may be granted the required access. Requests that
+    // require no access, such as GET /v2/, must still authenticate, which is
+    // how clients discover how to do so.
+    fn check(&self, user: Option<String>, required: &[Access]) -> Result<Option<String>, Denial> {
+        if user.is_none() && required.is_empty() {
+            return Err(Denial::Unauthorized("authentication required".to_string()));
+        }
+        let granted: Vec<Access> = required
+            .iter()
+            .map(|r| self.grant(user.as_deref(), r))
+            .collect();
+        if allowed(&granted, required) {
+            return Ok(user);
+        }
+        let detail = format!("access to {} is denied", scopes(required));
+        match user {
+            None => Err(Denial::Unauthorized(detail)),
+            Some(_) => Err(Denial::Denied(detail)),
+        }
+    }
+}
+
+// Reports whether a repository matches a pattern of a policy.
+fn matches_repository(pattern: &str, repo: &str) -> bool {
+    match pattern.strip_suffix("*") {
+        None => pattern == repo,
+        Some("") => true,
+        Some(prefix) => prefix.ends_with('/') && repo.starts_with(prefix),
+    }
+}
+
+// Authenticator decides whether requests may access what they require.
+pub trait Authenticator: Send + Sync {
+    // Checks the Authorization header of a request against the access it
+    // requires. Returns the authenticated user, if any.
+    fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: &[Access],
+    ) -> Result<Option<String>, Denial>;
+
+    // Returns the WWW-Authenticate challenge for a request that was not
+    // authorized. The host is the registry as addressed by the client.
+    fn challenge(&self, host: Option<&str>, required: &[Access], denial: &Denial) -> String;
+
+    // Returns the user that an authorized request authenticated as, without
+    // checking its credentials again. Returns None for anonymous requests.
+    fn identity(&self, _authorization: Option<&str>) -> Option<String> {
+        None
+    }
+
+    // Issues a bearer token for the requested access. Returns None if the
+    // authenticator does not accept tokens.
+    fn issue_token(
+        &self,
+        _authorization: Option<&str>,
+        _requested: &[Access],
+    ) -> Option<Result<Token, Denial>> {
+        None
+    }
+}
+
+pub type Auth = Arc<dyn Authenticator>;
+
+// NoAuth allows every request.
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn authorize(&self, _: Option<&str>, _: &[Access]) -> Result<Option<String>, Denial> {
+        Ok(None)
+    }
+
+    fn challenge(&self, _: Option<&str>, _: &[Access], _: &Denial) -> String {
+        String::new()
+    }
+}
+
+// Builds the authenticator selected by the configuration, for a registry
+// that is served over TLS if tls is set.
+pub fn from_config(c: &AuthConfig, tls: bool) -> Result<Auth, String> {
+    if c.mode == AuthMode::None {
+        return Ok(Arc::new(NoAuth));
+    }
+    let path = c
+        .htpasswd
+        .as_ref()
+        .ok_or_else(|| "an htpasswd file is required".to_string())?;
+    let users =
+        Htpasswd::open(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let policy = match &c.policy {
+        Some(p) => Policy::open(p, c.anonymous_pull)?,
+        None => Policy {
+            anonymous_pull: c.anonymous_pull,
+            rules: None,
+        },
+    };
+    if c.mode == AuthMode::Basic {
+        return Ok(Arc::new(BasicAuth::new(users, policy)));
+    }
+    let key = match &c.token_key {
+        Some(k) => k.as_bytes().to_vec(),
+        None => {
+            log::warn!("no token key configured, tokens will not be valid after a restart");
+            rand::thread_rng().gen::<[u8; 32]>().to_vec()
+        }
+    };
+    let issuer = TokenIssuer::new(key, c.token_service.clone(), c.token_ttl);
+    Ok(Arc::new(TokenAuth::new(
+        issuer,
+        users,
+        policy,
+        c.token_realm.clone(),
+        tls,
+    )))
+}
+
+// Extracts the user and password from a Basic Authorization header.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Compares secrets in time that does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &str) -> Policy {
+        Policy {
+            anonymous_pull: false,
+            rules: Some(serde_json::from_str(rules).unwrap()),
+        }
+    }
+
+    fn granted(policy: &Policy, user: Option<&str>, scope: &str) -> Vec<String> {
+        policy.grant(user, &Access::parse(scope).unwrap()).actions
+    }
+
+    #[test]
+    fn parses_scopes() {
+        let a = Access::parse("repository:library/ubuntu:pull,push").unwrap();
+        assert_eq!(a, Access::repository("library/ubuntu", &["pull", "push"]));
+        assert_eq!(a.scope(), "repository:library/ubuntu:pull,push");
+        assert_eq!(Access::parse("registry:catalog:*"), Some(Access::catalog()));
+        assert_eq!(Access::parse("repository"), None);
+    }
+
+    #[test]
+    fn matches_repository_patterns() {
+        assert!(matches_repository("*", "a/b"));
+        assert!(matches_repository("a/b", "a/b"));
+        assert!(!matches_repository("a/b", "a/bc"));
+        assert!(matches_repository("a/*", "a/b"));
+        assert!(matches_repository("a/*", "a/b/c"));
+        assert!(!matches_repository("a/*", "a"));
+        assert!(!matches_repository("a/*", "ab/c"));
+        assert!(!matches_repository("a*", "ab"));
+    }
+
+    #[test]
+    fn users_without_rules_may_use_every_repository_but_not_administer() {
+        let p = Policy::default();
+        assert_eq!(
+            granted(&p, Some("alice"), "repository:a/b:pull,push,delete"),
+            vec!["pull", "push", "delete"]
+        );
+        assert!(granted(&p, Some("alice"), "registry:admin:*").is_empty());
+        assert_eq!(granted(&p, Some("alice"), "registry:catalog:*"), vec!["*"]);
+    }
+
+    #[test]
+    fn rules_scope_repositories() {
+        let p = policy(
+            r#"{
+                "alice": {"repositories": {"team/*": ["pull", "push"], "shared": ["*"]}},
+                "*": {"repositories": {"public/*": ["pull"]}}
+            }"#,
+        );
+        assert_eq!(
+            granted(&p, Some("alice"), "repository:team/app:pull,push,delete"),
+            vec!["pull", "push"]
+        );
+        assert_eq!(
+            granted(&p, Some("alice"), "repository:shared:pull,push,delete"),
+            vec!["pull", "push", "delete"]
+        );
+        assert_eq!(
+            granted(&p, Some("alice"), "repository:public/x:pull,push"),
+            vec!["pull"]
+        );
+        assert!(granted(&p, Some("alice"), "repository:other/x:pull").is_empty());
+        assert!(granted(&p, Some("bob"), "repository:team/app:pull").is_empty());
+        assert_eq!(
+            granted(&p, Some("bob"), "repository:public/x:pull"),
+            vec!["pull"]
+        );
+    }
+
+    #[test]
+    fn admin_is_granted_separately() {
+        let p = policy(
+            r#"{
+                "root": {"admin": true},
+                "alice": {"repositories": {"*": ["*"]}}
+            }"#,
+        );
+        assert_eq!(granted(&p, Some("root"), "registry:admin:*"), vec!["*"]);
+        assert!(granted(&p, Some("alice"), "registry:admin:*").is_empty());
+        assert!(granted(&p, Some("root"), "repository:a/b:pull").is_empty());
+    }
+
+    #[test]
+    fn anonymous_clients_may_only_pull_if_enabled() {
+        let mut p = Policy::default();
+        assert!(granted(&p, None, "repository:a/b:pull").is_empty());
+        assert!(granted(&p, None, "registry:catalog:*").is_empty());
+        p.anonymous_pull = true;
+        assert_eq!(granted(&p, None, "repository:a/b:pull,push"), vec!["pull"]);
+        assert_eq!(granted(&p, None, "registry:catalog:*"), vec!["*"]);
+        assert!(granted(&p, None, "registry:admin:*").is_empty());
+    }
+
+    #[test]
+    fn checks_require_authentication_for_unscoped_requests() {
+        let p = Policy::default();
+        assert!(matches!(p.check(None, &[]), Err(Denial::Unauthorized(_))));
+        assert!(p.check(Some("alice".to_string()), &[]).is_ok());
+        let admin = [Access::admin()];
+        assert!(matches!(
+            p.check(Some("alice".to_string()), &admin),
+            Err(Denial::Denied(_))
+        ));
+    }
+}
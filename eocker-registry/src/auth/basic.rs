@@ -0,0 +1,434 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{basic_credentials, constant_time_eq, Access, Authenticator, Denial, Policy, REALM};
+
+// BasicAuth authenticates every request with the users of an htpasswd file.
+pub struct BasicAuth {
+    users: Htpasswd,
+    policy: Policy,
+}
+
+impl BasicAuth {
+    pub fn new(users: Htpasswd, policy: Policy) -> BasicAuth {
+        BasicAuth { users, policy }
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: &[Access],
+    ) -> Result<Option<String>, Denial> {
+        let user = match authorization {
+            None => None,
+            Some(a) => Some(self.users.authenticate(a)?),
+        };
+        self.policy.check(user, required)
+    }
+
+    fn challenge(&self, _: Option<&str>, _: &[Access], _: &Denial) -> String {
+        format!("Basic realm=\"{}\"", REALM)
+    }
+
+    fn identity(&self, authorization: Option<&str>) -> Option<String> {
+        basic_credentials(authorization?).map(|(user, _)| user)
+    }
+}
+
+// Htpasswd holds the users of an htpasswd file. Passwords may be hashed with
+// SHA-256 or SHA-512 crypt ($5$ and $6$, as produced by openssl passwd -5) or
+// with SHA-1 ({SHA}, as produced by htpasswd -s). Other hashes, such as
+// bcrypt, are not supported and their users cannot log in.
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    // The digest of each user's password and when it was last verified, so
+    // that clients sending the same credentials on every request do not pay
+    // for SHA-crypt each time.
+    verified: Mutex<HashMap<String, (Vec<u8>, Instant)>>,
+}
+
+// How long a verified password is accepted without hashing it again.
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+impl Htpasswd {
+    pub fn open(path: &Path) -> io::Result<Htpasswd> {
+        let content = std::fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = match line.split_once(':') {
+                None => continue,
+                Some(l) => l,
+            };
+            if verify_password(hash, "").is_none() {
+                log::warn!("htpasswd: {} has an unsupported password hash", user);
+                continue;
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Htpasswd::new(users))
+    }
+
+    fn new(users: HashMap<String, String>) -> Htpasswd {
+        Htpasswd {
+            users,
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        self.verify_at(user, password, Instant::now())
+    }
+
+    fn verify_at(&self, user: &str, password: &str, now: Instant) -> bool {
+        let hash = match self.users.get(user) {
+            None => return false,
+            Some(h) => h,
+        };
+        let digest = Sha256::digest(password.as_bytes()).to_vec();
+        if let Some((d, at)) = self.verified.lock().unwrap().get(user) {
+            if now.saturating_duration_since(*at) < VERIFIED_TTL && constant_time_eq(d, &digest) {
+                return true;
+            }
+        }
+        if !verify_password(hash, password).unwrap_or(false) {
+            return false;
+        }
+        // Only users of the file are kept, so the map cannot grow past it.
+        self.verified
+            .lock()
+            .unwrap()
+            .insert(user.to_string(), (digest, now));
+        true
+    }
+
+    // Returns the user of a Basic Authorization header if its password is
+    // correct.
+    pub(super) fn authenticate(&self, authorization: &str) -> Result<String, Denial> {
+        match basic_credentials(authorization) {
+            Some((user, password)) if self.verify(&user, &password) => Ok(user),
+            _ => Err(Denial::Unauthorized(
+                "invalid username or password".to_string(),
+            )),
+        }
+    }
+}
+
+// Checks a password against a hash. Returns None if the hash is not in a
+// supported format.
+fn verify_password(hash: &str, password: &str) -> Option<bool> {
+    if let Some(encoded) = hash.strip_prefix("{SHA}") {
+        let actual = STANDARD.encode(<Sha1 as sha1::Digest>::digest(password.as_bytes()));
+        return Some(constant_time_eq(actual.as_bytes(), encoded.as_bytes()));
+    }
+    let actual = if let Some(setting) = hash.strip_prefix("$5$") {
+        sha_crypt::<Sha256>(password.as_bytes(), setting, &SHA256_ORDER)?
+    } else if let Some(setting) = hash.strip_prefix("$6$") {
+        sha_crypt::<Sha512>(password.as_bytes(), setting, &SHA512_ORDER)?
+    } else {
+        return None;
+    };
+    // The hash is compared without its prefix and settings.
+    let expected = hash.rsplit('$').next()?;
+    Some(constant_time_eq(actual.as_bytes(), expected.as_bytes()))
+}
+
+const ROUNDS_DEFAULT: usize = 5000;
+const ROUNDS_MIN: usize = 1000;
+// Passwords are hashed on every Basic request, so hashes with more rounds
+// than this are not supported, although SHA-crypt allows up to 999999999.
+const ROUNDS_MAX: usize = 100_000;
+
+// The order bytes of the final digest are encoded in, three at a time.
+const SHA256_ORDER: [usize; 32] = [
+    0, 10, 20, 21, 1, 11, 12, 22, 2, 3, 13, 23, 24, 4, 14, 15, 25, 5, 6, 16, 26, 27, 7, 17, 18, 28,
+    8, 9, 19, 29, 31, 30,
+];
+const SHA512_ORDER: [usize; 64] = [
+    0, 21, 42, 22, 43, 1, 44, 2, 23, 3, 24, 45, 25, 46, 4, 47, 5, 26, 6, 27, 48, 28, 49, 7, 50, 8,
+    29, 9, 30, 51, 31, 52, 10, 53, 11, 32, 12, 33, 54, 34, 55, 13, 56, 14, 35, 15, 36, 57, 37, 58,
+    16, 59, 17, 38, 18, 39, 60, 40, 61, 19, 62, 20, 41, 63,
+];
+
+// Computes the encoded digest of SHA-crypt for a password and the settings
+// of a hash, which are an optional rounds=<n>$ followed by the salt.
+// https://www.akkadia.org/drepper/SHA-crypt.txt
+fn sha_crypt<D: Digest + Clone>(password: &[u8], setting: &str, order: &[usize]) -> Option<String> {
+    let (rounds, rest) = match setting.strip_prefix("rounds=") {
+        Some(r) => {
+            let (n, rest) = r.split_once('$')?;
+            let n = n.parse::<usize>().ok().filter(|n| *n <= ROUNDS_MAX)?;
+            (n.max(ROUNDS_MIN), rest)
+        }
+        None => (ROUNDS_DEFAULT, setting),
+    };
+    let salt = rest.split('$').next()?.as_bytes();
+    let salt = &salt[..salt.len().min(16)];
+    let size = D::output_size();
+
+    let b = D::new()
+        .chain(password)
+        .chain(salt)
+        .chain(password)
+        .finalize();
+    let mut a = D::new().chain(password).chain(salt);
+    let mut n = password.len();
+    while n > size {
+        a.update(&b);
+        n -= size;
+    }
+    a.update(&b[..n]);
+    let mut n = password.len();
+    while n > 0 {
+        if n & 1 == 1 {
+            a.update(&b);
+        } else {
+            a.update(password);
+        }
+        n >>= 1;
+    }
+    let a = a.finalize();
+
+    let mut dp = D::new();
+    for _ in 0..password.len() {
+        dp.update(password);
+    }
+    let p = repeat(&dp.finalize(), password.len());
+    let mut ds = D::new();
+    for _ in 0..16 + a[0] as usize {
+        ds.update(salt);
+    }
+    let s = repeat(&ds.finalize(), salt.len());
+
+    let mut c = a.to_vec();
+    for i in 0..rounds {
+        let mut d = D::new();
+        if i & 1 == 1 {
+            d.update(&p);
+        } else {
+            d.update(&c);
+        }
+        if i % 3 != 0 {
+            d.update(&s);
+        }
+        if i % 7 != 0 {
+            d.update(&p);
+        }
+        if i & 1 == 1 {
+            d.update(&c);
+        } else {
+            d.update(&p);
+        }
+        c = d.finalize().to_vec();
+    }
+
+    let bytes: Vec<u8> = order.iter().map(|i| c[*i]).collect();
+    Some(crypt_base64(&bytes))
+}
+
+// Repeats a digest until it is len bytes long.
+fn repeat(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().cycle().take(len).copied().collect()
+}
+
+// Encodes bytes with the base64 variant of crypt, which emits the six bit
+// groups of every three bytes starting with the least significant.
+fn crypt_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut out = String::new();
+    for group in bytes.chunks(3) {
+        let mut w = 0u32;
+        for (i, b) in group.iter().enumerate() {
+            w |= (*b as u32) << (8 * (group.len() - 1 - i));
+        }
+        for _ in 0..group.len() + 1 {
+            out.push(ALPHABET[(w & 0x3f) as usize] as char);
+            w >>= 6;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors of https://www.akkadia.org/drepper/SHA-crypt.txt, along
+    // with hashes produced by openssl passwd.
+    #[test]
+    fn sha_crypt_known_answers() {
+        let cases = [
+            (
+                "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5",
+                "Hello world!",
+            ),
+            (
+                "$5$rounds=10000$saltstringsaltst$3xv.VbSHBb41AL9AvLeujZkZRBAwqFMz2.opqey6IcA",
+                "Hello world!",
+            ),
+            (
+                "$5$rounds=5000$toolongsaltstrin$Un/5jzAHMgOGZ5.mWJpuVolil07guHPvOW8mGRcvxa5",
+                "This is just a test",
+            ),
+            (
+                "$5$rounds=1000$roundstoolow$yfvwcWrQ8l/K0DAWyuPMDNHpIVlTQebY9l/gL972bIC",
+                "the minimum number is still observed",
+            ),
+            (
+                "$5$saltstring$FdNfA4gXqvCeO6iZs7G/.wwwoywYZqo0l1pwmfWaBA7",
+                "",
+            ),
+            (
+                "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+                "Hello world!",
+            ),
+            (
+                "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.",
+                "Hello world!",
+            ),
+            (
+                "$6$rounds=1400$anotherlongsalts$POfYwTEok97VWcjxIiSOjiykti.o/pQs.wPvMxQ6Fm7I6IoYN3CmLs66x9t0oSwbtEW7o7UmJEiDwGqd8p4ur1",
+                "a very much longer text to encrypt.  This one even stretches over morethan one line.",
+            ),
+        ];
+        for (hash, password) in cases.iter() {
+            assert_eq!(verify_password(hash, password), Some(true), "{}", hash);
+            assert_eq!(verify_password(hash, "wrong"), Some(false), "{}", hash);
+        }
+    }
+
+    // Hashes produced by openssl passwd -5 and -6, and by glibc crypt(3) for
+    // the ones with rounds, which openssl passwd cannot set.
+    #[test]
+    fn verifies_reference_hashes() {
+        let cases = [
+            (
+                "$5$Zx9pQ2wL$YMvYvHh97UW3T9TMWX9U22g/RGiEH/4R9e3ljLe1T/.",
+                "correct horse",
+            ),
+            (
+                "$6$Zx9pQ2wL$JZgKjJXlTDAssao/aqIyMqeVTRRHlFSQZim8Jkh5McDhz1pHj5owCQX60AcR6s8YAqCHrf0v7coKG04IR3K8K/",
+                "correct horse",
+            ),
+            (
+                "$5$k3Jb.7/eM$xMEzWAf8VrlYXpSpkvXgmgrS54t1SLWXj/C0WN0tmo8",
+                "p@ss:word",
+            ),
+            (
+                "$6$k3Jb.7/eM$D5U0BW.aaMoq4TT5Ik6RypHXY1i30iP58k9g5bzyk2HocKM6civM8glKD.fp8SNGGKnJ2e37dj9Vni8KiI/b/0",
+                "p@ss:word",
+            ),
+            (
+                "$5$rounds=12345$Zx9pQ2wL$ufy0ytZQBwiC370n2a2X6Guv6q.MRM5ViA62jEfXPUB",
+                "correct horse",
+            ),
+            (
+                "$6$rounds=2000$Zx9pQ2wL$HJhFC.cgMkhsKVJoHsXM/GaqAaSntskwA8MdOeOXvqzFZK.oaMKKQuflwRQhFKQrz6U726cEl255fQpW7YftF1",
+                "correct horse",
+            ),
+        ];
+        for (hash, password) in cases.iter() {
+            assert_eq!(verify_password(hash, password), Some(true), "{}", hash);
+            assert_eq!(
+                verify_password(hash, "correct horse!"),
+                Some(false),
+                "{}",
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn remembers_verified_passwords_for_a_while() {
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_string(),
+            "$5$Zx9pQ2wL$YMvYvHh97UW3T9TMWX9U22g/RGiEH/4R9e3ljLe1T/.".to_string(),
+        );
+        let users = Htpasswd::new(users);
+        let start = Instant::now();
+        assert!(!users.verify_at("alice", "wrong", start));
+        assert!(users.verified.lock().unwrap().is_empty());
+
+        assert!(users.verify_at("alice", "correct horse", start));
+        let at = |users: &Htpasswd| users.verified.lock().unwrap()["alice"].1;
+        assert_eq!(at(&users), start);
+        // Within the TTL the password is accepted without hashing it again,
+        // while other passwords are still checked against the hash.
+        let later = start + VERIFIED_TTL / 2;
+        assert!(users.verify_at("alice", "correct horse", later));
+        assert_eq!(at(&users), start);
+        assert!(!users.verify_at("alice", "wrong", later));
+        // Once it expires, the password is hashed and remembered again.
+        let expired = start + VERIFIED_TTL;
+        assert!(users.verify_at("alice", "correct horse", expired));
+        assert_eq!(at(&users), expired);
+    }
+
+    #[test]
+    fn rounds_below_the_minimum_are_raised() {
+        // The spec's vector for rounds=10 is computed with 1000 rounds.
+        let hash = "$5$rounds=10$roundstoolow$yfvwcWrQ8l/K0DAWyuPMDNHpIVlTQebY9l/gL972bIC";
+        assert_eq!(
+            verify_password(hash, "the minimum number is still observed"),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn too_many_rounds_are_unsupported() {
+        let hash = "$5$rounds=999999999$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5";
+        assert_eq!(verify_password(hash, "Hello world!"), None);
+    }
+
+    #[test]
+    fn sha1_hashes() {
+        // htpasswd -nbs alice password
+        let hash = "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+        assert_eq!(verify_password(hash, "password"), Some(true));
+        assert_eq!(verify_password(hash, "Password"), Some(false));
+    }
+
+    #[test]
+    fn unsupported_hashes() {
+        let bcrypt = "$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC";
+        assert_eq!(verify_password(bcrypt, "password"), None);
+        assert_eq!(verify_password("plain", "plain"), None);
+    }
+
+    #[test]
+    fn crypt_base64_encodes_least_significant_bits_first() {
+        assert_eq!(crypt_base64(&[0, 0, 0]), "....");
+        assert_eq!(crypt_base64(&[0, 0, 1]), "/...");
+        assert_eq!(crypt_base64(&[0xff]), "z1");
+    }
+
+    #[test]
+    fn authenticates_basic_credentials() {
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_string(),
+            "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5".to_string(),
+        );
+        let users = Htpasswd::new(users);
+        let header = |c: &str| format!("Basic {}", STANDARD.encode(c));
+        assert_eq!(
+            users.authenticate(&header("alice:Hello world!")).ok(),
+            Some("alice".to_string())
+        );
+        assert!(users.authenticate(&header("alice:hello world!")).is_err());
+        assert!(users.authenticate(&header("bob:Hello world!")).is_err());
+        assert!(users.authenticate("Bearer abc").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::{allowed, constant_time_eq, scopes, Access, Authenticator, Denial, Htpasswd, Policy};
+
+// TokenAuth authorizes requests with bearer tokens, which clients obtain from
+// the token endpoint by authenticating with the users of an htpasswd file.
+pub struct TokenAuth {
+    issuer: TokenIssuer,
+    users: Htpasswd,
+    policy: Policy,
+    // URL of the token endpoint advertised in challenges. Defaults to the
+    // /token endpoint of the registry.
+    realm: Option<String>,
+    // Whether the registry is served over TLS, and so is its /token endpoint.
+    tls: bool,
+}
+
+impl TokenAuth {
+    pub fn new(
+        issuer: TokenIssuer,
+        users: Htpasswd,
+        policy: Policy,
+        realm: Option<String>,
+        tls: bool,
+    ) -> TokenAuth {
+        TokenAuth {
+            issuer,
+            users,
+            policy,
+            realm,
+            tls,
+        }
+    }
+}
+
+impl Authenticator for TokenAuth {
+    fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: &[Access],
+    ) -> Result<Option<String>, Denial> {
+        // Clients may skip fetching a token for access anyone is granted.
+        let token = match authorization {
+            None => return self.policy.check(None, required),
+            Some(a) => a,
+        };
+        let claims = match token.split_once(' ') {
+            Some((scheme, t)) if scheme.eq_ignore_ascii_case("bearer") => {
+                self.issuer.verify(t.trim())
+            }
+            _ => None,
+        };
+        let claims =
+            claims.ok_or_else(|| Denial::Unauthorized("invalid or expired token".to_string()))?;
+        let user = Some(claims.sub).filter(|s| !s.is_empty());
+        if allowed(&claims.access, required) {
+            return Ok(user);
+        }
+        let detail = format!("token does not grant {}", scopes(required));
+        // Anonymous clients may be granted more once they log in.
+        match user {
+            None => Err(Denial::Unauthorized(detail)),
+            Some(_) => Err(Denial::Denied(detail)),
+        }
+    }
+
+    fn challenge(&self, host: Option<&str>, required: &[Access], denial: &Denial) -> String {
+        let realm = match &self.realm {
+            Some(r) => r.clone(),
+            None => format!(
+                "{}://{}/token",
+                if self.tls { "https" } else { "http" },
+                host.unwrap_or("localhost")
+            ),
+        };
+        let mut challenge = format!(
+            "Bearer realm=\"{}\",service=\"{}\"",
+            realm, self.issuer.service
+        );
+        if !required.is_empty() {
+            challenge.push_str(&format!(",scope=\"{}\"", scopes(required)));
+        }
+        if let Denial::Denied(_) = denial {
+            challenge.push_str(",error=\"insufficient_scope\"");
+        }
+        challenge
+    }
+
+    fn identity(&self, authorization: Option<&str>) -> Option<String> {
+        let (scheme, token) = authorization?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        Some(self.issuer.verify(token.trim())?.sub).filter(|s| !s.is_empty())
+    }
+
+    fn issue_token(
+        &self,
+        authorization: Option<&str>,
+        requested: &[Access],
+    ) -> Option<Result<Token, Denial>> {
+        let user = match authorization.map(|a| self.users.authenticate(a)) {
+            None => None,
+            Some(Ok(u)) => Some(u),
+            Some(Err(d)) => return Some(Err(d)),
+        };
+        // Access that cannot be granted is left out of the token rather than
+        // refused, as clients request every scope they might need.
+        let granted = requested
+            .iter()
+            .map(|r| self.policy.grant(user.as_deref(), r))
+            .filter(|a| !a.actions.is_empty())
+            .collect();
+        Some(Ok(self.issuer.issue(user.as_deref(), granted)))
+    }
+}
+
+// Token is the response of the token endpoint. Both fields hold the token, as
+// older clients read token and OAuth2 clients read access_token.
+#[derive(Debug, Serialize)]
+pub struct Token {
+    pub token: String,
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+// Claims of the JSON Web Tokens issued by the registry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    // The authenticated user, or empty for anonymous clients.
+    pub sub: String,
+    pub aud: String,
+    pub exp: u64,
+    pub nbf: u64,
+    pub iat: u64,
+    pub jti: String,
+    pub access: Vec<Access>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+// TokenIssuer signs and verifies JSON Web Tokens with HMAC-SHA256. The
+// registry is both the issuer and the audience of its tokens.
+pub struct TokenIssuer {
+    key: Vec<u8>,
+    service: String,
+    ttl: Duration,
+}
+
+impl TokenIssuer {
+    pub fn new(key: Vec<u8>, service: String, ttl: Duration) -> TokenIssuer {
+        TokenIssuer { key, service, ttl }
+    }
+
+    pub fn issue(&self, user: Option<&str>, access: Vec<Access>) -> Token {
+        let now = unix_now();
+        let claims = Claims {
+            iss: self.service.clone(),
+            sub: user.unwrap_or_default().to_string(),
+            aud: self.service.clone(),
+            exp: now + self.ttl.as_secs(),
+            nbf: now,
+            iat: now,
+            jti: Uuid::new_v4().to_string(),
+            access,
+        };
+        let header = Header {
+            alg: "HS256".to_string(),
+            typ: "JWT".to_string(),
+        };
+        // Serializing structs of strings and numbers cannot fail.
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap())
+        );
+        let signature = URL_SAFE_NO_PAD.encode(hmac_sha256(&self.key, signed.as_bytes()));
+        let token = format!("{}.{}", signed, signature);
+        Token {
+            access_token: token.clone(),
+            token,
+            expires_in: self.ttl.as_secs(),
+        }
+    }
+
+    // Returns the claims of a token if it was issued by the registry and has
+    // not expired.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !constant_time_eq(&hmac_sha256(&self.key, signed.as_bytes()), &signature) {
+            return None;
+        }
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header.alg != "HS256" {
+            return None;
+        }
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        let now = unix_now();
+        if claims.iss != self.service || claims.aud != self.service {
+            return None;
+        }
+        if now >= claims.exp || now < claims.nbf {
+            return None;
+        }
+        Some(claims)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// https://www.rfc-editor.org/rfc/rfc2104
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut key = if key.len() > BLOCK_SIZE {
+        Sha256::digest(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    key.resize(BLOCK_SIZE, 0);
+    let pad = |b: u8| key.iter().map(|k| k ^ b).collect::<Vec<u8>>();
+    let inner = Sha256::new().chain(pad(0x36)).chain(message).finalize();
+    Sha256::new()
+        .chain(pad(0x5c))
+        .chain(inner)
+        .finalize()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Test cases 1, 2, 3, 6 and 7 of https://www.rfc-editor.org/rfc/rfc4231
+    #[test]
+    fn hmac_sha256_known_answers() {
+        let cases: [(Vec<u8>, Vec<u8>, &str); 5] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, expected) in cases.iter() {
+            assert_eq!(hex(&hmac_sha256(key, message)), *expected);
+        }
+    }
+
+    fn issuer(ttl: u64) -> TokenIssuer {
+        TokenIssuer::new(
+            b"secret".to_vec(),
+            "registry".to_string(),
+            Duration::from_secs(ttl),
+        )
+    }
+
+    fn pull(repo: &str) -> Vec<Access> {
+        vec![Access::repository(repo, &["pull"])]
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let token = issuer(60).issue(Some("alice"), pull("a/b"));
+        assert_eq!(token.token, token.access_token);
+        let claims = issuer(60).verify(&token.token).unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.access, pull("a/b"));
+        assert_eq!(claims.exp - claims.iat, 60);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let token = issuer(0).issue(Some("alice"), pull("a/b"));
+        assert!(issuer(0).verify(&token.token).is_none());
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let token = issuer(60).issue(Some("alice"), pull("a/b")).token;
+        let parts: Vec<&str> = token.split('.').collect();
+
+        // Claims granting more than was issued.
+        let mut claims = issuer(60).verify(&token).unwrap();
+        claims.access = vec![Access::repository("a/b", &["pull", "push"])];
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap()),
+            parts[2]
+        );
+        assert!(issuer(60).verify(&forged).is_none());
+
+        // A signature that was cut short.
+        let truncated = &token[..token.len() - 2];
+        assert!(issuer(60).verify(truncated).is_none());
+
+        // An unsigned token.
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#);
+        let unsigned = format!("{}.{}.", header, parts[1]);
+        assert!(issuer(60).verify(&unsigned).is_none());
+
+        assert!(issuer(60).verify("not a token").is_none());
+    }
+
+    #[test]
+    fn tokens_of_other_keys_and_services_are_rejected() {
+        let token = issuer(60).issue(Some("alice"), pull("a/b")).token;
+        let other_key = TokenIssuer::new(
+            b"other".to_vec(),
+            "registry".to_string(),
+            Duration::from_secs(60),
+        );
+        assert!(other_key.verify(&token).is_none());
+        let other_service = TokenIssuer::new(
+            b"secret".to_vec(),
+            "other".to_string(),
+            Duration::from_secs(60),
+        );
+        assert!(other_service.verify(&token).is_none());
+    }
+}
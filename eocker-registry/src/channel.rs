@@ -1,15 +1,108 @@
+use futures::{stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast;
 use tokio::sync::Mutex;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
 use warp::http::{Method, StatusCode};
 
-pub type ChannelMap = Arc<Mutex<HashMap<String, broadcast::Sender<Event>>>>;
+pub type ChannelMap = Arc<Mutex<Channels>>;
 
-// TODO(hasheddan): channels are not cleaned up when no one is subscribed.
-pub fn new_channel_map() -> ChannelMap {
-    Arc::new(Mutex::new(HashMap::new()))
+// Channels holds a channel for every namespace that has subscribers, and the
+// history of every namespace that has had one. A channel is removed once its
+// last subscriber leaves, while its history is kept so that subscribers that
+// reconnect are sent what they missed in the meantime. Histories without
+// events are removed along with their channel, and only the latest
+// MAX_IDLE_HISTORIES namespaces to lose their subscribers keep theirs.
+pub struct Channels {
+    channels: HashMap<String, broadcast::Sender<(u64, Event)>>,
+    histories: HashMap<String, History>,
+    // Namespaces that have a history but no channel, in the order their last
+    // subscriber left.
+    idle: VecDeque<String>,
+    // How many events a subscriber may fall behind by before it misses some.
+    buffer_size: usize,
+    // How many events each history keeps for subscribers that reconnect.
+    history_size: usize,
+    // Event ids start with the time the registry started, so that those of
+    // an earlier run are not mistaken for ids of this one.
+    epoch: u64,
+}
+
+// How many namespaces without subscribers keep their history.
+const MAX_IDLE_HISTORIES: usize = 1024;
+
+struct History {
+    // Sequence number of the next event.
+    next_seq: u64,
+    // The latest events along with their sequence numbers.
+    events: VecDeque<(u64, Event)>,
+}
+
+// Message is what subscribers are sent.
+pub enum Message {
+    Event(String, Event),
+    // Events the subscriber missed, either because it fell behind or because
+    // they are no longer kept to be replayed.
+    Lagged(u64),
+    // The subscriber reconnected with the id of an event that is unknown,
+    // such as one sent before the registry restarted, so it may have missed
+    // any number of events.
+    Reset,
+}
+
+pub fn new_channel_map(buffer_size: usize, history_size: usize) -> ChannelMap {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    Arc::new(Mutex::new(Channels {
+        channels: HashMap::new(),
+        histories: HashMap::new(),
+        idle: VecDeque::new(),
+        buffer_size,
+        history_size,
+        epoch: started,
+    }))
+}
+
+impl Channels {
+    // Returns the number of subscribers of each namespace.
+    pub fn subscribers(&self) -> Vec<(String, usize)> {
+        self.channels
+            .iter()
+            .map(|(ns, tx)| (ns.clone(), tx.receiver_count()))
+            .collect()
+    }
+
+    // Removes the channel of a namespace if no one is subscribed to it.
+    fn remove_unsubscribed(&mut self, ns: &str) {
+        if let Some(tx) = self.channels.get(ns) {
+            if tx.receiver_count() == 0 {
+                self.remove_channel(ns);
+            }
+        }
+    }
+
+    // Removes the channel of a namespace. Its history is kept unless it has
+    // no events, dropping that of the namespace idle the longest if too many
+    // are.
+    fn remove_channel(&mut self, ns: &str) {
+        self.channels.remove(ns);
+        if self.histories.get(ns).map(|h| h.events.is_empty()) != Some(false) {
+            self.histories.remove(ns);
+            return;
+        }
+        self.idle.push_back(ns.to_string());
+        if self.idle.len() > MAX_IDLE_HISTORIES {
+            if let Some(oldest) = self.idle.pop_front() {
+                self.histories.remove(&oldest);
+            }
+        }
+    }
 }
 
 pub async fn send(
@@ -21,20 +114,117 @@ pub async fn send(
     refs: Option<Vec<Ref>>,
     sm: ChannelMap,
 ) {
-    let st = sm.lock().await;
-    match st.get(ns) {
-        // If channel exists we will send on it.
+    let mut st = sm.lock().await;
+    let history_size = st.history_size;
+    // Only namespaces that have had subscribers keep a history.
+    let h = match st.histories.get_mut(ns) {
+        None => return,
+        Some(h) => h,
+    };
+    let event = Event {
+        data_type,
+        method: method.to_string(),
+        status: status.as_str().to_string(),
+        repo: ns.to_string(),
+        identifier,
+        objects: refs,
+    };
+    let seq = h.next_seq;
+    h.next_seq += 1;
+    if history_size > 0 {
+        if h.events.len() == history_size {
+            h.events.pop_front();
+        }
+        h.events.push_back((seq, event.clone()));
+    }
+    if let Some(tx) = st.channels.get(ns) {
+        // Sending only fails if every subscriber has left.
+        if tx.send((seq, event)).is_err() {
+            st.remove_channel(ns);
+        }
+    }
+}
+
+// Subscribes to the events of a namespace. Subscribers that reconnect with
+// the id of the last event they received are first sent the events they
+// missed that are still kept.
+pub async fn subscribe(
+    cm: ChannelMap,
+    ns: String,
+    last_event_id: Option<String>,
+) -> impl Stream<Item = Message> + Send + 'static {
+    let mut st = cm.lock().await;
+    let (buffer_size, epoch) = (st.buffer_size, st.epoch);
+    if !st.channels.contains_key(&ns) {
+        st.idle.retain(|n| *n != ns);
+    }
+    // Subscribing while the channels are locked ensures that no event is
+    // both replayed and received.
+    let rx = st
+        .channels
+        .entry(ns.clone())
+        .or_insert_with(|| broadcast::channel(buffer_size).0)
+        .subscribe();
+    let h = st.histories.entry(ns.clone()).or_insert_with(|| History {
+        next_seq: 1,
+        events: VecDeque::new(),
+    });
+    let replayed = |after: u64| {
+        h.events
+            .iter()
+            .filter(move |(seq, _)| *seq > after)
+            .map(move |(seq, e)| Message::Event(event_id(epoch, *seq), e.clone()))
+    };
+    let mut replay = vec![];
+    match last_event_id.as_deref().map(parse_id) {
+        Some(Some((e, seq))) if e == epoch && seq < h.next_seq => {
+            let oldest = h.next_seq - h.events.len() as u64;
+            if seq + 1 < oldest {
+                replay.push(Message::Lagged(oldest - seq - 1));
+            }
+            replay.extend(replayed(seq));
+        }
+        Some(_) => {
+            replay.push(Message::Reset);
+            replay.extend(replayed(0));
+        }
         None => (),
-        Some(tx) => {
-            tx.send(Event {
-                data_type: data_type,
-                method: method.to_string(),
-                status: status.as_str().to_string(),
-                repo: ns.to_string(),
-                identifier: identifier,
-                objects: refs,
-            })
-            .unwrap();
+    }
+    drop(st);
+
+    let guard = Unsubscribe { cm, ns };
+    let live = BroadcastStream::new(rx).map(move |msg| {
+        let _ = &guard;
+        match msg {
+            Ok((seq, e)) => Message::Event(event_id(epoch, seq), e),
+            Err(BroadcastStreamRecvError::Lagged(n)) => Message::Lagged(n),
+        }
+    });
+    stream::iter(replay).chain(live)
+}
+
+fn event_id(epoch: u64, seq: u64) -> String {
+    format!("{}-{}", epoch, seq)
+}
+
+fn parse_id(id: &str) -> Option<(u64, u64)> {
+    let (epoch, seq) = id.split_once('-')?;
+    Some((epoch.parse().ok()?, seq.parse().ok()?))
+}
+
+// Unsubscribe removes the channel of a namespace once the stream of its last
+// subscriber is dropped.
+struct Unsubscribe {
+    cm: ChannelMap,
+    ns: String,
+}
+
+impl Drop for Unsubscribe {
+    fn drop(&mut self) {
+        // The channel is otherwise removed as the next event is sent.
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            let (cm, ns) = (self.cm.clone(), std::mem::take(&mut self.ns));
+            rt.spawn(async move { cm.lock().await.remove_unsubscribed(&ns) });
         }
     }
 }
@@ -57,4 +247,123 @@ pub struct Ref {
     pub data_type: String,
     pub repo: String,
     pub identifier: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn send_tag(ns: &str, tag: &str, cm: &ChannelMap) {
+        let (method, status) = (Method::PUT, StatusCode::CREATED);
+        send(
+            ns,
+            "Manifest".into(),
+            method,
+            status,
+            tag.into(),
+            None,
+            cm.clone(),
+        )
+        .await;
+    }
+
+    async fn next_event(s: &mut (impl Stream<Item = Message> + Unpin)) -> (String, String) {
+        match s.next().await {
+            Some(Message::Event(id, e)) => (id, e.identifier),
+            _ => panic!("expected an event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_events_sent_after_the_last_subscriber_left() {
+        let cm = new_channel_map(16, 16);
+        let mut s = Box::pin(subscribe(cm.clone(), "a".into(), None).await);
+        send_tag("a", "v1", &cm).await;
+        let (id, tag) = next_event(&mut s).await;
+        assert_eq!(tag, "v1");
+        drop(s);
+        // Let the channel be removed.
+        tokio::task::yield_now().await;
+        assert!(cm.lock().await.subscribers().is_empty());
+
+        send_tag("a", "v2", &cm).await;
+        let mut s = Box::pin(subscribe(cm.clone(), "a".into(), Some(id)).await);
+        assert_eq!(next_event(&mut s).await.1, "v2");
+        send_tag("a", "v3", &cm).await;
+        assert_eq!(next_event(&mut s).await.1, "v3");
+    }
+
+    #[tokio::test]
+    async fn reports_events_no_longer_kept() {
+        let cm = new_channel_map(16, 2);
+        let mut s = Box::pin(subscribe(cm.clone(), "a".into(), None).await);
+        send_tag("a", "v1", &cm).await;
+        let (id, _) = next_event(&mut s).await;
+        for tag in &["v2", "v3", "v4", "v5"] {
+            send_tag("a", tag, &cm).await;
+        }
+        let mut s = Box::pin(subscribe(cm.clone(), "a".into(), Some(id)).await);
+        assert!(matches!(s.next().await, Some(Message::Lagged(2))));
+        assert_eq!(next_event(&mut s).await.1, "v4");
+        assert_eq!(next_event(&mut s).await.1, "v5");
+    }
+
+    #[tokio::test]
+    async fn resets_subscribers_with_unknown_ids() {
+        let cm = new_channel_map(16, 16);
+        let _s = subscribe(cm.clone(), "a".into(), None).await;
+        send_tag("a", "v1", &cm).await;
+        let epoch = cm.lock().await.epoch;
+        // Ids of another run, that cannot be parsed, or of events not yet
+        // sent.
+        for id in &["1-1", "not an id", &event_id(epoch, 9)] {
+            let mut s = Box::pin(subscribe(cm.clone(), "a".into(), Some(id.to_string())).await);
+            assert!(matches!(s.next().await, Some(Message::Reset)));
+            assert_eq!(next_event(&mut s).await.1, "v1");
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_histories_without_events() {
+        let cm = new_channel_map(16, 16);
+        drop(subscribe(cm.clone(), "a".into(), None).await);
+        tokio::task::yield_now().await;
+        let st = cm.lock().await;
+        assert!(st.channels.is_empty());
+        assert!(st.histories.is_empty());
+        assert!(st.idle.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_histories_of_the_latest_idle_namespaces() {
+        let cm = new_channel_map(16, 16);
+        for i in 0..MAX_IDLE_HISTORIES + 2 {
+            let ns = format!("n{}", i);
+            let s = subscribe(cm.clone(), ns.clone(), None).await;
+            send_tag(&ns, "v1", &cm).await;
+            drop(s);
+            tokio::task::yield_now().await;
+        }
+        let st = cm.lock().await;
+        assert!(st.channels.is_empty());
+        assert_eq!(st.histories.len(), MAX_IDLE_HISTORIES);
+        assert_eq!(st.idle.len(), MAX_IDLE_HISTORIES);
+        assert!(!st.histories.contains_key("n0"));
+        assert!(!st.histories.contains_key("n1"));
+        assert!(st.histories.contains_key("n2"));
+        drop(st);
+
+        // Namespaces are no longer idle once they are subscribed to again.
+        let _s = subscribe(cm.clone(), "n2".into(), None).await;
+        let st = cm.lock().await;
+        assert_eq!(st.idle.len(), MAX_IDLE_HISTORIES - 1);
+        assert!(!st.idle.iter().any(|n| n == "n2"));
+    }
+
+    #[tokio::test]
+    async fn drops_events_of_namespaces_never_subscribed_to() {
+        let cm = new_channel_map(16, 16);
+        send_tag("a", "v1", &cm).await;
+        assert!(cm.lock().await.histories.is_empty());
+    }
+}
@@ -1,5 +1,11 @@
-// TODO(hasheddan): return error codes as defined in spec
-enum Errors {
+use bytes::Bytes;
+use serde::Serialize;
+use warp::http::{Response, StatusCode};
+
+// Error codes as defined in the distribution specification.
+// https://github.com/opencontainers/distribution-spec/blob/main/spec.md#error-codes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Errors {
     BlobUnknown,
     BlobUploadInvalid,
     BlobUploadUnknown,
@@ -15,3 +21,105 @@ enum Errors {
     Unsupported,
     Toomanyrequests,
 }
+
+impl Errors {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::BlobUnknown => "BLOB_UNKNOWN",
+            Errors::BlobUploadInvalid => "BLOB_UPLOAD_INVALID",
+            Errors::BlobUploadUnknown => "BLOB_UPLOAD_UNKNOWN",
+            Errors::DigestInvalid => "DIGEST_INVALID",
+            Errors::ManifestBlobUnknown => "MANIFEST_BLOB_UNKNOWN",
+            Errors::ManifestInvalid => "MANIFEST_INVALID",
+            Errors::ManifestUnknown => "MANIFEST_UNKNOWN",
+            Errors::NameInvalid => "NAME_INVALID",
+            Errors::NameUnknown => "NAME_UNKNOWN",
+            Errors::SizeInvalid => "SIZE_INVALID",
+            Errors::Unauthorized => "UNAUTHORIZED",
+            Errors::Denied => "DENIED",
+            Errors::Unsupported => "UNSUPPORTED",
+            Errors::Toomanyrequests => "TOOMANYREQUESTS",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Errors::BlobUnknown => "blob unknown to registry",
+            Errors::BlobUploadInvalid => "blob upload invalid",
+            Errors::BlobUploadUnknown => "blob upload unknown to registry",
+            Errors::DigestInvalid => "provided digest did not match uploaded content",
+            Errors::ManifestBlobUnknown => {
+                "manifest references a manifest or blob unknown to registry"
+            }
+            Errors::ManifestInvalid => "manifest invalid",
+            Errors::ManifestUnknown => "manifest unknown to registry",
+            Errors::NameInvalid => "invalid repository name",
+            Errors::NameUnknown => "repository name not known to registry",
+            Errors::SizeInvalid => "provided length did not match content length",
+            Errors::Unauthorized => "authentication required",
+            Errors::Denied => "requested access to the resource is denied",
+            Errors::Unsupported => "the operation is unsupported",
+            Errors::Toomanyrequests => "too many requests",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Errors::BlobUnknown
+            | Errors::BlobUploadUnknown
+            | Errors::ManifestBlobUnknown
+            | Errors::ManifestUnknown
+            | Errors::NameUnknown => StatusCode::NOT_FOUND,
+            Errors::BlobUploadInvalid
+            | Errors::DigestInvalid
+            | Errors::ManifestInvalid
+            | Errors::NameInvalid
+            | Errors::SizeInvalid => StatusCode::BAD_REQUEST,
+            Errors::Unauthorized => StatusCode::UNAUTHORIZED,
+            Errors::Denied => StatusCode::FORBIDDEN,
+            Errors::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+            Errors::Toomanyrequests => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    // Builds a response with the status associated with the error code.
+    pub fn response(&self, detail: impl Into<String>) -> warp::http::Result<Response<Bytes>> {
+        self.response_with_status(self.status(), detail)
+    }
+
+    // Builds a response with an explicit status. Used when the specification
+    // requires a status other than the default for the error code, such as
+    // 416 for out of order chunks.
+    pub fn response_with_status(
+        &self,
+        status: StatusCode,
+        detail: impl Into<String>,
+    ) -> warp::http::Result<Response<Bytes>> {
+        let body = ErrorResponse {
+            errors: vec![ErrorInfo {
+                code: self.code(),
+                message: self.message(),
+                detail: detail.into(),
+            }],
+        };
+        // Serializing a struct of strings cannot fail.
+        let content = serde_json::to_vec(&body).unwrap();
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .header("Content-Length", content.len())
+            .body(content.into())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    errors: Vec<ErrorInfo>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorInfo {
+    code: &'static str,
+    message: &'static str,
+    detail: String,
+}
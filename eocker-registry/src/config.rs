@@ -0,0 +1,823 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Config holds runtime options for the registry.
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Address to listen on.
+    pub listen: SocketAddr,
+    // Certificate chain and private key to serve TLS with, both PEM encoded.
+    // Plain HTTP is served if unset.
+    pub tls: Option<TlsConfig>,
+    // Whether manifests, tags and blobs may be deleted.
+    pub delete_enabled: bool,
+    // Backend used to store content.
+    pub storage_driver: StorageDriver,
+    // Directory to persist content to. Required by every driver other than
+    // memory.
+    pub storage_path: Option<PathBuf>,
+    // How long an upload may go without receiving content before it is
+    // discarded.
+    pub upload_ttl: Duration,
+    // How long blobs and manifests are exempt from garbage collection after
+    // they are stored, so that pushes in progress are not collected.
+    pub gc_grace: Duration,
+    // How many events a subscriber may fall behind by before it misses
+    // some.
+    pub event_buffer_size: usize,
+    // How many events are kept for each namespace for subscribers that
+    // reconnect.
+    pub event_history_size: usize,
+    // Whether the visualizer is served at /.
+    pub ui: bool,
+    // Whether metrics are served at /metrics, to administrators.
+    pub metrics: bool,
+    // How much content repositories may store.
+    pub quota: QuotaConfig,
+    // How fast each client may pull.
+    pub rate_limit: RateLimitConfig,
+    // How clients authenticate.
+    pub auth: AuthConfig,
+    // Registry to mirror. Content the registry does not have is fetched
+    // from it.
+    pub upstream: Option<UpstreamConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    // URL of the upstream, such as https://registry-1.docker.io.
+    pub url: String,
+    // Credentials to log in to the upstream with. Content is pulled
+    // anonymously if unset.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // PEM certificates to trust for the upstream instead of those of the
+    // system.
+    pub ca: Option<PathBuf>,
+    // How long tags are served before they are revalidated.
+    pub tag_ttl: Duration,
+}
+
+// Quotas limit the bytes of blobs and manifests stored. Content shared by
+// repositories counts towards each of them, but only once towards the total.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaConfig {
+    // Bytes the registry may store in total.
+    pub total: Option<u64>,
+    // Bytes every repository may store, unless it is listed in
+    // repositories.
+    pub repository: Option<u64>,
+    // Bytes that specific repositories may store.
+    pub repositories: HashMap<String, u64>,
+}
+
+impl QuotaConfig {
+    // Returns the quota of a repository.
+    pub fn of(&self, repo: &str) -> Option<u64> {
+        self.repositories.get(repo).copied().or(self.repository)
+    }
+}
+
+// Rate limits are kept per client, which is the authenticated user or else
+// the IP address of the client. Budgets that are unset are not limited.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    // Manifest GETs.
+    pub manifests: Option<Rate>,
+    // Bytes of blobs pulled.
+    pub blob_bytes: Option<Rate>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    // How many requests or bytes the budget is refilled with every second.
+    pub per_second: f64,
+    // How many requests or bytes can be spent at once.
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    // Reads the certificate chain and private key, checking that they hold
+    // PEM encoded certificates and a key before the server is started with
+    // them.
+    pub fn load(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))
+        };
+        let (cert, key) = (read(&self.cert)?, read(&self.key)?);
+        let certs = rustls_pemfile::certs(&mut cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid certificates in {}: {}", self.cert.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("no certificates found in {}", self.cert.display()));
+        }
+        match rustls_pemfile::private_key(&mut key.as_slice()) {
+            Ok(Some(_)) => Ok((cert, key)),
+            Ok(None) => Err(format!("no private key found in {}", self.key.display())),
+            Err(e) => Err(format!(
+                "invalid private key in {}: {}",
+                self.key.display(),
+                e
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    // htpasswd file holding the users that may log in. Required by every
+    // mode other than none.
+    pub htpasswd: Option<PathBuf>,
+    // Whether clients that do not log in may pull.
+    pub anonymous_pull: bool,
+    // JSON file of the access granted to each user. Every user may pull,
+    // push and delete in every repository if unset.
+    pub policy: Option<PathBuf>,
+    // URL of the token endpoint advertised to clients. Defaults to the
+    // registry's own /token endpoint.
+    pub token_realm: Option<String>,
+    // Name of the registry in tokens and challenges.
+    pub token_service: String,
+    // Key tokens are signed with. A random key is generated if unset.
+    pub token_key: Option<String>,
+    // How long issued tokens are valid.
+    pub token_ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    // Every request is allowed.
+    None,
+    // Requests carry the credentials of an htpasswd user.
+    Basic,
+    // Requests carry bearer tokens issued by the registry's token endpoint.
+    Token,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AuthMode::None),
+            "basic" => Ok(AuthMode::Basic),
+            "token" => Ok(AuthMode::Token),
+            _ => Err(format!(
+                "unknown auth mode {}, expected one of none, basic or token",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageDriver {
+    // Content is kept in memory and lost on exit.
+    Memory,
+    // Content is stored in the registry's own directory layout.
+    Filesystem,
+    // Each repository is stored as an OCI image layout.
+    Layout,
+}
+
+impl FromStr for StorageDriver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageDriver::Memory),
+            "filesystem" => Ok(StorageDriver::Filesystem),
+            "oci" => Ok(StorageDriver::Layout),
+            _ => Err(format!(
+                "unknown storage driver {}, expected one of memory, filesystem or oci",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            tls: None,
+            delete_enabled: true,
+            storage_driver: StorageDriver::Memory,
+            storage_path: None,
+            upload_ttl: Duration::from_secs(24 * 60 * 60),
+            gc_grace: Duration::from_secs(60 * 60),
+            event_buffer_size: 10,
+            event_history_size: 100,
+            ui: true,
+            metrics: true,
+            quota: QuotaConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            auth: AuthConfig::default(),
+            upstream: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            mode: AuthMode::None,
+            htpasswd: None,
+            anonymous_pull: false,
+            policy: None,
+            token_realm: None,
+            token_service: "eocker-registry".to_string(),
+            token_key: None,
+            token_ttl: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+// Options may be set in a JSON config file, as environment variables and as
+// command line flags, each overriding the one before. The variable of an
+// option is its name in upper case prefixed with EOCKER_, and its flag is
+// its name prefixed with --, such as EOCKER_STORAGE_DRIVER and
+// --storage-driver.
+const OPTIONS: &[(&str, &str)] = &[
+    (
+        "config",
+        "JSON file to read options from, keyed by their names",
+    ),
+    ("listen", "address to listen on (default 127.0.0.1:8080)"),
+    ("tls-cert", "PEM certificate chain to serve TLS with"),
+    ("tls-key", "PEM private key of the TLS certificate"),
+    (
+        "upstream",
+        "URL of a registry to mirror, such as https://registry-1.docker.io",
+    ),
+    ("upstream-username", "user to log in to the upstream as"),
+    (
+        "upstream-password",
+        "password to log in to the upstream with",
+    ),
+    (
+        "upstream-ca",
+        "PEM certificates to trust for the upstream (default the system's)",
+    ),
+    (
+        "upstream-tag-ttl-seconds",
+        "how long mirrored tags are served before revalidation (default 300)",
+    ),
+    (
+        "storage-driver",
+        "memory, filesystem or oci (default memory)",
+    ),
+    ("storage-path", "directory to store content in"),
+    (
+        "delete-enabled",
+        "whether content may be deleted (default true)",
+    ),
+    (
+        "upload-ttl-seconds",
+        "how long idle uploads are kept (default 86400)",
+    ),
+    (
+        "gc-grace-seconds",
+        "how long new content is exempt from garbage collection (default 3600)",
+    ),
+    ("quota-total-bytes", "bytes the registry may store"),
+    ("quota-repository-bytes", "bytes each repository may store"),
+    (
+        "quota-repositories",
+        "quotas of specific repositories as <name>=<bytes>,...",
+    ),
+    (
+        "rate-limit-manifests",
+        "manifest GETs per second each client may make",
+    ),
+    (
+        "rate-limit-manifests-burst",
+        "manifest GETs a client may make at once (default the rate)",
+    ),
+    (
+        "rate-limit-blob-bytes",
+        "blob bytes per second each client may pull, charged in full as each pull starts",
+    ),
+    (
+        "rate-limit-blob-bytes-burst",
+        "blob bytes a client may pull at once (default the rate)",
+    ),
+    (
+        "event-buffer-size",
+        "events kept for slow subscribers (default 10)",
+    ),
+    (
+        "event-history-size",
+        "events kept for subscribers that reconnect (default 100)",
+    ),
+    ("ui", "whether to serve the visualizer (default true)"),
+    (
+        "metrics",
+        "whether to serve /metrics, which requires registry:admin access (default true)",
+    ),
+    ("auth", "none, basic or token (default none)"),
+    (
+        "auth-htpasswd",
+        "htpasswd file of the users that may log in",
+    ),
+    (
+        "auth-anonymous-pull",
+        "whether anonymous clients may pull (default false)",
+    ),
+    (
+        "auth-policy",
+        "JSON file of the repositories each user may access",
+    ),
+    (
+        "auth-token-realm",
+        "token endpoint advertised to clients (default /token)",
+    ),
+    (
+        "auth-token-service",
+        "name of the registry in tokens (default eocker-registry)",
+    ),
+    ("auth-token-key", "key to sign tokens with (default random)"),
+    (
+        "auth-token-ttl-seconds",
+        "how long tokens are valid (default 300)",
+    ),
+];
+
+fn env_var(option: &str) -> String {
+    format!("EOCKER_{}", option.to_uppercase().replace('-', "_"))
+}
+
+// Returns the description of the command line flags.
+pub fn usage() -> String {
+    let mut u = String::from("Usage: eocker-registry [--<option> <value>]...\n\nOptions:\n");
+    for (name, help) in OPTIONS {
+        u.push_str(&format!("  --{:<24} {}\n", name, help));
+    }
+    u
+}
+
+fn parse_bool(option: &str, v: &str) -> Result<bool, String> {
+    match v.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("invalid {} {}: expected true or false", option, v)),
+    }
+}
+
+fn parse_secs(option: &str, v: &str) -> Result<Duration, String> {
+    v.parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| format!("invalid {} {}: {}", option, v, e))
+}
+
+// Parses a duration that must not be zero, such as one that sets how often a
+// task runs.
+fn parse_positive_secs(option: &str, v: &str) -> Result<Duration, String> {
+    v.trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|s| *s > 0)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid {} {}: expected a positive number", option, v))
+}
+
+fn parse_bytes(option: &str, v: &str) -> Result<u64, String> {
+    v.trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid {} {}: {}", option, v, e))
+}
+
+fn parse_rate(option: &str, v: &str) -> Result<f64, String> {
+    v.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|r| r.is_finite() && *r > 0.0)
+        .ok_or_else(|| format!("invalid {} {}: expected a positive number", option, v))
+}
+
+// Parses the rate of a budget and its burst, which defaults to the rate but
+// is never less than a single request.
+fn parse_budget(options: &HashMap<String, String>, option: &str) -> Result<Option<Rate>, String> {
+    let burst = format!("{}-burst", option);
+    let per_second = match options.get(option) {
+        None if options.contains_key(&burst) => {
+            return Err(format!("{} requires {} to be set", burst, option))
+        }
+        None => return Ok(None),
+        Some(v) => parse_rate(option, v)?,
+    };
+    let burst = match options.get(&burst) {
+        None => per_second.max(1.0),
+        Some(v) => parse_rate(&burst, v)?.max(1.0),
+    };
+    Ok(Some(Rate { per_second, burst }))
+}
+
+// Parses quotas given as <name>=<bytes> separated by commas.
+fn parse_quotas(option: &str, v: &str) -> Result<HashMap<String, u64>, String> {
+    v.split(',')
+        .filter(|q| !q.trim().is_empty())
+        .map(|q| match q.split_once('=') {
+            Some((name, bytes)) => Ok((name.trim().to_string(), parse_bytes(option, bytes)?)),
+            None => Err(format!("invalid {} {}: expected <name>=<bytes>", option, q)),
+        })
+        .collect()
+}
+
+// Reads the options of a config file, which is a JSON object whose values
+// are strings, numbers or booleans.
+fn read_file(path: &str) -> Result<HashMap<String, String>, String> {
+    let content = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let values: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&content).map_err(|e| format!("invalid {}: {}", path, e))?;
+    values
+        .into_iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => Ok((k, s)),
+            serde_json::Value::Bool(_) | serde_json::Value::Number(_) => Ok((k, v.to_string())),
+            _ => Err(format!(
+                "invalid {} in {}: expected a string, number or boolean",
+                k, path
+            )),
+        })
+        .collect()
+}
+
+// Parses flags given as --<option> <value> or --<option>=<value>.
+fn parse_args(args: impl Iterator<Item = String>) -> Result<HashMap<String, String>, String> {
+    let mut options = HashMap::new();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument {}", arg))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((n, v)) => (n.to_string(), v.to_string()),
+            None => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| format!("missing value of --{}", flag))?;
+                (flag.to_string(), v)
+            }
+        };
+        options.insert(name, value);
+    }
+    Ok(options)
+}
+
+impl Config {
+    // Builds a config from defaults overridden by the config file, the
+    // environment and the command line arguments, in that order.
+    pub fn load(args: impl Iterator<Item = String>) -> Result<Config, String> {
+        Config::load_from(args, |name| env::var(name).ok())
+    }
+
+    // Builds a config like load, reading environment variables with var.
+    fn load_from(
+        args: impl Iterator<Item = String>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, String> {
+        let flags = parse_args(args)?;
+        let mut options = HashMap::new();
+        let file = flags
+            .get("config")
+            .cloned()
+            .or_else(|| var(&env_var("config")));
+        if let Some(path) = file {
+            options.extend(read_file(&path)?);
+        }
+        for (name, _) in OPTIONS {
+            if let Some(v) = var(&env_var(name)) {
+                options.insert(name.to_string(), v);
+            }
+        }
+        options.extend(flags);
+        options.remove("config");
+
+        let mut c = Config::default();
+        for (name, value) in &options {
+            c.set(name, value)?;
+        }
+        // Setting a path alone keeps the behavior of persisting to the
+        // filesystem driver.
+        if c.storage_path.is_some() && !options.contains_key("storage-driver") {
+            c.storage_driver = StorageDriver::Filesystem;
+        }
+        c.tls = match (options.get("tls-cert"), options.get("tls-key")) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            }),
+            (None, None) => None,
+            _ => return Err("tls-cert and tls-key must be set together".to_string()),
+        };
+        if let Some(u) = c.upstream.as_mut() {
+            u.username = options.get("upstream-username").cloned();
+            u.password = options.get("upstream-password").cloned();
+            u.ca = options.get("upstream-ca").map(PathBuf::from);
+            if let Some(v) = options.get("upstream-tag-ttl-seconds") {
+                u.tag_ttl = parse_secs("upstream-tag-ttl-seconds", v)?;
+            }
+            if u.username.is_some() != u.password.is_some() {
+                return Err(
+                    "upstream-username and upstream-password must be set together".to_string(),
+                );
+            }
+        } else if let Some(o) = options.keys().find(|o| o.starts_with("upstream-")) {
+            return Err(format!("{} requires upstream to be set", o));
+        }
+        c.rate_limit = RateLimitConfig {
+            manifests: parse_budget(&options, "rate-limit-manifests")?,
+            blob_bytes: parse_budget(&options, "rate-limit-blob-bytes")?,
+        };
+        c.validate()?;
+        Ok(c)
+    }
+
+    fn set(&mut self, option: &str, v: &str) -> Result<(), String> {
+        let path = || Some(PathBuf::from(v));
+        match option {
+            "listen" => {
+                self.listen = v
+                    .parse()
+                    .map_err(|e| format!("invalid listen {}: {}", v, e))?
+            }
+            // The certificate and key are only valid together, so they are
+            // set once every option is known.
+            "tls-cert" | "tls-key" => (),
+            "upstream" => {
+                self.upstream = Some(UpstreamConfig {
+                    url: v.to_string(),
+                    username: None,
+                    password: None,
+                    ca: None,
+                    tag_ttl: Duration::from_secs(5 * 60),
+                })
+            }
+            // The upstream options are set once the upstream is known.
+            "upstream-username"
+            | "upstream-password"
+            | "upstream-ca"
+            | "upstream-tag-ttl-seconds" => (),
+            "storage-driver" => self.storage_driver = v.parse()?,
+            "storage-path" => self.storage_path = path(),
+            "delete-enabled" => self.delete_enabled = parse_bool(option, v)?,
+            "upload-ttl-seconds" => self.upload_ttl = parse_positive_secs(option, v)?,
+            "gc-grace-seconds" => self.gc_grace = parse_secs(option, v)?,
+            "quota-total-bytes" => self.quota.total = Some(parse_bytes(option, v)?),
+            "quota-repository-bytes" => self.quota.repository = Some(parse_bytes(option, v)?),
+            "quota-repositories" => self.quota.repositories = parse_quotas(option, v)?,
+            // Rates are set along with their bursts once every option is
+            // known.
+            "rate-limit-manifests"
+            | "rate-limit-manifests-burst"
+            | "rate-limit-blob-bytes"
+            | "rate-limit-blob-bytes-burst" => (),
+            "event-buffer-size" => {
+                self.event_buffer_size =
+                    v.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| {
+                        format!(
+                            "invalid event-buffer-size {}: expected a positive number",
+                            v
+                        )
+                    })?
+            }
+            "event-history-size" => {
+                self.event_history_size = v
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid event-history-size {}: expected a number", v))?
+            }
+            "ui" => self.ui = parse_bool(option, v)?,
+            "metrics" => self.metrics = parse_bool(option, v)?,
+            "auth" => self.auth.mode = v.parse()?,
+            "auth-htpasswd" => self.auth.htpasswd = path(),
+            "auth-anonymous-pull" => self.auth.anonymous_pull = parse_bool(option, v)?,
+            "auth-policy" => self.auth.policy = path(),
+            "auth-token-realm" => self.auth.token_realm = Some(v.to_string()),
+            "auth-token-service" => self.auth.token_service = v.to_string(),
+            "auth-token-key" => self.auth.token_key = Some(v.to_string()),
+            "auth-token-ttl-seconds" => self.auth.token_ttl = parse_secs(option, v)?,
+            _ => return Err(format!("unknown option {}", option)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.auth.mode != AuthMode::None && self.auth.htpasswd.is_none() {
+            return Err("auth-htpasswd is required by the auth mode".to_string());
+        }
+        if self.storage_driver != StorageDriver::Memory && self.storage_path.is_none() {
+            return Err("storage-path is required by the storage driver".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::load_from(args.iter().map(|a| a.to_string()), |name| {
+            vars.get(name).cloned()
+        })
+    }
+
+    // Writes a config file that is removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(content: &str) -> ConfigFile {
+            let path = env::temp_dir().join(format!("eocker-config-{}.json", Uuid::new_v4()));
+            fs::write(&path, content).unwrap();
+            ConfigFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn defaults_without_options() {
+        let c = load(&[], &[]).unwrap();
+        assert_eq!(c.listen, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(c.storage_driver, StorageDriver::Memory);
+        assert!(c.tls.is_none() && c.upstream.is_none());
+        assert!(c.rate_limit.manifests.is_none());
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let file = ConfigFile::new(
+            r#"{"listen": "0.0.0.0:1", "gc-grace-seconds": 10, "ui": false, "event-buffer-size": 3}"#,
+        );
+        let vars = [
+            ("EOCKER_CONFIG", file.path()),
+            ("EOCKER_LISTEN", "0.0.0.0:2"),
+            ("EOCKER_GC_GRACE_SECONDS", "20"),
+            ("OTHER", "ignored"),
+        ];
+        let c = load(&["--listen", "0.0.0.0:3"], &vars).unwrap();
+        assert_eq!(c.listen, SocketAddr::from(([0, 0, 0, 0], 3)));
+        assert_eq!(c.gc_grace, Duration::from_secs(20));
+        assert!(!c.ui);
+        assert_eq!(c.event_buffer_size, 3);
+
+        // The file given as a flag is read instead of that of the
+        // environment.
+        let other = ConfigFile::new(r#"{"ui": true, "delete-enabled": "false"}"#);
+        let c = load(&[&format!("--config={}", other.path())], &vars).unwrap();
+        assert!(c.ui && !c.delete_enabled);
+        assert_eq!(c.event_buffer_size, 10);
+    }
+
+    #[test]
+    fn selects_storage_drivers() {
+        let c = load(&["--storage-path", "/data"], &[]).unwrap();
+        assert_eq!(c.storage_driver, StorageDriver::Filesystem);
+        let c = load(&["--storage-driver", "oci", "--storage-path", "/data"], &[]).unwrap();
+        assert_eq!(c.storage_driver, StorageDriver::Layout);
+        let c = load(&[], &[("EOCKER_STORAGE_DRIVER", "memory")]).unwrap();
+        assert_eq!(c.storage_driver, StorageDriver::Memory);
+        assert!(load(&["--storage-driver", "filesystem"], &[])
+            .unwrap_err()
+            .contains("storage-path is required"));
+        assert!(load(&["--storage-driver", "s3"], &[])
+            .unwrap_err()
+            .contains("unknown storage driver s3"));
+    }
+
+    #[test]
+    fn parses_rates_with_their_bursts() {
+        let c = load(
+            &[
+                "--rate-limit-manifests",
+                "0.5",
+                "--rate-limit-blob-bytes=1000",
+            ],
+            &[("EOCKER_RATE_LIMIT_BLOB_BYTES_BURST", "5000")],
+        )
+        .unwrap();
+        let m = c.rate_limit.manifests.unwrap();
+        // Bursts default to the rate, and are never less than one.
+        assert_eq!((m.per_second, m.burst), (0.5, 1.0));
+        let b = c.rate_limit.blob_bytes.unwrap();
+        assert_eq!((b.per_second, b.burst), (1000.0, 5000.0));
+
+        assert_eq!(
+            load(&["--rate-limit-manifests-burst", "10"], &[]).unwrap_err(),
+            "rate-limit-manifests-burst requires rate-limit-manifests to be set"
+        );
+        for rate in &["0", "-1", "inf", "fast"] {
+            assert!(
+                load(&["--rate-limit-manifests", rate], &[]).is_err(),
+                "{}",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn sets_tls_and_upstream_options_together() {
+        let c = load(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"], &[]).unwrap();
+        let tls = c.tls.unwrap();
+        assert_eq!((tls.cert, tls.key), ("cert.pem".into(), "key.pem".into()));
+        assert_eq!(
+            load(&["--tls-cert", "cert.pem"], &[]).unwrap_err(),
+            "tls-cert and tls-key must be set together"
+        );
+
+        let c = load(
+            &[
+                "--upstream",
+                "https://example.com",
+                "--upstream-tag-ttl-seconds",
+                "7",
+            ],
+            &[],
+        )
+        .unwrap();
+        let u = c.upstream.unwrap();
+        assert_eq!(u.url, "https://example.com");
+        assert_eq!(u.tag_ttl, Duration::from_secs(7));
+        assert_eq!(
+            load(&["--upstream-username", "bob"], &[]).unwrap_err(),
+            "upstream-username requires upstream to be set"
+        );
+        assert!(load(
+            &[
+                "--upstream",
+                "https://example.com",
+                "--upstream-username",
+                "bob"
+            ],
+            &[]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parses_quotas() {
+        let c = load(
+            &[
+                "--quota-repositories",
+                "a=10, b/c=20,",
+                "--quota-total-bytes",
+                "100",
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(c.quota.total, Some(100));
+        assert_eq!(c.quota.of("b/c"), Some(20));
+        assert_eq!(c.quota.of("d"), None);
+        assert!(load(&["--quota-repositories", "a"], &[]).is_err());
+        assert!(load(&["--quota-total-bytes", "-1"], &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        for args in &[
+            &["--unknown", "1"][..],
+            &["listen"],
+            &["--listen"],
+            &["--listen", "nowhere"],
+            &["--ui", "maybe"],
+            &["--upload-ttl-seconds", "0"],
+            &["--event-buffer-size", "0"],
+            &["--auth", "basic"],
+        ] {
+            assert!(load(args, &[]).is_err(), "{:?}", args);
+        }
+        assert!(load(&[], &[("EOCKER_UI", "maybe")]).is_err());
+        let file = ConfigFile::new(r#"{"listen": ["0.0.0.0:1"]}"#);
+        assert!(load(&["--config", file.path()], &[])
+            .unwrap_err()
+            .contains("expected a string, number or boolean"));
+        assert!(load(&["--config", "/nonexistent/config.json"], &[]).is_err());
+    }
+}
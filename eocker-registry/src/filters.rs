@@ -1,30 +1,107 @@
+use bytes::Buf;
+use futures::future;
+use futures::{Stream, StreamExt};
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::http::header::{HeaderMap, ACCEPT};
+use warp::http::uri::Authority;
+use warp::http::Method;
+use warp::path::{FullPath, Tail};
 use warp::Filter;
 
 use super::handlers::{
-    blob_exists, get_blob, get_manifest, manifest_exists, send_events, store_blob, store_chunk,
-    store_manifest,
+    authorize_request, blob_exists, collect_garbage, delete_blob, delete_manifest, delete_upload,
+    get_blob, get_manifest, issue_token, limit_request, list_referrers, list_repositories,
+    list_tags, manifest_exists, mount_blob, render_metrics, report_usage, send_events,
+    start_upload, store_blob, store_chunk, store_manifest, store_monolithic_blob, upload_status,
 };
 
+use super::auth::{Access, Auth};
 use super::channel::ChannelMap;
-use super::store::{BlobStore, ManifestStore, PushQuery, UploadStore};
+use super::config::Config;
+use super::gc;
+use super::metrics::Metrics;
+use super::ratelimit::{Budget, Limiter};
+use super::store::{
+    valid_repository, ByteStream, GcQuery, ListQuery, MountQuery, PushQuery, Quotas,
+    ReferrersQuery, Store,
+};
 
-fn with_blob_store(
-    store: BlobStore,
-) -> impl Filter<Extract = (BlobStore,), Error = std::convert::Infallible> + Clone {
+fn with_store(
+    store: Store,
+) -> impl Filter<Extract = (Store,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || store.clone())
 }
 
-fn with_upload_store(
-    store: UploadStore,
-) -> impl Filter<Extract = (UploadStore,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || store.clone())
+fn with_quotas(
+    quotas: Quotas,
+) -> impl Filter<Extract = (Quotas,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || quotas.clone())
 }
 
-fn with_manifest_store(
-    store: ManifestStore,
-) -> impl Filter<Extract = (ManifestStore,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || store.clone())
+fn with_metrics(
+    metrics: Metrics,
+) -> impl Filter<Extract = (Metrics,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
+
+fn with_limiter(
+    limiter: Limiter,
+) -> impl Filter<Extract = (Limiter,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+fn with_gc_lock(
+    lock: gc::Lock,
+) -> impl Filter<Extract = (gc::Lock,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || lock.clone())
+}
+
+fn with_auth(
+    auth: Auth,
+) -> impl Filter<Extract = (Auth,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || auth.clone())
+}
+
+// Extracts the raw query string, which is empty if the request has none.
+fn raw_query() -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
+}
+
+// Decodes the parameters of a query string, keeping those that are repeated.
+fn query_pairs(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        urlencoding::decode(&s).map(|d| d.into_owned()).unwrap_or(s)
+    };
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+// Collects the media types listed in every Accept header of a request, as
+// clients may send several. Parameters such as quality values are dropped.
+fn with_accept() -> impl Filter<Extract = (Vec<String>,), Error = std::convert::Infallible> + Clone
+{
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|t| t.split(';').next())
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect()
+    })
 }
 
 fn with_cm(
@@ -33,44 +110,444 @@ fn with_cm(
     warp::any().map(move || cm.clone())
 }
 
+// Passes the request body to handlers as a stream rather than buffering it in
+// memory.
+fn with_body_stream(
+    metrics: Metrics,
+) -> impl Filter<Extract = (ByteStream,), Error = warp::Rejection> + Clone {
+    warp::body::stream()
+        .and(with_metrics(metrics))
+        .map(into_byte_stream)
+}
+
+fn into_byte_stream<S, B>(body: S, metrics: Metrics) -> ByteStream
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    Box::pin(body.map(move |chunk| {
+        chunk
+            .map(|mut b| {
+                metrics.received(b.remaining() as u64);
+                b.copy_to_bytes(b.remaining())
+            })
+            .map_err(io::Error::other)
+    }))
+}
+
+// Passes the request body to handlers once it has been received.
+fn with_body(
+    metrics: Metrics,
+) -> impl Filter<Extract = (bytes::Bytes,), Error = warp::Rejection> + Clone {
+    warp::body::bytes()
+        .and(with_metrics(metrics))
+        .map(|body: bytes::Bytes, metrics: Metrics| {
+            metrics.received(body.len() as u64);
+            body
+        })
+}
+
+// Records the responses of a route under its name. Routes are boxed so that
+// the type of the registry does not nest too deeply to compile.
+fn tracked<F, R>(
+    name: &'static str,
+    metrics: &Metrics,
+    route: F,
+) -> BoxedFilter<(warp::reply::Response,)>
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    let metrics = metrics.clone();
+    warp::any()
+        .map(Instant::now)
+        .and(route)
+        .map(move |started: Instant, reply: R| {
+            metrics.observe(name, reply.into_response(), started.elapsed())
+        })
+        .boxed()
+}
+
+// Splits the path below /v2/ into a repository name and the parameters of
+// route, whose segments are matched against the end of the path. A "*"
+// segment matches any one segment and is returned as a parameter.
+fn split_route(path: &str, route: &[&str]) -> Option<(String, Vec<String>)> {
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let segments: Vec<&str> = path.split('/').collect();
+    if segments.len() <= route.len() {
+        return None;
+    }
+    let (name, rest) = segments.split_at(segments.len() - route.len());
+    let mut params = vec![];
+    for (segment, r) in rest.iter().zip(route) {
+        match *r {
+            "*" if !segment.is_empty() => params.push(segment.to_string()),
+            "*" => return None,
+            r if r != *segment => return None,
+            _ => (),
+        }
+    }
+    Some((name.join("/"), params))
+}
+
+// Matches /v2/<name>/<route>. Repository names may contain slashes, which
+// warp's path! macro cannot match as a single parameter.
+fn repository(
+    route: &'static [&'static str],
+) -> impl Filter<Extract = (String, Vec<String>), Error = warp::Rejection> + Clone {
+    warp::path("v2")
+        .and(warp::path::tail())
+        .and_then(move |tail: Tail| {
+            future::ready(split_route(tail.as_str(), route).ok_or_else(warp::reject::not_found))
+        })
+        .untuple_one()
+}
+
+// Matches a route without parameters, extracting the repository name.
+fn repository_path(
+    route: &'static [&'static str],
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    repository(route).map(|name, _| name)
+}
+
+// Matches a route with a single parameter, extracting the repository name and
+// the parameter.
+fn repository_param<T>(
+    route: &'static [&'static str],
+) -> impl Filter<Extract = (String, T), Error = warp::Rejection> + Clone
+where
+    T: FromStr + Send + 'static,
+{
+    repository(route)
+        .and_then(|name, params: Vec<String>| {
+            let param = params
+                .first()
+                .and_then(|p| p.parse::<T>().ok())
+                .ok_or_else(warp::reject::not_found);
+            future::ready(param.map(|p| (name, p)))
+        })
+        .untuple_one()
+}
+
+// Routes below /v2/<name>/, used to find the repository a request accesses.
+const REPOSITORY_ROUTES: &[&[&str]] = &[
+    &["manifests", "*"],
+    &["blobs", "uploads", "*"],
+    &["blobs", "uploads"],
+    &["blobs", "*"],
+    &["tags", "list"],
+    &["referrers", "*"],
+];
+
+// Returns the access a request requires, or None if anyone may make it.
+fn required_access(method: &Method, path: &str, query: &str) -> Option<Vec<Access>> {
+    if let Some(ns) = path.strip_prefix("/events/") {
+        return Some(vec![Access::repository(ns, &["pull"])]);
+    }
+    if path.starts_with("/admin/") || path == "/metrics" {
+        return Some(vec![Access::admin()]);
+    }
+    let rest = path.strip_prefix("/v2")?;
+    match rest {
+        "" | "/" => return Some(vec![]),
+        "/_catalog" => return Some(vec![Access::catalog()]),
+        _ => (),
+    }
+    let (name, _) = REPOSITORY_ROUTES
+        .iter()
+        .find_map(|r| split_route(rest.strip_prefix('/')?, r))?;
+    let actions: &[&str] = match *method {
+        Method::GET | Method::HEAD => &["pull"],
+        Method::DELETE => &["delete"],
+        _ => &["pull", "push"],
+    };
+    let mut required = vec![Access::repository(&name, actions)];
+    // Mounting reads the blob from the source repository.
+    if *method == Method::POST {
+        if let Some((_, from)) = query_pairs(query).into_iter().find(|(k, _)| k == "from") {
+            required.push(Access::repository(&from, &["pull"]));
+        }
+    }
+    Some(required)
+}
+
+// Returns the budget that a request spends, along with the repository and
+// reference it pulls. Only manifest and blob GETs are rate limited.
+fn rate_limited(method: &Method, path: &str) -> Option<(Budget, String, String)> {
+    if *method != Method::GET {
+        return None;
+    }
+    let rest = path.strip_prefix("/v2/")?;
+    let (budget, (name, mut params)) = match split_route(rest, &["manifests", "*"]) {
+        Some(r) => (Budget::Manifests, r),
+        None => (Budget::BlobBytes, split_route(rest, &["blobs", "*"])?),
+    };
+    Some((budget, name, params.pop()?))
+}
+
+// Returns the scopes requested from the token endpoint. A scope parameter
+// may hold several scopes separated by spaces.
+fn requested_scopes(query: String) -> Vec<Access> {
+    query_pairs(&query)
+        .into_iter()
+        .filter(|(k, _)| k == "scope")
+        .flat_map(|(_, v)| v.split(' ').filter_map(Access::parse).collect::<Vec<_>>())
+        .collect()
+}
+
 pub fn registry(
-    manifests: ManifestStore,
-    blobs: BlobStore,
-    uploads: UploadStore,
+    store: Store,
     cm: ChannelMap,
+    config: Config,
+    auth: Auth,
+    quotas: Quotas,
+    limiter: Limiter,
+    metrics: Metrics,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    events(cm.clone())
-        .or(support())
-        .or(pull_manifest(manifests.clone(), cm.clone()))
-        .or(pull_blob(blobs.clone(), cm.clone()))
-        .or(check_manifest(manifests.clone(), cm.clone()))
-        .or(check_blob(blobs.clone(), cm.clone()))
-        .or(blob_location())
-        .or(upload_chunk(uploads.clone(), cm.clone()))
-        .or(push_blob_location())
-        .or(push_blob(blobs, uploads, cm.clone()))
-        .or(push_manifest(manifests, cm))
-        .or(warp::path::end()
-            .and(warp::get())
-            .and(warp::fs::file("./static/index.html")))
+    let m = &metrics;
+    let gc_lock = gc::new_lock();
+    tracked("authorize", m, authorize(auth.clone()))
+        .or(tracked(
+            "rate_limit",
+            m,
+            rate_limit(limiter, auth.clone(), store.clone()),
+        ))
+        .or(tracked("token", m, token(auth)))
+        .or(events(cm.clone()))
+        .or(tracked("support", m, support()))
+        .or(tracked("catalog", m, catalog(store.clone())))
+        .or(tracked(
+            "pull_manifest",
+            m,
+            pull_manifest(store.clone(), cm.clone()),
+        ))
+        .or(tracked(
+            "pull_blob",
+            m,
+            pull_blob(store.clone(), cm.clone()),
+        ))
+        .or(tracked("tags", m, tags(store.clone())))
+        .or(tracked("referrers", m, referrers(store.clone())))
+        .or(tracked(
+            "check_manifest",
+            m,
+            check_manifest(store.clone(), cm.clone()),
+        ))
+        .or(tracked(
+            "check_blob",
+            m,
+            check_blob(store.clone(), cm.clone()),
+        ))
+        .or(tracked("check_upload", m, check_upload(store.clone())))
+        .or(tracked("mount", m, mount(store.clone(), cm.clone())))
+        .or(tracked(
+            "push_blob_monolithic",
+            m,
+            push_blob_monolithic(store.clone(), cm.clone(), m.clone()),
+        ))
+        .or(tracked("blob_location", m, blob_location(store.clone())))
+        .or(tracked(
+            "upload_chunk",
+            m,
+            upload_chunk(store.clone(), cm.clone(), m.clone()),
+        ))
+        .or(tracked(
+            "push_blob",
+            m,
+            push_blob(store.clone(), cm.clone(), m.clone()),
+        ))
+        .or(tracked(
+            "push_manifest",
+            m,
+            push_manifest(store.clone(), cm.clone(), m.clone(), gc_lock.clone()),
+        ))
+        .or(tracked(
+            "remove_manifest",
+            m,
+            remove_manifest(store.clone(), config.delete_enabled, cm.clone()),
+        ))
+        .or(tracked(
+            "remove_blob",
+            m,
+            remove_blob(store.clone(), config.delete_enabled, cm.clone()),
+        ))
+        .or(tracked(
+            "cancel_upload",
+            m,
+            cancel_upload(store.clone(), cm.clone()),
+        ))
+        .or(tracked(
+            "gc",
+            m,
+            gc(
+                store.clone(),
+                config.gc_grace,
+                config.delete_enabled,
+                cm.clone(),
+                gc_lock,
+            ),
+        ))
+        .or(tracked("usage", m, usage(quotas.clone())))
+        .or(serve_metrics(
+            config.metrics,
+            metrics.clone(),
+            store,
+            quotas,
+            cm,
+        ))
+        .or(visualizer(config.ui))
 }
 
 pub fn events(
     cm: ChannelMap,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("events" / String)
+    warp::path("events")
+        .and(warp::path::tail())
+        .and_then(|ns: Tail| {
+            // Every namespace subscribed to is kept track of, so only those
+            // that can be repositories are accepted.
+            let ns = ns.as_str().to_string();
+            future::ready(if valid_repository(&ns) {
+                Ok(ns)
+            } else {
+                Err(warp::reject::not_found())
+            })
+        })
         .and(warp::get())
+        .and(warp::header::optional::<String>("Last-Event-ID"))
         .and(with_cm(cm))
         .and_then(send_events)
 }
 
+// The visualizer is built into the binary so that it is served regardless of
+// the directory the registry is run from.
+const VISUALIZER: &str = include_str!("../static/index.html");
+
+// Visualizer
+// GET /
+pub fn visualizer(
+    enabled: bool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .and_then(move || {
+            future::ready(if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            })
+        })
+        .untuple_one()
+        .map(|| warp::reply::html(VISUALIZER))
+}
+
+// Metrics
+// GET /metrics
+pub fn serve_metrics(
+    enabled: bool,
+    metrics: Metrics,
+    store: Store,
+    quotas: Quotas,
+    cm: ChannelMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and_then(move || {
+            future::ready(if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            })
+        })
+        .untuple_one()
+        .and(with_metrics(metrics))
+        .and(with_store(store))
+        .and(with_quotas(quotas))
+        .and(with_cm(cm))
+        .and_then(render_metrics)
+}
+
+// --- Auth
+
+// Authorize
+// Responds to requests that are not authorized for the access they require.
+// Authorized requests are rejected so that they continue on to the routes.
+pub fn authorize(
+    auth: Auth,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(raw_query())
+        .and_then(|method: Method, path: FullPath, query: String| {
+            let required = required_access(&method, path.as_str(), &query);
+            future::ready(required.ok_or_else(warp::reject::not_found))
+        })
+        .and(warp::header::optional::<String>("Authorization"))
+        // The authority is read from the URI as well as the Host header, as
+        // HTTP/2 requests carry it in the former.
+        .and(warp::host::optional().map(|a: Option<Authority>| a.map(|a| a.to_string())))
+        .and(with_auth(auth))
+        .and_then(authorize_request)
+}
+
+// Rate Limit
+// Responds to manifest and blob pulls of clients that have spent their
+// budget. Other requests are rejected so that they continue on to the
+// routes.
+pub fn rate_limit(
+    limiter: Limiter,
+    auth: Auth,
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and_then(|method: Method, path: FullPath| {
+            let limited = rate_limited(&method, path.as_str());
+            future::ready(limited.ok_or_else(warp::reject::not_found))
+        })
+        .and(warp::header::optional::<String>("Authorization"))
+        .and(warp::header::optional::<String>("Range"))
+        .and(warp::addr::remote())
+        .and(with_auth(auth))
+        .and(with_limiter(limiter))
+        .and(with_store(store))
+        .and_then(limit_request)
+}
+
+// Token
+// Issues a bearer token for the requested scopes to clients that log in with
+// Basic credentials, or to anonymous clients.
+// GET /token?service=<service>&scope=<scope>
+pub fn token(
+    auth: Auth,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("token")
+        .and(warp::get())
+        .and(raw_query().map(requested_scopes))
+        .and(warp::header::optional::<String>("Authorization"))
+        .and(with_auth(auth))
+        .and_then(issue_token)
+}
+
 // --- Support
 
 // Specification Support
 // GET /v2/
-// Does not currently support authn / authz checks.
-pub fn support() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2").and(warp::get()).map(|| warp::reply())
+// Clients learn how to authenticate from the challenge of this endpoint.
+pub fn support() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("v2").and(warp::get()).map(warp::reply)
+}
+
+// Catalog
+// GET /v2/_catalog?n=<integer>&last=<repository>
+pub fn catalog(
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "_catalog")
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and(with_store(store))
+        .and_then(list_repositories)
 }
 
 // --- Pull
@@ -78,12 +555,13 @@ pub fn support() -> impl Filter<Extract = impl warp::Reply, Error = warp::Reject
 // Pull Manifest
 // GET /v2/<name>/manifests/<reference>
 pub fn pull_manifest(
-    store: ManifestStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "manifests" / String)
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["manifests", "*"])
         .and(warp::get())
-        .and(with_manifest_store(store))
+        .and(with_accept())
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(get_manifest)
 }
@@ -91,25 +569,52 @@ pub fn pull_manifest(
 // Pull Blob
 // GET /v2/<name>/blobs/<digest>
 pub fn pull_blob(
-    store: BlobStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / String)
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["blobs", "*"])
         .and(warp::get())
-        .and(with_blob_store(store))
+        .and(warp::header::optional::<String>("Range"))
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(get_blob)
 }
 
+// List Tags
+// GET /v2/<name>/tags/list?n=<integer>&last=<tag>
+pub fn tags(
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_path(&["tags", "list"])
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and(with_store(store))
+        .and_then(list_tags)
+}
+
+// List Referrers
+// Lists the manifests that refer to a manifest through their subject field.
+// GET /v2/<name>/referrers/<digest>?artifactType=<type>
+pub fn referrers(
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["referrers", "*"])
+        .and(warp::get())
+        .and(warp::query::<ReferrersQuery>())
+        .and(with_store(store))
+        .and_then(list_referrers)
+}
+
 // Check Manifest
 // HEAD /v2/<name>/manifests/<reference>
 pub fn check_manifest(
-    store: ManifestStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "manifests" / String)
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["manifests", "*"])
         .and(warp::head())
-        .and(with_manifest_store(store))
+        .and(with_accept())
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(manifest_exists)
 }
@@ -117,12 +622,12 @@ pub fn check_manifest(
 // Check Blob
 // HEAD /v2/<name>/blobs/<digest>
 pub fn check_blob(
-    store: BlobStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / String)
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["blobs", "*"])
         .and(warp::head())
-        .and(with_blob_store(store))
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(blob_exists)
 }
@@ -131,53 +636,80 @@ pub fn check_blob(
 // Currently only support monolithic POST / PUT and chunked upload
 
 // Blob Location
-// POST /v2/<name>/blobs/uploads
-pub fn blob_location() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / "uploads")
+// Starts an upload session.
+// POST /v2/<name>/blobs/uploads/
+pub fn blob_location(
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_path(&["blobs", "uploads"])
         .and(warp::post())
-        .map(|name: String| {
-            warp::reply::with_header(
-                (|| warp::http::StatusCode::ACCEPTED)(),
-                "Location",
-                format!("/v2/{}/blobs/uploads/{}", name, Uuid::new_v4()),
-            )
-        })
+        .and(with_store(store))
+        .and_then(start_upload)
+}
+
+// Mount Blob
+// Mounts a blob from another repository, or starts an upload session if it
+// cannot be mounted.
+// POST /v2/<name>/blobs/uploads/?mount=<digest>&from=<repository>
+pub fn mount(
+    store: Store,
+    cm: ChannelMap,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_path(&["blobs", "uploads"])
+        .and(warp::post())
+        .and(warp::query::<MountQuery>())
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and_then(mount_blob)
 }
 
-// Push Blob Location
-// Redirects single POST blob upload to PUT.
+// Monolithic Push
+// Uploads a blob in the request that starts the upload.
 // POST /v2/<name>/blobs/uploads/?digest=<digest>
-pub fn push_blob_location(
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / "uploads")
+pub fn push_blob_monolithic(
+    store: Store,
+    cm: ChannelMap,
+    metrics: Metrics,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_path(&["blobs", "uploads"])
         .and(warp::post())
-        .and(warp::header("Content-Length"))
+        .and(warp::header::<String>("Content-Length"))
         .and(warp::header::exact(
             "Content-Type",
             "application/octet-stream",
         ))
         .and(warp::query::<PushQuery>())
-        .map(|name: String, _: String, _: PushQuery| {
-            warp::reply::with_header(
-                (|| warp::http::StatusCode::ACCEPTED)(),
-                "Location",
-                format!("/v2/{}/blobs/uploads/{}", name, Uuid::new_v4()),
-            )
-        })
+        .and(with_body_stream(metrics))
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and_then(store_monolithic_blob)
+}
+
+// Upload Status
+// Reports how much of an upload has been received so it can be resumed.
+// GET /v2/<name>/blobs/uploads/<uuid>
+pub fn check_upload(
+    store: Store,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<Uuid>(&["blobs", "uploads", "*"])
+        .and(warp::get())
+        .and(with_store(store))
+        .and_then(upload_status)
 }
 
 // Upload Chunk
 // PATCH /v2/<name>/blobs/uploads/<uuid>
 pub fn upload_chunk(
-    store: UploadStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / "uploads" / Uuid)
+    metrics: Metrics,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<Uuid>(&["blobs", "uploads", "*"])
         .and(warp::patch())
         .and(warp::header::optional::<String>("Content-Length"))
         .and(warp::header::optional::<String>("Content-Range"))
-        .and(warp::body::bytes())
-        .and(with_upload_store(store))
+        .and(with_body_stream(metrics))
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(store_chunk)
 }
@@ -186,17 +718,16 @@ pub fn upload_chunk(
 // Could be committing chunked upload or doing monolithic push.
 // PUT /v2/<name>/blobs/uploads/<uuid>?digest=<digest>
 pub fn push_blob(
-    blob_store: BlobStore,
-    upload_store: UploadStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "blobs" / "uploads" / Uuid)
+    metrics: Metrics,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<Uuid>(&["blobs", "uploads", "*"])
         .and(warp::put())
         .and(warp::header("Content-Length"))
         .and(warp::query::<PushQuery>())
-        .and(warp::body::bytes())
-        .and(with_blob_store(blob_store))
-        .and(with_upload_store(upload_store))
+        .and(with_body_stream(metrics))
+        .and(with_store(store))
         .and(with_cm(cm))
         .and_then(store_blob)
 }
@@ -204,14 +735,618 @@ pub fn push_blob(
 // Push Manifest
 // PUT /v2/<name>/manifests/<reference>
 pub fn push_manifest(
-    store: ManifestStore,
+    store: Store,
     cm: ChannelMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("v2" / String / "manifests" / String)
+    metrics: Metrics,
+    gc_lock: gc::Lock,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["manifests", "*"])
         .and(warp::put())
         .and(warp::header("Content-Type"))
-        .and(warp::body::bytes())
-        .and(with_manifest_store(store))
+        .and(with_body(metrics))
+        .and(with_store(store))
         .and(with_cm(cm))
+        .and(with_gc_lock(gc_lock))
         .and_then(store_manifest)
 }
+
+// --- Delete
+
+// Delete Manifest
+// Deleting by digest removes the manifest and all tags that reference it,
+// deleting by tag only removes the tag.
+// DELETE /v2/<name>/manifests/<reference>
+pub fn remove_manifest(
+    store: Store,
+    enabled: bool,
+    cm: ChannelMap,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["manifests", "*"])
+        .and(warp::delete())
+        .and(warp::any().map(move || enabled))
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and_then(delete_manifest)
+}
+
+// Delete Blob
+// DELETE /v2/<name>/blobs/<digest>
+pub fn remove_blob(
+    store: Store,
+    enabled: bool,
+    cm: ChannelMap,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<String>(&["blobs", "*"])
+        .and(warp::delete())
+        .and(warp::any().map(move || enabled))
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and_then(delete_blob)
+}
+
+// Cancel Upload
+// DELETE /v2/<name>/blobs/uploads/<uuid>
+pub fn cancel_upload(
+    store: Store,
+    cm: ChannelMap,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    repository_param::<Uuid>(&["blobs", "uploads", "*"])
+        .and(warp::delete())
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and_then(delete_upload)
+}
+
+// --- Admin
+
+// Garbage Collection
+// Removes blobs and, with untagged, manifests that nothing references. GET
+// only reports what would be removed, as does POST with dryRun.
+// GET /admin/gc?untagged=<bool>
+// POST /admin/gc?dryRun=<bool>&untagged=<bool>
+pub fn gc(
+    store: Store,
+    grace: Duration,
+    enabled: bool,
+    cm: ChannelMap,
+    gc_lock: gc::Lock,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "gc")
+        .and(
+            warp::get()
+                .map(|| true)
+                .or(warp::post().map(|| false))
+                .unify(),
+        )
+        .and(warp::query::<GcQuery>())
+        .and(warp::any().map(move || grace))
+        .and(warp::any().map(move || enabled))
+        .and(with_store(store))
+        .and(with_cm(cm))
+        .and(with_gc_lock(gc_lock))
+        .and_then(collect_garbage)
+}
+
+// Usage
+// Reports the bytes stored by the registry and each repository, along with
+// their quotas.
+// GET /admin/usage
+pub fn usage(
+    quotas: Quotas,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "usage")
+        .and(warp::get())
+        .and(with_quotas(quotas))
+        .and_then(report_usage)
+}
+
+// Builds the routes of a registry backed by memory storage, returning its
+// storage along with them.
+#[cfg(test)]
+pub async fn test_registry(
+    config: Config,
+) -> (
+    Store,
+    impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+) {
+    use super::{auth, channel, metrics, ratelimit, store};
+    let quotas = store::new_quota_store(store::new_memory_store(), config.quota.clone())
+        .await
+        .unwrap();
+    let local: Store = quotas.clone();
+    let routes = registry(
+        local.clone(),
+        channel::new_channel_map(config.event_buffer_size, config.event_history_size),
+        config.clone(),
+        auth::from_config(&config.auth, false).unwrap(),
+        quotas,
+        ratelimit::new_limiter(&config.rate_limit),
+        metrics::new_metrics(),
+    );
+    (local, routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::{QuotaConfig, Rate, RateLimitConfig};
+    use super::super::{channel, handlers};
+    use super::*;
+    use bytes::Bytes;
+    use eocker::digest::Hash;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use warp::http::{Response, StatusCode};
+
+    const MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+    const INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+    fn digest(content: &[u8]) -> String {
+        Hash::of("sha256", content).unwrap().to_string()
+    }
+
+    fn header<'a>(res: &'a Response<Bytes>, name: &str) -> &'a str {
+        res.headers()[name].to_str().unwrap()
+    }
+
+    fn json(res: &Response<Bytes>) -> serde_json::Value {
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    fn error_code(res: &Response<Bytes>) -> String {
+        json(res)["errors"][0]["code"].as_str().unwrap().to_string()
+    }
+
+    async fn send<F>(routes: &F, method: &str, path: &str) -> Response<Bytes>
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .reply(routes)
+            .await
+    }
+
+    // Pushes a blob in the request that starts its upload.
+    async fn push_blob<F>(routes: &F, repo: &str, content: &[u8]) -> Response<Bytes>
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/v2/{}/blobs/uploads/?digest={}",
+                repo,
+                digest(content)
+            ))
+            .header("Content-Type", "application/octet-stream")
+            .body(content)
+            .reply(routes)
+            .await
+    }
+
+    async fn push_manifest<F>(
+        routes: &F,
+        repo: &str,
+        reference: &str,
+        media_type: &str,
+        content: &str,
+    ) -> Response<Bytes>
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        warp::test::request()
+            .method("PUT")
+            .path(&format!("/v2/{}/manifests/{}", repo, reference))
+            .header("Content-Type", media_type)
+            .body(content)
+            .reply(routes)
+            .await
+    }
+
+    // An image manifest with the config blob {} and fields added to its end.
+    fn image(extra: &str) -> String {
+        format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"{}"}},"layers":[]{}}}"#,
+            MANIFEST,
+            digest(b"{}"),
+            extra
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_cancels_and_expires_uploads() {
+        let (store, routes) = test_registry(Config::default()).await;
+        let start = |routes| async move {
+            let res = send(routes, "POST", "/v2/a/blobs/uploads/").await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            let location = header(&res, "Location").to_string();
+            let res = warp::test::request()
+                .method("PATCH")
+                .path(&location)
+                .body("hel")
+                .reply(routes)
+                .await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            assert_eq!(header(&res, "Range"), "0-2");
+            location
+        };
+
+        let location = start(&routes).await;
+        let res = send(&routes, "GET", &location).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&res, "Range"), "0-2");
+        assert_eq!(header(&res, "Location"), location);
+        assert!(location.ends_with(header(&res, "Docker-Upload-UUID")));
+
+        let res = send(&routes, "DELETE", &location).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        for method in ["GET", "DELETE"].iter() {
+            let res = send(&routes, method, &location).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(error_code(&res), "BLOB_UPLOAD_UNKNOWN");
+        }
+
+        // Uploads are only expired once they have not received content for
+        // the ttl.
+        let location = start(&routes).await;
+        let cm = channel::new_channel_map(10, 100);
+        handlers::expire_uploads(store.clone(), cm.clone(), Duration::from_secs(60)).await;
+        assert_eq!(
+            send(&routes, "GET", &location).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        handlers::expire_uploads(store.clone(), cm, Duration::from_millis(10)).await;
+        let res = send(&routes, "GET", &location).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_code(&res), "BLOB_UPLOAD_UNKNOWN");
+        assert_eq!(store.active_uploads().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn pushes_blobs_in_the_request_that_starts_the_upload() {
+        let (store, routes) = test_registry(Config::default()).await;
+        let d = digest(b"layer");
+        let res = push_blob(&routes, "a", b"layer").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(header(&res, "Location"), format!("/v2/a/blobs/{}", d));
+        assert_eq!(header(&res, "Docker-Content-Digest"), d);
+        let res = send(&routes, "GET", &format!("/v2/a/blobs/{}", d)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().as_ref(), b"layer");
+
+        // Content that does not match its digest is not stored, and its
+        // upload is not left behind.
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v2/a/blobs/uploads/?digest={}", digest(b"other")))
+            .header("Content-Type", "application/octet-stream")
+            .body("layer")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(&res), "DIGEST_INVALID");
+        assert_eq!(store.active_uploads().await.unwrap(), 0);
+        let res = send(
+            &routes,
+            "HEAD",
+            &format!("/v2/a/blobs/{}", digest(b"other")),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mounts_blobs_from_other_repositories() {
+        let (_, routes) = test_registry(Config::default()).await;
+        let d = digest(b"layer");
+        assert_eq!(
+            push_blob(&routes, "a", b"layer").await.status(),
+            StatusCode::CREATED
+        );
+        let mount = |from: &str, digest: &str| {
+            format!("/v2/b/blobs/uploads/?mount={}&from={}", digest, from)
+        };
+
+        let res = send(&routes, "POST", &mount("a", &d)).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(header(&res, "Location"), format!("/v2/b/blobs/{}", d));
+        assert_eq!(header(&res, "Docker-Content-Digest"), d);
+        let res = send(&routes, "GET", &format!("/v2/b/blobs/{}", d)).await;
+        assert_eq!(res.body().as_ref(), b"layer");
+
+        // Blobs the source repository does not hold are uploaded instead.
+        for path in [mount("c", &d), mount("a", &digest(b"missing"))].iter() {
+            let res = send(&routes, "POST", path).await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            assert!(header(&res, "Location").starts_with("/v2/b/blobs/uploads/"));
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_manifest_media_types() {
+        let (_, routes) = test_registry(Config::default()).await;
+        push_blob(&routes, "a", b"{}").await;
+        let manifest = image("");
+        let manifest_digest = digest(manifest.as_bytes());
+        let res = push_manifest(&routes, "a", &manifest_digest, MANIFEST, &manifest).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let index = format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","manifests":[{{"mediaType":"{}","size":{},"digest":"{}","platform":{{"architecture":"amd64","os":"linux"}}}}]}}"#,
+            INDEX,
+            MANIFEST,
+            manifest.len(),
+            manifest_digest
+        );
+        let res = push_manifest(&routes, "a", "latest", INDEX, &index).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let pull = |method: &'static str, accept: &'static str| {
+            warp::test::request()
+                .method(method)
+                .path("/v2/a/manifests/latest")
+                .header("Accept", accept)
+                .reply(&routes)
+        };
+        for accept in [INDEX, "*/*", "application/*"].iter() {
+            let res = pull("GET", accept).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", accept);
+            assert_eq!(header(&res, "Content-Type"), INDEX);
+            assert_eq!(res.body().as_ref(), index.as_bytes());
+        }
+        let res = send(&routes, "GET", "/v2/a/manifests/latest").await;
+        assert_eq!(header(&res, "Content-Type"), INDEX);
+
+        // Clients that do not accept indexes are served the linux/amd64
+        // manifest.
+        for method in ["GET", "HEAD"].iter() {
+            let res = pull(
+                method,
+                "text/plain;q=0.5, application/vnd.oci.image.manifest.v1+json",
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(&res, "Content-Type"), MANIFEST);
+            assert_eq!(header(&res, "Docker-Content-Digest"), manifest_digest);
+        }
+
+        let docker = "application/vnd.docker.distribution.manifest.v2+json";
+        for method in ["GET", "HEAD"].iter() {
+            let res = pull(method, docker).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+            if *method == "GET" {
+                assert_eq!(error_code(&res), "MANIFEST_UNKNOWN");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_referrers() {
+        let (_, routes) = test_registry(Config::default()).await;
+        push_blob(&routes, "a", b"{}").await;
+        let subject = image("");
+        let subject_digest = digest(subject.as_bytes());
+        push_manifest(&routes, "a", "latest", MANIFEST, &subject).await;
+        let referrer = |artifact_type: &str| {
+            image(&format!(
+                r#","artifactType":"{}","subject":{{"mediaType":"{}","size":{},"digest":"{}"}}"#,
+                artifact_type,
+                MANIFEST,
+                subject.len(),
+                subject_digest
+            ))
+        };
+        for artifact_type in [
+            "application/vnd.example.sbom",
+            "application/vnd.example.sig",
+        ]
+        .iter()
+        {
+            let m = referrer(artifact_type);
+            let res = push_manifest(&routes, "a", &digest(m.as_bytes()), MANIFEST, &m).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(header(&res, "OCI-Subject"), subject_digest);
+        }
+
+        let path = format!("/v2/a/referrers/{}", subject_digest);
+        let res = send(&routes, "GET", &path).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "Content-Type"), INDEX);
+        assert!(res.headers().get("OCI-Filters-Applied").is_none());
+        let mut types: Vec<String> = json(&res)["manifests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["artifactType"].as_str().unwrap().to_string())
+            .collect();
+        types.sort();
+        assert_eq!(
+            types,
+            vec![
+                "application/vnd.example.sbom",
+                "application/vnd.example.sig"
+            ]
+        );
+
+        let res = send(
+            &routes,
+            "GET",
+            &format!("{}?artifactType=application/vnd.example.sig", path),
+        )
+        .await;
+        assert_eq!(header(&res, "OCI-Filters-Applied"), "artifactType");
+        let manifests = json(&res)["manifests"].as_array().unwrap().clone();
+        assert_eq!(manifests.len(), 1);
+        let sig = referrer("application/vnd.example.sig");
+        assert_eq!(manifests[0]["digest"], digest(sig.as_bytes()));
+
+        // Subjects without referrers have an empty list.
+        let res = send(&routes, "GET", &format!("/v2/a/referrers/{}", digest(b"x"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json(&res)["manifests"], serde_json::json!([]));
+        let res = send(&routes, "GET", "/v2/a/referrers/sha256:nope").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(&res), "DIGEST_INVALID");
+    }
+
+    #[tokio::test]
+    async fn collects_garbage_on_request() {
+        let config = Config {
+            gc_grace: Duration::from_secs(0),
+            ..Config::default()
+        };
+        let (_, routes) = test_registry(config).await;
+        push_blob(&routes, "a", b"unused").await;
+        let d = digest(b"unused");
+        let blob = format!("/v2/a/blobs/{}", d);
+
+        for (method, path) in [("GET", "/admin/gc"), ("POST", "/admin/gc?dryRun=true")].iter() {
+            let res = send(&routes, method, path).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(&res, "Content-Type"), "application/json");
+            let report = json(&res);
+            assert_eq!(report["dryRun"], true);
+            assert_eq!(report["repositories"][0]["name"], "a");
+            assert_eq!(report["repositories"][0]["blobsUnlinked"][0], d);
+            assert_eq!(report["blobsRemoved"][0], d);
+            assert_eq!(send(&routes, "HEAD", &blob).await.status(), StatusCode::OK);
+        }
+
+        let res = send(&routes, "POST", "/admin/gc").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report = json(&res);
+        assert_eq!(report["dryRun"], false);
+        assert_eq!(report["blobsRemoved"][0], d);
+        assert_eq!(
+            send(&routes, "HEAD", &blob).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn only_reports_garbage_when_deletes_are_disabled() {
+        let config = Config {
+            delete_enabled: false,
+            ..Config::default()
+        };
+        let (_, routes) = test_registry(config).await;
+        let res = send(&routes, "POST", "/admin/gc").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error_code(&res), "UNSUPPORTED");
+        let res = send(&routes, "GET", "/admin/gc").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json(&res)["dryRun"], true);
+    }
+
+    #[tokio::test]
+    async fn reports_usage_against_quotas() {
+        let mut repositories = HashMap::new();
+        repositories.insert("b".to_string(), 100);
+        let config = Config {
+            quota: QuotaConfig {
+                total: None,
+                repository: Some(8),
+                repositories,
+            },
+            ..Config::default()
+        };
+        let (_, routes) = test_registry(config).await;
+        for repo in ["a", "b"].iter() {
+            assert_eq!(
+                push_blob(&routes, repo, b"{}").await.status(),
+                StatusCode::CREATED
+            );
+        }
+        let res = push_blob(&routes, "a", b"too large!").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(&res), "DENIED");
+
+        let res = send(&routes, "GET", "/admin/usage").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "Content-Type"), "application/json");
+        // Content held by several repositories counts once in the total.
+        assert_eq!(
+            json(&res),
+            serde_json::json!({
+                "totalBytes": 2,
+                "quotaBytes": null,
+                "repositories": [
+                    {"name": "a", "bytes": 2, "quotaBytes": 8},
+                    {"name": "b", "bytes": 2, "quotaBytes": 100},
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pulls_over_budget_with_retry_after() {
+        let config = Config {
+            rate_limit: RateLimitConfig {
+                manifests: Some(Rate {
+                    per_second: 0.25,
+                    burst: 2.0,
+                }),
+                blob_bytes: None,
+            },
+            ..Config::default()
+        };
+        let (_, routes) = test_registry(config).await;
+        let pull = |ip: [u8; 4]| {
+            warp::test::request()
+                .path("/v2/a/manifests/latest")
+                .remote_addr(SocketAddr::from((ip, 1234)))
+                .reply(&routes)
+        };
+        for _ in 0..2 {
+            assert_eq!(pull([10, 0, 0, 1]).await.status(), StatusCode::NOT_FOUND);
+        }
+        let res = pull([10, 0, 0, 1]).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["Retry-After"], "4");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["errors"][0]["code"], "TOOMANYREQUESTS");
+        assert_eq!(body["errors"][0]["detail"], "retry in 4 seconds");
+        // Other addresses have budgets of their own.
+        assert_eq!(pull([10, 0, 0, 2]).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn subscribes_only_to_repository_names() {
+        let cm = channel::new_channel_map(10, 100);
+        let routes = events(cm.clone());
+        for path in ["/events/", "/events/a//b", "/events/Upper", "/events/a/-b"].iter() {
+            let res = send(&routes, "GET", path).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        assert!(cm.lock().await.subscribers().is_empty());
+    }
+
+    #[test]
+    fn metrics_require_admin_access() {
+        assert_eq!(
+            required_access(&Method::GET, "/metrics", ""),
+            Some(vec![Access::admin()])
+        );
+    }
+
+    #[test]
+    fn requests_require_access_to_their_repository() {
+        assert_eq!(
+            required_access(&Method::GET, "/v2/a/b/manifests/latest", ""),
+            Some(vec![Access::repository("a/b", &["pull"])])
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/v2/a/blobs/uploads/", "mount=x&from=c"),
+            Some(vec![
+                Access::repository("a", &["pull", "push"]),
+                Access::repository("c", &["pull"]),
+            ])
+        );
+        assert_eq!(required_access(&Method::GET, "/v2/", ""), Some(vec![]));
+        assert_eq!(required_access(&Method::GET, "/", ""), None);
+    }
+}
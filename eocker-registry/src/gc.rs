@@ -0,0 +1,396 @@
+use eocker::types::MediaType;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use super::store::{parse_manifest, referrer, Manifest, Store, StoreResult, Stored};
+
+// Lock is held by manifest pushes from when the content they reference is
+// checked until they are stored, and by collections that remove content for
+// as long as they run, so that no manifest is pushed that references content
+// a collection removes.
+pub type Lock = Arc<RwLock<()>>;
+
+pub fn new_lock() -> Lock {
+    Arc::new(RwLock::new(()))
+}
+
+// Options of a garbage collection.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    // Whether to only report what would be removed.
+    pub dry_run: bool,
+    // Whether manifests that no tag references, directly or through an
+    // index, are removed as well.
+    pub untagged: bool,
+    // How long content is exempt from collection after it is stored.
+    pub grace: Duration,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub dry_run: bool,
+    pub repositories: Vec<RepositoryReport>,
+    // Blobs whose content was removed because no repository links to them
+    // anymore.
+    pub blobs_removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryReport {
+    pub name: String,
+    pub manifests_removed: Vec<String>,
+    pub blobs_unlinked: Vec<String>,
+}
+
+// Collects the garbage of every repository with mark and sweep. Every
+// manifest is marked, or with untagged only those that are tagged, and so are
+// the config, layers and child manifests they reference. Blob links that were
+// not marked are removed, followed by the content of blobs no repository links
+// to. Manifests cannot be pushed while content is removed, but blobs can be
+// uploaded, so content stored within the grace period is never removed, as it
+// may belong to a push in progress. Dry runs do not hold up pushes.
+pub async fn collect(store: &Store, options: &Options, lock: &Lock) -> StoreResult<Report> {
+    let _collecting = if options.dry_run {
+        None
+    } else {
+        Some(lock.write().await)
+    };
+    let cutoff = SystemTime::now()
+        .checked_sub(options.grace)
+        .unwrap_or(UNIX_EPOCH);
+    let mut manifests: BTreeMap<String, Vec<Stored>> = BTreeMap::new();
+    for m in store.stored_manifests().await? {
+        manifests.entry(m.repo.clone()).or_default().push(m);
+    }
+    let mut links: BTreeMap<String, Vec<Stored>> = BTreeMap::new();
+    for l in store.blob_links().await? {
+        links.entry(l.repo.clone()).or_default().push(l);
+    }
+    let repos: Vec<String> = manifests
+        .keys()
+        .chain(links.keys())
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut repositories = vec![];
+    let mut unlinked = HashSet::new();
+    let mut linked = HashSet::new();
+    for repo in sorted(repos) {
+        let (r, kept) = collect_repository(
+            store,
+            options,
+            cutoff,
+            &repo,
+            manifests.remove(&repo).unwrap_or_default(),
+            links.remove(&repo).unwrap_or_default(),
+        )
+        .await?;
+        linked.extend(kept);
+        unlinked.extend(r.blobs_unlinked.iter().cloned());
+        if !r.manifests_removed.is_empty() || !r.blobs_unlinked.is_empty() {
+            repositories.push(r);
+        }
+    }
+
+    // A dry run leaves the links in place, so blobs that would lose their
+    // last link are added to those purged.
+    let mut removed: HashSet<String> = store
+        .purge_blobs(cutoff, options.dry_run)
+        .await?
+        .into_iter()
+        .collect();
+    removed.extend(unlinked.into_iter().filter(|d| !linked.contains(d)));
+    Ok(Report {
+        dry_run: options.dry_run,
+        repositories,
+        blobs_removed: sorted(removed),
+    })
+}
+
+// Marks and sweeps a repository. Returns its report along with the blobs
+// that remain linked to it.
+async fn collect_repository(
+    store: &Store,
+    options: &Options,
+    cutoff: SystemTime,
+    repo: &str,
+    manifests: Vec<Stored>,
+    links: Vec<Stored>,
+) -> StoreResult<(RepositoryReport, HashSet<String>)> {
+    let linked: HashSet<&str> = links.iter().map(|l| l.digest.as_str()).collect();
+    let mut stored = HashMap::new();
+    for m in &manifests {
+        if let Some(content) = store.get_manifest(repo, &m.digest).await? {
+            stored.insert(m.digest.clone(), content);
+        }
+    }
+
+    let mut roots = vec![];
+    for m in &manifests {
+        if !options.untagged || m.created >= cutoff {
+            roots.push(m.digest.clone());
+        }
+    }
+    if options.untagged {
+        for tag in store.tags(repo).await?.unwrap_or_default() {
+            if let Some(m) = store.get_manifest(repo, &tag).await? {
+                roots.push(m.digest);
+            }
+        }
+    }
+
+    let mut mark = Mark::default();
+    mark.manifests(store, repo, &linked, &mut stored, roots)
+        .await?;
+    // Referrers are kept for as long as their subject is.
+    if options.untagged {
+        loop {
+            let referrers: Vec<String> = stored
+                .iter()
+                .filter(|(d, _)| !mark.manifests.contains(*d))
+                .filter(|(_, m)| {
+                    referrer(m)
+                        .map(|(s, _)| mark.manifests.contains(&s))
+                        .unwrap_or(false)
+                })
+                .map(|(d, _)| d.clone())
+                .collect();
+            if referrers.is_empty() {
+                break;
+            }
+            mark.manifests(store, repo, &linked, &mut stored, referrers)
+                .await?;
+        }
+    }
+
+    let manifests_removed: Vec<String> = sorted(
+        manifests
+            .iter()
+            .map(|m| m.digest.clone())
+            .filter(|d| !mark.manifests.contains(d)),
+    );
+    // Blobs cannot be swept safely if the references of a manifest are
+    // unknown.
+    let blobs_unlinked: Vec<String> = if mark.complete {
+        sorted(
+            links
+                .iter()
+                .filter(|l| l.created < cutoff)
+                .map(|l| l.digest.clone())
+                .filter(|d| !mark.blobs.contains(d) && !mark.manifests.contains(d))
+                // Removing a manifest removes its content as well.
+                .filter(|d| !manifests_removed.contains(d)),
+        )
+    } else {
+        log::warn!(
+            "not collecting blobs of {}, which has manifests that cannot be parsed",
+            repo
+        );
+        vec![]
+    };
+
+    if !options.dry_run {
+        for d in &manifests_removed {
+            store.delete_manifest(repo, d).await?;
+        }
+        for d in &blobs_unlinked {
+            store.delete_blob(repo, d).await?;
+        }
+    }
+    let kept = links
+        .into_iter()
+        .map(|l| l.digest)
+        .filter(|d| !blobs_unlinked.contains(d) && !manifests_removed.contains(d))
+        .collect();
+    Ok((
+        RepositoryReport {
+            name: repo.to_string(),
+            manifests_removed,
+            blobs_unlinked,
+        },
+        kept,
+    ))
+}
+
+// Mark holds the manifests and blobs found to be referenced.
+struct Mark {
+    manifests: HashSet<String>,
+    blobs: HashSet<String>,
+    // Whether the references of every marked manifest are known.
+    complete: bool,
+}
+
+impl Default for Mark {
+    fn default() -> Mark {
+        Mark {
+            manifests: HashSet::new(),
+            blobs: HashSet::new(),
+            complete: true,
+        }
+    }
+}
+
+impl Mark {
+    // Marks manifests along with everything they reference. Manifests that
+    // are only stored as blobs, such as the children of an index in a seeded
+    // image layout, are read as they are found.
+    async fn manifests(
+        &mut self,
+        store: &Store,
+        repo: &str,
+        linked: &HashSet<&str>,
+        stored: &mut HashMap<String, Manifest>,
+        mut pending: Vec<String>,
+    ) -> StoreResult<()> {
+        while let Some(digest) = pending.pop() {
+            if !self.manifests.insert(digest.clone()) {
+                continue;
+            }
+            if !stored.contains_key(&digest) && linked.contains(digest.as_str()) {
+                if let Some(m) = store.get_manifest(repo, &digest).await? {
+                    stored.insert(digest.clone(), m);
+                }
+            }
+            let m = match stored.get(&digest) {
+                None => continue,
+                Some(m) => m,
+            };
+            let media_type = MediaType::from(m.content_type.as_str());
+            let refs = match parse_manifest(&media_type, &m.content) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::warn!("could not parse manifest {} of {}: {}", digest, repo, e);
+                    self.complete = false;
+                    continue;
+                }
+            };
+            for (data_type, d) in refs {
+                match data_type {
+                    "Manifest" => pending.push(d.digest.to_string()),
+                    _ => {
+                        self.blobs.insert(d.digest.to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sorted(digests: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut v: Vec<String> = digests.into_iter().collect();
+    v.sort();
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{new_memory_store, ByteStream};
+    use bytes::Bytes;
+    use eocker::digest::Hash;
+    use futures::stream;
+
+    const REPO: &str = "library/app";
+
+    async fn push_blob(store: &Store, content: &'static [u8]) -> String {
+        let digest = Hash::of("sha256", content).unwrap();
+        let id = store.start_upload(REPO).await.unwrap();
+        let chunk: ByteStream =
+            Box::pin(stream::once(async move { Ok(Bytes::from_static(content)) }));
+        store
+            .commit_upload(REPO, &id, &digest, chunk)
+            .await
+            .unwrap();
+        digest.to_string()
+    }
+
+    async fn push_image(store: &Store, tag: &str, config: &str) -> String {
+        let content = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"{}"}},"layers":[]}}"#,
+            config
+        );
+        let m = Manifest {
+            digest: Hash::of("sha256", content.as_bytes()).unwrap().to_string(),
+            content_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            content: content.into(),
+        };
+        store.put_manifest(REPO, tag, m.clone()).await.unwrap();
+        m.digest
+    }
+
+    fn options(dry_run: bool, grace: Duration) -> Options {
+        Options {
+            dry_run,
+            untagged: true,
+            grace,
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_unreferenced_content() {
+        let store = new_memory_store();
+        let config = push_blob(&store, b"{}").await;
+        let unused = push_blob(&store, b"unused").await;
+        let manifest = push_image(&store, "latest", &config).await;
+        let lock = new_lock();
+
+        // Nothing is removed within the grace period.
+        let report = collect(&store, &options(false, Duration::from_secs(60)), &lock)
+            .await
+            .unwrap();
+        assert!(report.repositories.is_empty());
+        assert!(report.blobs_removed.is_empty());
+
+        let report = collect(&store, &options(true, Duration::from_secs(0)), &lock)
+            .await
+            .unwrap();
+        assert_eq!(report.blobs_removed, vec![unused.clone()]);
+        assert!(store.blob_size(REPO, &unused).await.unwrap().is_some());
+
+        let report = collect(&store, &options(false, Duration::from_secs(0)), &lock)
+            .await
+            .unwrap();
+        assert_eq!(report.blobs_removed, vec![unused.clone()]);
+        assert!(store.blob_size(REPO, &unused).await.unwrap().is_none());
+        assert!(store.blob_size(REPO, &config).await.unwrap().is_some());
+        assert!(store.get_manifest(REPO, &manifest).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn purges_only_content_stored_before_the_cutoff() {
+        let store = new_memory_store();
+        let before = SystemTime::now();
+        let digest = push_blob(&store, b"unused").await;
+        assert!(store.delete_blob(REPO, &digest).await.unwrap());
+        assert!(store.purge_blobs(before, false).await.unwrap().is_empty());
+        let purged = store.purge_blobs(SystemTime::now(), false).await.unwrap();
+        assert_eq!(purged, vec![digest]);
+    }
+
+    #[tokio::test]
+    async fn collections_wait_for_pushes_but_dry_runs_do_not() {
+        let store = new_memory_store();
+        let lock = new_lock();
+        let (dry_run, run) = (
+            options(true, Duration::from_secs(0)),
+            options(false, Duration::from_secs(0)),
+        );
+        let pushing = lock.read().await;
+        let collected =
+            tokio::time::timeout(Duration::from_millis(50), collect(&store, &dry_run, &lock));
+        assert!(collected.await.is_ok());
+        let collected =
+            tokio::time::timeout(Duration::from_millis(50), collect(&store, &run, &lock));
+        assert!(collected.await.is_err());
+        drop(pushing);
+        collect(&store, &run, &lock).await.unwrap();
+    }
+}
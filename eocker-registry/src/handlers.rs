@@ -1,117 +1,391 @@
-use bytes::{BufMut, Bytes};
-use eocker;
-use eocker::types::MediaType;
+use bytes::Bytes;
+use eocker::digest::Hash;
+use eocker::types::{Media, MediaType};
 use futures::Stream;
 use futures::StreamExt;
-use sha2::{Digest, Sha256};
-use std::convert::TryFrom;
-use std::io::Write;
-use std::{collections::HashMap, convert::Infallible};
-use tokio::sync::broadcast;
-use tokio_stream::wrappers::BroadcastStream;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
-use warp::http::{Method, StatusCode};
+use warp::http::header::{HeaderValue, RETRY_AFTER, WWW_AUTHENTICATE};
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::Body;
 
-use super::channel::{send, ChannelMap, Event, Ref};
-use super::store::{BlobStore, Manifest, ManifestStore, PushQuery, UploadStore};
+use super::auth::{self, Access, Auth, Denial};
+use super::channel::{self, send, ChannelMap, Message, Ref};
+use super::codes::Errors;
+use super::gc;
+use super::metrics::{Gauges, Metrics};
+use super::ratelimit::{Budget, Limiter};
+use super::store::{
+    parse_manifest, referrer, valid_repository, valid_tag, ByteStream, Catalog, GcQuery, ListQuery,
+    Manifest, MountQuery, PushQuery, Quotas, ReferrersQuery, Store, StoreError, TagList,
+};
+
+// Converts a storage failure into a response. Failures that are not caused by
+// the client are logged and reported as internal errors.
+fn store_error(e: StoreError) -> warp::http::Result<Response<Bytes>> {
+    match e {
+        StoreError::RangeInvalid(size) => Errors::BlobUploadInvalid.response_with_status(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            format!("chunk must start at {}", size),
+        ),
+        StoreError::DigestInvalid(detail) => Errors::DigestInvalid.response(detail),
+        StoreError::NameInvalid(name) => Errors::NameInvalid.response(name),
+        StoreError::UploadUnknown(id) => Errors::BlobUploadUnknown.response(id),
+        StoreError::Denied(detail) => Errors::Denied.response(detail),
+        StoreError::Io(e) => {
+            log::error!("storage failure: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Bytes::new())
+        }
+    }
+}
+
+// Responds to a request that is not authorized for the access it requires,
+// and rejects authorized requests so that they reach the routes.
+pub async fn authorize_request(
+    required: Vec<Access>,
+    authorization: Option<String>,
+    host: Option<String>,
+    auth: Auth,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Verifying a password hash can take milliseconds of CPU, which must not
+    // hold up the other requests of an async worker.
+    let (required, result) = {
+        let auth = auth.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = auth.authorize(authorization.as_deref(), &required);
+            (required, result)
+        })
+        .await
+        .expect("authorization panicked")
+    };
+    let denial = match result {
+        Ok(_) => return Err(warp::reject::not_found()),
+        Err(d) => d,
+    };
+    let challenge = auth.challenge(host.as_deref(), &required, &denial);
+    Ok(denied(denial, &challenge))
+}
+
+// Builds the response to a request that was not authorized.
+fn denied(denial: Denial, challenge: &str) -> warp::http::Result<Response<Bytes>> {
+    let res = match denial {
+        Denial::Unauthorized(detail) => Errors::Unauthorized.response(detail),
+        Denial::Denied(detail) => Errors::Denied.response(detail),
+    };
+    res.map(|mut r| {
+        if let Ok(v) = HeaderValue::from_str(challenge) {
+            r.headers_mut().insert(WWW_AUTHENTICATE, v);
+        }
+        r
+    })
+}
+
+// Responds to a pull of a client that has spent its budget, and rejects other
+// pulls so that they reach the routes. Clients are the user they
+// authenticated as, or else their IP address.
+pub async fn limit_request(
+    (budget, ns, reference): (Budget, String, String),
+    authorization: Option<String>,
+    range: Option<String>,
+    remote: Option<SocketAddr>,
+    auth: Auth,
+    limiter: Limiter,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !limiter.limits(budget) {
+        return Err(warp::reject::not_found());
+    }
+    let client = rate_limited_client(auth.identity(authorization.as_deref()), remote);
+    let cost = match budget {
+        Budget::Manifests => 1,
+        // The whole blob, or range of it, is charged before any of it is
+        // sent, so pulls that are aborted spend the full cost. Blobs that
+        // cannot be served are left to the route to respond to.
+        Budget::BlobBytes => match store.blob_size(&ns, &reference).await {
+            Ok(Some(size)) => match range.map(|r| blob_range(&r, size)) {
+                None | Some(RangeRequest::Full) => size,
+                Some(RangeRequest::Partial(r)) => r.end - r.start,
+                Some(_) => return Err(warp::reject::not_found()),
+            },
+            _ => return Err(warp::reject::not_found()),
+        },
+    };
+    let wait = match limiter.take(budget, &client, cost).await {
+        Ok(()) => return Err(warp::reject::not_found()),
+        Err(w) => w,
+    };
+    // Retry-After is given in whole seconds.
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Ok(Errors::Toomanyrequests
+        .response(format!("retry in {} seconds", seconds))
+        .map(|mut r| {
+            r.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+            r
+        }))
+}
+
+// Returns the key that a client is rate limited by, which is the user it
+// authenticated as, or else its IP address.
+fn rate_limited_client(user: Option<String>, remote: Option<SocketAddr>) -> String {
+    match user {
+        Some(user) => format!("user {}", user),
+        None => remote.map(|a| a.ip().to_string()).unwrap_or_default(),
+    }
+}
+
+pub async fn issue_token(
+    requested: Vec<Access>,
+    authorization: Option<String>,
+    auth: Auth,
+) -> Result<impl warp::Reply, Infallible> {
+    // Like authorize_request, the password is verified on a blocking thread.
+    let issued =
+        tokio::task::spawn_blocking(move || auth.issue_token(authorization.as_deref(), &requested))
+            .await
+            .expect("token issuing panicked");
+    let token = match issued {
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Bytes::new()))
+        }
+        Some(Err(d)) => return Ok(denied(d, &format!("Basic realm=\"{}\"", auth::REALM))),
+        Some(Ok(t)) => t,
+    };
+    // Serializing a struct of strings and numbers cannot fail.
+    let body = serde_json::to_vec(&token).unwrap();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Bytes::from(body)))
+}
+
+pub async fn start_upload(ns: String, store: Store) -> Result<impl warp::Reply, Infallible> {
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    Ok(new_upload(&ns, &store).await)
+}
+
+async fn new_upload(ns: &str, store: &Store) -> warp::http::Result<Response<Bytes>> {
+    let id = match store.start_upload(ns).await {
+        Ok(id) => id,
+        Err(e) => return store_error(e),
+    };
+    warp::http::Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Location", format!("/v2/{}/blobs/uploads/{}", ns, id))
+        .header("Range", "0-0")
+        .header("Docker-Upload-UUID", id)
+        .body(Bytes::new())
+}
+
+// Mounts a blob from another repository instead of uploading it. Falls back to
+// starting an upload if the blob cannot be mounted.
+pub async fn mount_blob(
+    ns: String,
+    query: MountQuery,
+    store: Store,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    let digest = match query.mount.parse::<Hash>() {
+        Ok(d) => d.to_string(),
+        Err(_) => return Ok(new_upload(&ns, &store).await),
+    };
+    if !valid_repository(&query.from) || query.from == ns {
+        return Ok(new_upload(&ns, &store).await);
+    }
+    match store.mount_blob(&ns, &query.from, &digest).await {
+        Ok(true) => (),
+        Ok(false) => return Ok(new_upload(&ns, &store).await),
+        Err(e) => return Ok(store_error(e)),
+    }
+    send(
+        &ns,
+        "Blob".to_string(),
+        Method::POST,
+        StatusCode::CREATED,
+        digest.clone(),
+        Some(vec![Ref {
+            data_type: "Blob".to_string(),
+            repo: query.from,
+            identifier: digest.clone(),
+        }]),
+        cm,
+    )
+    .await;
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Location", format!("/v2/{}/blobs/{}", ns, digest))
+        .header("Docker-Content-Digest", digest)
+        .body(Bytes::new()))
+}
+
+pub async fn upload_status(
+    ns: String,
+    id: Uuid,
+    store: Store,
+) -> Result<impl warp::Reply, Infallible> {
+    let session = match store
+        .upload_session(ns.as_str(), id.to_string().as_str())
+        .await
+    {
+        Ok(Some(s)) => s,
+        Ok(None) => return Ok(Errors::BlobUploadUnknown.response(id.to_string())),
+        Err(e) => return Ok(store_error(e)),
+    };
+    // The range tells clients where to resume the upload from.
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Location", format!("/v2/{}/blobs/uploads/{}", ns, id))
+        .header("Range", format!("0-{}", session.size.saturating_sub(1)))
+        .header("Docker-Upload-UUID", id.to_string())
+        .body(Bytes::new()))
+}
+
+pub async fn delete_upload(
+    ns: String,
+    id: Uuid,
+    store: Store,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    match store
+        .cancel_upload(ns.as_str(), id.to_string().as_str())
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Ok(Errors::BlobUploadUnknown.response(id.to_string())),
+        Err(e) => return Ok(store_error(e)),
+    }
+    send(
+        &ns,
+        "Upload".to_string(),
+        Method::DELETE,
+        StatusCode::NO_CONTENT,
+        id.to_string(),
+        None,
+        cm,
+    )
+    .await;
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Bytes::new()))
+}
+
+// Discards uploads that have not received content within the ttl. Run
+// periodically by the registry.
+pub async fn expire_uploads(store: Store, cm: ChannelMap, ttl: Duration) {
+    let before = match SystemTime::now().checked_sub(ttl) {
+        None => return,
+        Some(t) => t,
+    };
+    let expired = match store.expire_uploads(before).await {
+        Ok(e) => e,
+        Err(e) => {
+            log::error!("could not expire uploads: {}", e);
+            return;
+        }
+    };
+    for session in expired {
+        log::info!(
+            "expired upload {} to {} after receiving {} bytes in {}s",
+            session.id,
+            session.repo,
+            session.size,
+            session
+                .updated
+                .duration_since(session.created)
+                .unwrap_or_default()
+                .as_secs()
+        );
+        send(
+            &session.repo,
+            "Upload".to_string(),
+            Method::DELETE,
+            StatusCode::NO_CONTENT,
+            session.id,
+            None,
+            cm.clone(),
+        )
+        .await;
+    }
+}
 
 pub async fn store_chunk(
     ns: String,
     id: Uuid,
-    _: Option<String>,
+    content_length: Option<String>,
     content_range: Option<String>,
-    content: Bytes,
-    store: UploadStore,
+    content: ByteStream,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    // NOTE(hasheddan): chunks are currently stored at global scope
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
     let start = match content_range {
         None => None,
         Some(content_range) => {
-            let spl = content_range.split("-").collect::<Vec<&str>>();
-            if spl.len() != 2 {
-                return Ok(warp::http::Response::builder()
-                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                    .body(bytes::Bytes::new()));
-            }
-            let start = match spl[0].parse::<usize>() {
-                Ok(start) => start,
-                Err(_) => {
-                    return Ok(warp::http::Response::builder()
-                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                        .body(bytes::Bytes::new()))
-                }
-            };
-            Some(start)
-        }
-    };
-    let mut s = store.lock().await;
-    let id_string = id.to_string();
-    let content_len = content.len() - 1;
-    match s.get_mut(id_string.as_str()) {
-        None => {
-            match start {
-                // If no content range provided, we treat it as 0
+            let range = content_range
+                .split_once('-')
+                .and_then(|(s, e)| Some((s.parse::<u64>().ok()?, e.parse::<u64>().ok()?)))
+                .filter(|(s, e)| s <= e);
+            let (start, end) = match range {
+                Some(range) => range,
                 None => {
-                    s.insert(id_string, content);
+                    return Ok(Errors::BlobUploadInvalid.response_with_status(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        format!("malformed Content-Range {}", content_range),
+                    ))
                 }
-                // If content range is provided, it must start with 0 because we
-                // don't have any existing chunks
-                Some(start) => {
-                    if start != 0 {
-                        return Ok(warp::http::Response::builder()
-                            .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                            .body(bytes::Bytes::new()));
-                    }
-                    s.insert(id_string, content);
+            };
+            // The range is inclusive, so it covers one byte more than its
+            // difference.
+            if let Some(length) = content_length {
+                if length.parse::<u64>().ok() != Some(end - start + 1) {
+                    return Ok(Errors::SizeInvalid.response(format!(
+                        "Content-Length {} does not match Content-Range {}",
+                        length, content_range
+                    )));
                 }
             }
-            send(
-                &ns,
-                "Upload".to_string(),
-                Method::PATCH,
-                StatusCode::ACCEPTED,
-                id.to_string(),
-                None,
-                cm,
-            )
-            .await;
-            Ok(warp::http::Response::builder()
-                .status(StatusCode::ACCEPTED)
-                .header("Location", format!("/v2/{}/blobs/uploads/{}", ns, id))
-                .header("Range", format!("0-{}", content_len))
-                .body(bytes::Bytes::new()))
-        }
-        Some(b) => {
-            // Ensure that content start equals length of previously uploaded
-            // chunks
-            if start != Some(b.len()) {
-                return Ok(warp::http::Response::builder()
-                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                    .body(bytes::Bytes::new()));
-            }
-            let mut buf = vec![].writer();
-            // BufMut operations are infallible so we can unwrap these writes
-            // safely
-            buf.write(b).unwrap();
-            buf.write(&content).unwrap();
-            *b = buf.into_inner().into();
-            send(
-                &ns,
-                "Upload".to_string(),
-                Method::PATCH,
-                StatusCode::ACCEPTED,
-                id_string,
-                None,
-                cm,
-            )
-            .await;
-            Ok(warp::http::Response::builder()
-                .status(StatusCode::ACCEPTED)
-                .header("Location", format!("/v2/{}/blobs/uploads/{}", ns, id))
-                .body(bytes::Bytes::new()))
+            Some(start)
         }
-    }
+    };
+    // If no content range provided, we treat it as 0, so only the first chunk
+    // may omit it.
+    let size = match store
+        .append_upload(ns.as_str(), id.to_string().as_str(), start, content)
+        .await
+    {
+        Ok(size) => size,
+        Err(e) => return Ok(store_error(e)),
+    };
+    send(
+        &ns,
+        "Upload".to_string(),
+        Method::PATCH,
+        StatusCode::ACCEPTED,
+        id.to_string(),
+        None,
+        cm,
+    )
+    .await;
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Location", format!("/v2/{}/blobs/uploads/{}", ns, id))
+        .header("Range", format!("0-{}", size.saturating_sub(1)))
+        .header("Docker-Upload-UUID", id.to_string())
+        .body(bytes::Bytes::new()))
 }
 
 pub async fn store_blob(
@@ -119,100 +393,256 @@ pub async fn store_blob(
     id: Uuid,
     _: String,
     query: PushQuery,
-    content: Bytes,
-    blob_store: BlobStore,
-    upload_store: UploadStore,
+    content: ByteStream,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    // NOTE(hasheddan): blobs and uploads are currently stored at global scope
-    let mut s = blob_store.lock().await;
-    let mut u = upload_store.lock().await;
-    let id_string = id.to_string();
-    match u.get_mut(id_string.as_str()) {
-        None => {
-            // Upload store does not have a record for id, so we go ahead and
-            // store full blob in blob store
-            s.insert(query.digest.clone(), content);
-        }
-        Some(b) => {
-            // Prior upload chunks exist so we append bytes to existing and
-            // store result in blob store
-            let mut buf = vec![].writer();
-            // BufMut operations are infallible so we can unwrap these writes
-            // safely
-            buf.write(b).unwrap();
-            buf.write(&content).unwrap();
-            s.insert(query.digest.clone(), buf.into_inner().into());
-            // Blob has been uploaded, chunks can be removed from upload store
-            u.remove(id_string.as_str());
-        }
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    let expected = match query.digest.parse::<Hash>() {
+        Ok(d) => d,
+        Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+    };
+    // Never trust the client provided digest, the blob must hash to it before
+    // it is committed.
+    if let Err(e) = store
+        .commit_upload(ns.as_str(), id.to_string().as_str(), &expected, content)
+        .await
+    {
+        return Ok(store_error(e));
     }
     send(
         &ns.clone(),
         "Blob".to_string(),
         Method::PUT,
         StatusCode::CREATED,
-        query.digest,
-        Some(vec![Ref { data_type: "Upload".to_string(), repo: ns, identifier: id.to_string()}]),
+        expected.to_string(),
+        Some(vec![Ref {
+            data_type: "Upload".to_string(),
+            repo: ns.clone(),
+            identifier: id.to_string(),
+        }]),
+        cm,
+    )
+    .await;
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Location", format!("/v2/{}/blobs/{}", ns, expected))
+        .header("Docker-Content-Digest", expected.to_string())
+        .body(bytes::Bytes::new()))
+}
+
+// Stores a blob sent in the body of the request that starts its upload.
+pub async fn store_monolithic_blob(
+    ns: String,
+    _: String,
+    query: PushQuery,
+    content: ByteStream,
+    store: Store,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    let expected = match query.digest.parse::<Hash>() {
+        Ok(d) => d,
+        Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+    };
+    let id = match store.start_upload(&ns).await {
+        Ok(id) => id,
+        Err(e) => return Ok(store_error(e)),
+    };
+    if let Err(e) = store
+        .commit_upload(ns.as_str(), id.as_str(), &expected, content)
+        .await
+    {
+        // The client never learns of the upload, so it cannot be resumed.
+        if let Err(e) = store.cancel_upload(&ns, &id).await {
+            log::warn!("failed to cancel upload {}: {}", id, e);
+        }
+        return Ok(store_error(e));
+    }
+    send(
+        &ns.clone(),
+        "Blob".to_string(),
+        Method::POST,
+        StatusCode::CREATED,
+        expected.to_string(),
+        None,
         cm,
     )
     .await;
-    Ok(StatusCode::CREATED)
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Location", format!("/v2/{}/blobs/{}", ns, expected))
+        .header("Docker-Content-Digest", expected.to_string())
+        .body(bytes::Bytes::new()))
+}
+
+// A Range header resolved against the size of a blob.
+enum RangeRequest {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+    Multiple,
+}
+
+// Resolves a Range header against the size of a blob. Headers that cannot be
+// parsed are ignored and the whole blob is served, as HTTP requires.
+fn blob_range(header: &str, size: u64) -> RangeRequest {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        None => return RangeRequest::Full,
+        Some(s) => s.trim(),
+    };
+    if spec.contains(',') {
+        return RangeRequest::Multiple;
+    }
+    let (first, last) = match spec.split_once('-') {
+        None => return RangeRequest::Full,
+        Some((f, l)) => (f.trim(), l.trim()),
+    };
+    let range = if first.is_empty() {
+        // A suffix range requests the last bytes of the blob.
+        match last.parse::<u64>() {
+            Err(_) => return RangeRequest::Full,
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(n) => size.saturating_sub(n)..size,
+        }
+    } else {
+        let start = match first.parse::<u64>() {
+            Err(_) => return RangeRequest::Full,
+            Ok(s) => s,
+        };
+        let end = match last.parse::<u64>() {
+            _ if last.is_empty() => size,
+            Ok(e) if e >= start => e.saturating_add(1).min(size),
+            _ => return RangeRequest::Full,
+        };
+        start..end
+    };
+    if range.start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(range)
 }
 
 pub async fn get_blob(
     ns: String,
     digest: String,
-    store: BlobStore,
+    range: Option<String>,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    let s = store.lock().await;
+    // Ranges are resolved against the size of the blob before it is read.
+    let range = match range {
+        None => None,
+        Some(header) => match store.blob_size(ns.as_str(), digest.as_str()).await {
+            Err(e) => return Ok(store_error(e).map(|r| r.map(Body::from))),
+            Ok(None) => None,
+            Ok(Some(size)) => match blob_range(&header, size) {
+                RangeRequest::Full => None,
+                RangeRequest::Partial(r) => Some(r),
+                RangeRequest::Multiple => {
+                    return Ok(Errors::Unsupported
+                        .response_with_status(
+                            StatusCode::RANGE_NOT_SATISFIABLE,
+                            "multiple ranges are not supported",
+                        )
+                        .map(|r| r.map(Body::from)))
+                }
+                RangeRequest::Unsatisfiable => {
+                    return Ok(warp::http::Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header("Content-Range", format!("bytes */{}", size))
+                        .body(Body::empty()))
+                }
+            },
+        },
+    };
+    let blob = match store
+        .get_blob(ns.as_str(), digest.as_str(), range.clone())
+        .await
+    {
+        Ok(b) => b,
+        Err(e) => return Ok(store_error(e).map(|r| r.map(Body::from))),
+    };
+    let status = match (&blob, &range) {
+        (None, _) => StatusCode::NOT_FOUND,
+        (Some(_), None) => StatusCode::OK,
+        (Some(_), Some(_)) => StatusCode::PARTIAL_CONTENT,
+    };
     send(
         &ns,
         "Blob".to_string(),
         Method::GET,
-        StatusCode::OK,
+        status,
         digest.clone(),
         None,
         cm,
     )
     .await;
-    match s.get(digest.as_str()) {
-        None => Ok(warp::http::Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(bytes::Bytes::new())),
-        Some(b) => Ok(warp::http::Response::builder()
-            .status(StatusCode::OK)
-            .header("Docker-Content-Digest", digest)
-            .header("Content-Length", b.len())
-            .body(b.clone())),
-    }
+    let b = match blob {
+        None => {
+            return Ok(Errors::BlobUnknown
+                .response(digest)
+                .map(|r| r.map(Body::from)))
+        }
+        Some(b) => b,
+    };
+    let mut res = warp::http::Response::builder()
+        .status(status)
+        .header("Docker-Content-Digest", digest)
+        .header("Accept-Ranges", "bytes");
+    res = match range {
+        None => res.header("Content-Length", b.size),
+        Some(r) => res.header("Content-Length", r.end - r.start).header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", r.start, r.end - 1, b.size),
+        ),
+    };
+    Ok(res.body(Body::wrap_stream(b.content)))
 }
 
-fn convert_broadcast(
-    s: tokio_stream::wrappers::BroadcastStream<Event>,
-) -> impl Stream<Item = Result<warp::sse::Event, warp::Error>> + Send + 'static {
-    // Convert broadcast stream messages into server side events.
-    s.map(|msg| Ok(warp::sse::Event::default().json_data(msg.unwrap()).unwrap()))
+fn convert_messages(
+    s: impl Stream<Item = Message> + Send + 'static,
+) -> impl Stream<Item = Result<warp::sse::Event, serde_json::Error>> + Send + 'static {
+    // Convert channel messages into server side events.
+    s.map(|msg| match msg {
+        Message::Event(id, e) => warp::sse::Event::default().id(id).json_data(e),
+        Message::Lagged(n) => Ok(warp::sse::Event::default()
+            .event("lagged")
+            .data(format!("lagged {} events", n))),
+        Message::Reset => Ok(warp::sse::Event::default()
+            .event("reset")
+            .data("events since the last one received are unknown")),
+    })
 }
 
-pub async fn send_events(ns: String, cm: ChannelMap) -> Result<impl warp::Reply, Infallible> {
-    let mut c = cm.lock().await;
-    // Check if channel exists for namespace and create one if it does not.
-    let tx = c.entry(ns).or_insert(broadcast::channel::<Event>(10).0);
-    Ok(warp::sse::reply(convert_broadcast(BroadcastStream::new(
-        tx.subscribe(),
-    ))))
+pub async fn send_events(
+    ns: String,
+    last_event_id: Option<String>,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    let messages = channel::subscribe(cm, ns, last_event_id).await;
+    // Comments are sent while there are no events so that subscribers that
+    // left are noticed.
+    Ok(warp::sse::reply(
+        warp::sse::keep_alive().stream(convert_messages(messages)),
+    ))
 }
 
 pub async fn blob_exists(
     ns: String,
     digest: String,
-    store: BlobStore,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    let s = store.lock().await;
-    if s.contains_key(digest.as_str()) {
+    let size = match store.blob_size(ns.as_str(), digest.as_str()).await {
+        Ok(s) => s,
+        Err(e) => return Ok(store_error(e)),
+    };
+    if let Some(size) = size {
         send(
             &ns,
             "Blob".to_string(),
@@ -223,7 +653,12 @@ pub async fn blob_exists(
             cm,
         )
         .await;
-        return Ok(StatusCode::OK);
+        return Ok(warp::http::Response::builder()
+            .status(StatusCode::OK)
+            .header("Docker-Content-Digest", digest)
+            .header("Content-Length", size)
+            .header("Accept-Ranges", "bytes")
+            .body(bytes::Bytes::new()));
     }
     send(
         &ns,
@@ -235,7 +670,7 @@ pub async fn blob_exists(
         cm,
     )
     .await;
-    Ok(StatusCode::NOT_FOUND)
+    Ok(Errors::BlobUnknown.response(digest))
 }
 
 pub async fn store_manifest(
@@ -243,56 +678,104 @@ pub async fn store_manifest(
     reference: String,
     content_type: String,
     content: Bytes,
-    store: ManifestStore,
+    store: Store,
     cm: ChannelMap,
+    gc_lock: gc::Lock,
 ) -> Result<impl warp::Reply, Infallible> {
-    let mut c = Sha256::new();
-    c.update(&content);
-    let digest = format!("sha256:{:x}", c.finalize());
-    // TODO(hasheddan): consider only locking nested repo manifest hash map
-    let mut s = store.lock().await;
-    let e = s.entry(ns.clone()).or_insert_with(|| HashMap::new());
-    let m = Manifest {
-        content_type: content_type.clone(),
-        content: content.clone(),
-    };
-    e.insert(reference.clone(), m.clone());
-    e.insert(digest.clone(), m);
-    let refs: Vec<Ref> = match MediaType::try_from(content_type.as_str()).unwrap() {
-        MediaType::OCIImageIndex | MediaType::DockerManifestList => {
-            let i: eocker::IndexManifest = serde_json::from_slice(content.as_ref()).unwrap();
-            i.manifests
-                .iter()
-                .map(|l| {
-                    return Ref {
-                        data_type: "Manifest".to_string(),
-                        repo: ns.clone(),
-                        identifier: format!("{}:{}", l.digest.algorithm, l.digest.hex),
-                    };
-                })
-                .collect()
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    // Garbage collection must not remove what the manifest references between
+    // checking that it exists and storing the manifest.
+    let _pushing = gc_lock.read().await;
+    // If the manifest is pushed by digest, the content must hash to it.
+    let digest = if reference.contains(':') {
+        let expected = match reference.parse::<Hash>() {
+            Ok(d) => d,
+            Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+        };
+        match Hash::of(&expected.algorithm, &content) {
+            Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+            Ok(actual) if actual != expected => {
+                return Ok(Errors::DigestInvalid.response(format!(
+                    "expected {} but manifest hashed to {}",
+                    expected, actual
+                )))
+            }
+            Ok(actual) => actual.to_string(),
         }
-        _ => {
-            let m: eocker::Manifest = serde_json::from_slice(content.as_ref()).unwrap();
-            let mut mrefs: Vec<Ref> = m
-                .layers
-                .iter()
-                .map(|l| {
-                    return Ref {
-                        data_type: "Blob".to_string(),
-                        repo: ns.clone(),
-                        identifier: format!("{}:{}", l.digest.algorithm, l.digest.hex),
-                    };
-                })
-                .collect();
-            mrefs.push(Ref {
-                data_type: "Blob".to_string(),
-                repo: ns.clone(),
-                identifier: format!("{}:{}", m.config.digest.algorithm, m.config.digest.hex),
-            });
-            mrefs
+    } else {
+        if !valid_tag(&reference) {
+            return Ok(Errors::ManifestInvalid.response(format!("invalid tag {}", reference)));
+        }
+        match Hash::of("sha256", &content) {
+            Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+            Ok(h) => h.to_string(),
         }
     };
+    let media_type = MediaType::from(content_type.as_str());
+    let mut descriptors = match parse_manifest(&media_type, &content) {
+        Ok(d) => d,
+        Err(e) => return Ok(Errors::ManifestInvalid.response(e)),
+    };
+    // Non-distributable layers are not pushed to the registry.
+    descriptors.retain(|(_, d)| d.media_type.is_distributable());
+    // Every blob referenced by the manifest must already have been pushed,
+    // and every manifest referenced by an index must already exist in the
+    // repository.
+    for (t, d) in descriptors.iter() {
+        let id = d.digest.to_string();
+        let size = if *t == "Blob" {
+            store.blob_size(ns.as_str(), id.as_str()).await
+        } else {
+            store
+                .get_manifest(ns.as_str(), id.as_str())
+                .await
+                .map(|m| m.map(|m| m.content.len() as u64))
+        };
+        match size {
+            Err(e) => return Ok(store_error(e)),
+            Ok(Some(size)) if size as i64 == d.size => (),
+            Ok(Some(size)) => {
+                return Ok(Errors::ManifestBlobUnknown.response(format!(
+                    "{} has size {} but manifest specifies {}",
+                    id, size, d.size
+                )))
+            }
+            Ok(None) => return Ok(Errors::ManifestBlobUnknown.response(id)),
+        }
+    }
+    let m = Manifest {
+        digest: digest.clone(),
+        content_type,
+        content,
+    };
+    // The subject does not have to exist, referrers may be pushed before the
+    // manifest they refer to.
+    let subject = referrer(&m);
+    if let Err(e) = store.put_manifest(ns.as_str(), reference.as_str(), m).await {
+        return Ok(store_error(e));
+    }
+    if let Some((s, d)) = subject.clone() {
+        if let Err(e) = store.add_referrer(ns.as_str(), s.as_str(), d).await {
+            return Ok(store_error(e));
+        }
+    }
+    let mut refs: Vec<Ref> = descriptors
+        .into_iter()
+        .map(|(t, d)| Ref {
+            data_type: t.to_string(),
+            repo: ns.clone(),
+            identifier: d.digest.to_string(),
+        })
+        .collect();
+    if let Some((s, _)) = &subject {
+        refs.push(Ref {
+            data_type: "Manifest".to_string(),
+            repo: ns.clone(),
+            identifier: s.clone(),
+        });
+    }
     send(
         &ns,
         "Manifest".to_string(),
@@ -303,122 +786,577 @@ pub async fn store_manifest(
         cm,
     )
     .await;
-    Ok(warp::http::Response::builder()
+    let mut res = warp::http::Response::builder()
         .status(StatusCode::CREATED)
-        .header("Docker-Content-Digest", digest)
-        .body(bytes::Bytes::new()))
+        .header("Location", format!("/v2/{}/manifests/{}", ns, digest))
+        .header("Docker-Content-Digest", digest);
+    // Tells clients that the registry indexed the referrer, so they do not
+    // have to maintain the referrers tag schema themselves.
+    if let Some((s, _)) = subject {
+        res = res.header("OCI-Subject", s);
+    }
+    Ok(res.body(bytes::Bytes::new()))
+}
+
+pub async fn list_referrers(
+    ns: String,
+    digest: String,
+    query: ReferrersQuery,
+    store: Store,
+) -> Result<impl warp::Reply, Infallible> {
+    if !valid_repository(&ns) {
+        return Ok(Errors::NameInvalid.response(ns));
+    }
+    let subject = match digest.parse::<Hash>() {
+        Ok(d) => d,
+        Err(e) => return Ok(Errors::DigestInvalid.response(e.to_string())),
+    };
+    // Subjects that do not exist simply have no referrers.
+    let mut manifests = match store.referrers(&ns, &subject.to_string()).await {
+        Ok(m) => m,
+        Err(e) => return Ok(store_error(e)),
+    };
+    if let Some(t) = &query.artifact_type {
+        manifests.retain(|d| d.artifact_type.as_ref().map(MediaType::as_str) == Some(t));
+    }
+    let body = serde_json::to_vec(&eocker::IndexManifest {
+        schema_version: 2,
+        media_type: Some(MediaType::OCIImageIndex),
+        manifests,
+        annotations: None,
+        artifact_type: None,
+        subject: None,
+    })
+    .unwrap();
+    let mut res = warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", MediaType::OCIImageIndex.as_str());
+    if query.artifact_type.is_some() {
+        res = res.header("OCI-Filters-Applied", "artifactType");
+    }
+    Ok(res.body(Bytes::from(body)))
+}
+
+// Returns the manifest for a reference, or the error to respond with if the
+// repository or manifest is unknown.
+async fn find_manifest(
+    ns: &str,
+    reference: &str,
+    store: &Store,
+) -> Result<Manifest, warp::http::Result<Response<Bytes>>> {
+    match store.get_manifest(ns, reference).await {
+        Err(e) => Err(store_error(e)),
+        Ok(Some(m)) => Ok(m),
+        Ok(None) => match store.repository_exists(ns).await {
+            Err(e) => Err(store_error(e)),
+            Ok(true) => Err(Errors::ManifestUnknown.response(reference)),
+            Ok(false) => Err(Errors::NameUnknown.response(ns)),
+        },
+    }
+}
+
+// Reports whether a client accepts a media type. Clients that do not send an
+// Accept header accept any manifest.
+fn accepts(accept: &[String], media_type: &str) -> bool {
+    accept.is_empty()
+        || accept.iter().any(|a| match a.strip_suffix("/*") {
+            Some("*") => true,
+            Some(prefix) => media_type.split('/').next() == Some(prefix),
+            None => a == media_type,
+        })
+}
+
+// Finds a manifest in a media type the client accepts. Clients that do not
+// accept an index are served the linux/amd64 manifest it references, if they
+// accept that, which is how clients that predate indexes pull multi-platform
+// images. Manifests that exist in no accepted media type are refused with 406
+// Not Acceptable, naming the media type that is available.
+async fn negotiate_manifest(
+    ns: &str,
+    reference: &str,
+    accept: &[String],
+    store: &Store,
+) -> Result<Manifest, warp::http::Result<Response<Bytes>>> {
+    let m = find_manifest(ns, reference, store).await?;
+    if accepts(accept, &m.content_type) {
+        return Ok(m);
+    }
+    let not_accepted = || {
+        Errors::ManifestUnknown.response_with_status(
+            StatusCode::NOT_ACCEPTABLE,
+            format!(
+                "manifest {} has media type {}, which is not accepted",
+                reference, m.content_type
+            ),
+        )
+    };
+    match MediaType::from(m.content_type.as_str()) {
+        MediaType::OCIImageIndex | MediaType::DockerManifestList => (),
+        _ => return Err(not_accepted()),
+    }
+    let index: eocker::IndexManifest = match serde_json::from_slice(&m.content) {
+        Ok(i) => i,
+        Err(_) => return Err(not_accepted()),
+    };
+    let default = index.manifests.into_iter().find(|d| {
+        let platform = d.platform.as_ref();
+        platform.map(|p| p.os == "linux" && p.architecture == "amd64") == Some(true)
+            && accepts(accept, &d.media_type.to_string())
+    });
+    let digest = match default {
+        None => return Err(not_accepted()),
+        Some(d) => d.digest.to_string(),
+    };
+    match store.get_manifest(ns, &digest).await {
+        Err(e) => Err(store_error(e)),
+        Ok(Some(m)) => Ok(m),
+        Ok(None) => Err(not_accepted()),
+    }
+}
+
+// Returns the status of an error response, for the event that reports it.
+fn error_status(res: &warp::http::Result<Response<Bytes>>) -> StatusCode {
+    res.as_ref()
+        .map(|r| r.status())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// Builds the response to GET and HEAD requests for a manifest, which carry
+// the same headers.
+fn manifest_response(m: Manifest, body: bool) -> warp::http::Result<Response<Bytes>> {
+    warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header("Docker-Content-Digest", m.digest)
+        .header("Content-Type", m.content_type)
+        .header("Content-Length", m.content.len())
+        .body(if body { m.content } else { Bytes::new() })
 }
 
 pub async fn get_manifest(
     ns: String,
     reference: String,
-    store: ManifestStore,
+    accept: Vec<String>,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    // TODO(hasheddan): consider only locking nested repo manifest hash map
-    let s = store.lock().await;
-    match s.get(ns.as_str()) {
-        None => {
+    match negotiate_manifest(&ns, &reference, &accept, &store).await {
+        Err(res) => {
             send(
                 &ns,
                 "Manifest".to_string(),
                 Method::GET,
-                StatusCode::NOT_FOUND,
+                error_status(&res),
                 reference,
                 None,
                 cm,
             )
             .await;
-            Ok(warp::http::Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(bytes::Bytes::new()))
+            Ok(res)
+        }
+        Ok(m) => {
+            send(
+                &ns,
+                "Manifest".to_string(),
+                Method::GET,
+                StatusCode::OK,
+                reference,
+                None,
+                cm,
+            )
+            .await;
+            Ok(manifest_response(m, true))
         }
-        Some(r) => match r.get(reference.as_str()) {
-            None => {
-                send(
-                    &ns,
-                    "Manifest".to_string(),
-                    Method::GET,
-                    StatusCode::NOT_FOUND,
-                    reference,
-                    None,
-                    cm,
-                )
-                .await;
-                Ok(warp::http::Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(bytes::Bytes::new()))
-            }
-            Some(m) => {
-                send(
-                    &ns,
-                    "Manifest".to_string(),
-                    Method::GET,
-                    StatusCode::OK,
-                    reference,
-                    None,
-                    cm,
-                )
-                .await;
-                Ok(warp::http::Response::builder()
-                    .status(StatusCode::OK)
-                    // TODO(hasheddan): set Docker-Content-Digest header
-                    .header("Content-Type", m.content_type.clone())
-                    .header("Content-Length", m.content.len())
-                    .body(m.content.clone()))
-            }
-        },
     }
 }
 
 pub async fn manifest_exists(
     ns: String,
     reference: String,
-    store: ManifestStore,
+    accept: Vec<String>,
+    store: Store,
     cm: ChannelMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    // TODO(hasheddan): consider only locking nested repo manifest hash map
-    let s = store.lock().await;
-    let e = s.get(ns.as_str());
-    match e {
-        None => {
+    match negotiate_manifest(&ns, &reference, &accept, &store).await {
+        Err(res) => {
             send(
                 &ns,
                 "Manifest".to_string(),
                 Method::HEAD,
-                StatusCode::NOT_FOUND,
+                error_status(&res),
                 reference,
                 None,
                 cm,
             )
             .await;
-            Ok(StatusCode::NOT_FOUND)
+            Ok(res)
         }
-        Some(m) => {
-            if m.contains_key(reference.as_str()) {
-                send(
-                    &ns,
-                    "Manifest".to_string(),
-                    Method::HEAD,
-                    StatusCode::OK,
-                    reference,
-                    None,
-                    cm,
-                )
-                .await;
-                return Ok(StatusCode::OK);
-            }
+        Ok(m) => {
             send(
                 &ns,
                 "Manifest".to_string(),
                 Method::HEAD,
-                StatusCode::NOT_FOUND,
+                StatusCode::OK,
                 reference,
                 None,
                 cm,
             )
             .await;
-            Ok(StatusCode::NOT_FOUND)
+            Ok(manifest_response(m, false))
+        }
+    }
+}
+
+pub async fn delete_manifest(
+    ns: String,
+    reference: String,
+    enabled: bool,
+    store: Store,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    if !enabled {
+        return Ok(Errors::Unsupported.response("deletes are disabled"));
+    }
+    let res = match store.delete_manifest(ns.as_str(), reference.as_str()).await {
+        Err(e) => Err(store_error(e)),
+        Ok(true) => Ok(()),
+        Ok(false) => match store.repository_exists(ns.as_str()).await {
+            Err(e) => Err(store_error(e)),
+            Ok(true) => Err(Errors::ManifestUnknown.response(reference.as_str())),
+            Ok(false) => Err(Errors::NameUnknown.response(ns.as_str())),
+        },
+    };
+    let status = if res.is_ok() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    send(
+        &ns,
+        "Manifest".to_string(),
+        Method::DELETE,
+        status,
+        reference,
+        None,
+        cm,
+    )
+    .await;
+    match res {
+        Err(res) => Ok(res),
+        Ok(()) => Ok(warp::http::Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .body(bytes::Bytes::new())),
+    }
+}
+
+pub async fn delete_blob(
+    ns: String,
+    digest: String,
+    enabled: bool,
+    store: Store,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    if !enabled {
+        return Ok(Errors::Unsupported.response("deletes are disabled"));
+    }
+    if let Err(e) = digest.parse::<Hash>() {
+        return Ok(Errors::DigestInvalid.response(e.to_string()));
+    }
+    let removed = match store.delete_blob(ns.as_str(), digest.as_str()).await {
+        Ok(r) => r,
+        Err(e) => return Ok(store_error(e)),
+    };
+    let status = if removed {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    send(
+        &ns,
+        "Blob".to_string(),
+        Method::DELETE,
+        status,
+        digest.clone(),
+        None,
+        cm,
+    )
+    .await;
+    if !removed {
+        return Ok(Errors::BlobUnknown.response(digest));
+    }
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(bytes::Bytes::new()))
+}
+
+// Collects garbage, which GET requests only report. Removals are sent as
+// delete events of each repository.
+pub async fn collect_garbage(
+    report_only: bool,
+    query: GcQuery,
+    grace: Duration,
+    enabled: bool,
+    store: Store,
+    cm: ChannelMap,
+    gc_lock: gc::Lock,
+) -> Result<impl warp::Reply, Infallible> {
+    let options = gc::Options {
+        dry_run: report_only || query.dry_run,
+        untagged: query.untagged,
+        grace,
+    };
+    if !options.dry_run && !enabled {
+        return Ok(Errors::Unsupported.response("deletes are disabled"));
+    }
+    let report = match gc::collect(&store, &options, &gc_lock).await {
+        Err(e) => return Ok(store_error(e)),
+        Ok(r) => r,
+    };
+    if !report.dry_run {
+        for r in &report.repositories {
+            let removed = r
+                .manifests_removed
+                .iter()
+                .map(|d| ("Manifest", d))
+                .chain(r.blobs_unlinked.iter().map(|d| ("Blob", d)));
+            for (data_type, digest) in removed {
+                send(
+                    &r.name,
+                    data_type.to_string(),
+                    Method::DELETE,
+                    StatusCode::ACCEPTED,
+                    digest.clone(),
+                    None,
+                    cm.clone(),
+                )
+                .await;
+            }
+        }
+    }
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&report).unwrap().into()))
+}
+
+pub async fn render_metrics(
+    metrics: Metrics,
+    store: Store,
+    quotas: Quotas,
+    cm: ChannelMap,
+) -> Result<impl warp::Reply, Infallible> {
+    let active_uploads = match store.active_uploads().await {
+        Ok(n) => n,
+        Err(e) => return Ok(store_error(e)),
+    };
+    let (blobs, manifests, stored_bytes) = quotas.objects().await;
+    let subscribers = cm.lock().await.subscribers();
+    let body = metrics.render(&Gauges {
+        active_uploads,
+        blobs,
+        manifests,
+        stored_bytes,
+        subscribers,
+    });
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Bytes::from(body)))
+}
+
+pub async fn report_usage(quotas: Quotas) -> Result<impl warp::Reply, Infallible> {
+    let usage = quotas.usage().await;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&usage).unwrap()))
+}
+
+pub async fn list_tags(
+    ns: String,
+    query: ListQuery,
+    store: Store,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut tags = match store.tags(ns.as_str()).await {
+        Err(e) => return Ok(store_error(e)),
+        Ok(None) => return Ok(Errors::NameUnknown.response(ns)),
+        Ok(Some(tags)) => tags,
+    };
+    tags.sort();
+    let (tags, next) = paginate(tags, &query);
+    let body = serde_json::to_vec(&TagList {
+        name: ns.clone(),
+        tags,
+    })
+    .unwrap();
+    Ok(list_response(
+        body,
+        format!("/v2/{}/tags/list", ns),
+        &query,
+        next,
+    ))
+}
+
+pub async fn list_repositories(
+    query: ListQuery,
+    store: Store,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut repositories = match store.repositories().await {
+        Err(e) => return Ok(store_error(e)),
+        Ok(r) => r,
+    };
+    repositories.sort();
+    let (repositories, next) = paginate(repositories, &query);
+    let body = serde_json::to_vec(&Catalog { repositories }).unwrap();
+    Ok(list_response(
+        body,
+        "/v2/_catalog".to_string(),
+        &query,
+        next,
+    ))
+}
+
+// Builds a JSON list response, linking to the next page if one exists.
+fn list_response(
+    body: Vec<u8>,
+    path: String,
+    query: &ListQuery,
+    next: Option<String>,
+) -> warp::http::Result<warp::http::Response<Bytes>> {
+    let mut res = warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len());
+    if let Some(last) = next {
+        res = res.header(
+            "Link",
+            format!(
+                "<{}?n={}&last={}>; rel=\"next\"",
+                path,
+                query.n.unwrap_or_default(),
+                urlencoding::encode(&last)
+            ),
+        );
+    }
+    res.body(body.into())
+}
+
+// Applies the n and last query parameters to a lexically sorted list. If
+// more entries remain, the last entry returned is provided so that the
+// caller can link to the next page.
+fn paginate(entries: Vec<String>, query: &ListQuery) -> (Vec<String>, Option<String>) {
+    let mut entries: Vec<String> = match &query.last {
+        None => entries,
+        Some(last) => entries.into_iter().filter(|e| e > last).collect(),
+    };
+    match query.n {
+        Some(n) if n < entries.len() => {
+            entries.truncate(n);
+            let next = entries.last().cloned();
+            (entries, next)
+        }
+        _ => (entries, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(header: &str, size: u64) -> Option<Range<u64>> {
+        match blob_range(header, size) {
+            RangeRequest::Partial(r) => Some(r),
+            _ => None,
         }
     }
+
+    #[test]
+    fn rate_limits_users_apart_from_addresses() {
+        let addr = |port| Some(SocketAddr::from(([10, 0, 0, 1], port)));
+        // Every connection from an address shares its budget.
+        assert_eq!(rate_limited_client(None, addr(1)), "10.0.0.1");
+        assert_eq!(rate_limited_client(None, addr(2)), "10.0.0.1");
+        // A user named like an address does not share the budget of the
+        // address.
+        let user = rate_limited_client(Some("10.0.0.1".to_string()), addr(1));
+        assert_eq!(user, "user 10.0.0.1");
+        assert_eq!(
+            rate_limited_client(Some("bob".to_string()), None),
+            "user bob"
+        );
+    }
+
+    #[test]
+    fn resolves_blob_ranges() {
+        assert_eq!(partial("bytes=0-4", 10), Some(0..5));
+        assert_eq!(partial(" bytes= 2 - 3 ", 10), Some(2..4));
+        // Ranges are clamped to the blob, and open ones run to its end.
+        assert_eq!(partial("bytes=5-100", 10), Some(5..10));
+        assert_eq!(partial("bytes=5-", 10), Some(5..10));
+        assert_eq!(partial("bytes=-3", 10), Some(7..10));
+        assert_eq!(partial("bytes=-30", 10), Some(0..10));
+    }
+
+    #[test]
+    fn serves_whole_blobs_for_ranges_that_cannot_be_parsed() {
+        for header in &[
+            "",
+            "items=0-1",
+            "bytes=1",
+            "bytes=a-2",
+            "bytes=3-1",
+            "bytes=-x",
+        ] {
+            assert!(
+                matches!(blob_range(header, 10), RangeRequest::Full),
+                "{}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_and_multiple_ranges() {
+        for header in &["bytes=10-", "bytes=10-20", "bytes=-0"] {
+            assert!(
+                matches!(blob_range(header, 10), RangeRequest::Unsatisfiable),
+                "{}",
+                header
+            );
+        }
+        assert!(matches!(
+            blob_range("bytes=-1", 0),
+            RangeRequest::Unsatisfiable
+        ));
+        assert!(matches!(
+            blob_range("bytes=0-1,3-4", 10),
+            RangeRequest::Multiple
+        ));
+    }
+
+    fn page(n: Option<usize>, last: Option<&str>) -> (Vec<String>, Option<String>) {
+        let entries = ["a", "b", "c", "d"].iter().map(|e| e.to_string()).collect();
+        let query = ListQuery {
+            n,
+            last: last.map(|l| l.to_string()),
+        };
+        paginate(entries, &query)
+    }
+
+    #[test]
+    fn paginates_lists() {
+        let strings = |e: &[&str]| e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(page(None, None), (strings(&["a", "b", "c", "d"]), None));
+        assert_eq!(
+            page(Some(2), None),
+            (strings(&["a", "b"]), Some("b".to_string()))
+        );
+        assert_eq!(
+            page(Some(1), Some("b")),
+            (strings(&["c"]), Some("c".to_string()))
+        );
+        // The last page does not link to another.
+        assert_eq!(page(Some(2), Some("b")), (strings(&["c", "d"]), None));
+        assert_eq!(page(Some(4), None), (strings(&["a", "b", "c", "d"]), None));
+        assert_eq!(page(Some(0), None), (vec![], None));
+        // Entries after last are returned even if last is not one of them.
+        assert_eq!(page(None, Some("bb")), (strings(&["c", "d"]), None));
+        assert_eq!(page(None, Some("d")), (vec![], None));
+    }
 }
@@ -1,24 +1,137 @@
+use config::StorageDriver;
+use futures::future;
+use std::time::Duration;
+use tokio::time;
 use warp::Filter;
 
+mod auth;
 mod channel;
 mod codes;
+mod config;
 mod filters;
+mod gc;
 mod handlers;
+mod metrics;
+mod ratelimit;
 mod store;
+mod upstream;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let blob_store = store::new_blob_store();
-    let upload_store = store::new_upload_store();
-    let manifest_store = store::new_manifest_store();
-    let channel_map = channel::new_channel_map();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        print!("{}", config::usage());
+        return;
+    }
+    let config = match config::Config::load(args.into_iter()) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let opened = match (config.storage_driver, &config.storage_path) {
+        (StorageDriver::Filesystem, Some(path)) => store::new_filesystem_store(path),
+        (StorageDriver::Layout, Some(path)) => store::new_layout_store(path),
+        _ => Ok(store::new_memory_store()),
+    };
+    let store = match opened {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("could not open storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Content fetched from the upstream counts towards the quotas as well.
+    let quotas = match store::new_quota_store(store, config.quota.clone()).await {
+        Ok(q) => q,
+        Err(e) => {
+            log::error!("could not account for storage usage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut store: store::Store = quotas.clone();
+    if let Some(u) = &config.upstream {
+        match upstream::Upstream::new(u) {
+            Ok(up) => store = store::new_proxy_store(store, up, u.tag_ttl),
+            Err(e) => {
+                log::error!("could not set up the upstream: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let auth = match auth::from_config(&config.auth, config.tls.is_some()) {
+        Ok(a) => a,
+        Err(e) => {
+            log::error!("could not set up authentication: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let channel_map = channel::new_channel_map(config.event_buffer_size, config.event_history_size);
+    let limiter = ratelimit::new_limiter(&config.rate_limit);
 
-    warp::serve(
-        filters::registry(manifest_store, blob_store, upload_store, channel_map)
-            .with(warp::log("eocker")),
-    )
-    .run(([127, 0, 0, 1], 8080))
-    .await;
+    // Periodically discard abandoned uploads.
+    let (reaper_store, reaper_cm, ttl) = (store.clone(), channel_map.clone(), config.upload_ttl);
+    tokio::spawn(async move {
+        let mut interval =
+            time::interval(ttl.clamp(Duration::from_secs(1), Duration::from_secs(60)));
+        loop {
+            interval.tick().await;
+            handlers::expire_uploads(reaper_store.clone(), reaper_cm.clone(), ttl).await;
+        }
+    });
+
+    let (listen, tls) = (config.listen, config.tls.clone());
+    let server = warp::serve(
+        filters::registry(
+            store,
+            channel_map,
+            config,
+            auth,
+            quotas,
+            limiter,
+            metrics::new_metrics(),
+        )
+        .with(warp::log("eocker")),
+    );
+    match tls {
+        Some(tls) => {
+            let (cert, key) = match tls.load() {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("could not set up TLS: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            // The server is never shut down, but only the graceful variant
+            // reports a failure to bind rather than panicking.
+            match server
+                .tls()
+                .cert(cert)
+                .key(key)
+                .try_bind_with_graceful_shutdown(listen, future::pending())
+            {
+                Ok((addr, serving)) => {
+                    log::info!("listening on https://{}", addr);
+                    serving.await;
+                }
+                Err(e) => {
+                    log::error!("could not listen on {}: {}", listen, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => match server.try_bind_ephemeral(listen) {
+            Ok((addr, serving)) => {
+                log::info!("listening on http://{}", addr);
+                serving.await;
+            }
+            Err(e) => {
+                log::error!("could not listen on {}: {}", listen, e);
+                std::process::exit(1);
+            }
+        },
+    }
 }
@@ -0,0 +1,299 @@
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+// Upper bounds of the request duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Recorder holds what the registry reports at /metrics in the Prometheus text
+// format. Requests are recorded as routes respond, while gauges such as the
+// active uploads are read from their source as metrics are rendered.
+#[derive(Default)]
+pub struct Recorder {
+    routes: Mutex<BTreeMap<&'static str, RouteMetrics>>,
+    received_bytes: AtomicU64,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    // Responses by status code.
+    responses: BTreeMap<u16, u64>,
+    // Cumulative counts of each bucket of DURATION_BUCKETS.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    duration_sum: f64,
+    count: u64,
+    sent_bytes: u64,
+}
+
+pub type Metrics = Arc<Recorder>;
+
+pub fn new_metrics() -> Metrics {
+    Arc::new(Recorder::default())
+}
+
+// Gauges read as metrics are rendered.
+pub struct Gauges {
+    pub active_uploads: usize,
+    pub blobs: usize,
+    pub manifests: usize,
+    pub stored_bytes: u64,
+    // Event subscribers by namespace.
+    pub subscribers: Vec<(String, usize)>,
+}
+
+impl Recorder {
+    // Records the response of a route, which took elapsed to produce, and
+    // returns it to be sent. The bytes sent are those of the body, which are
+    // counted as they are sent if the body is streamed.
+    pub fn observe(
+        self: &Arc<Self>,
+        route: &'static str,
+        res: Response,
+        elapsed: Duration,
+    ) -> Response {
+        let seconds = elapsed.as_secs_f64();
+        {
+            let mut routes = self.routes();
+            let m = routes.entry(route).or_default();
+            *m.responses.entry(res.status().as_u16()).or_default() += 1;
+            for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+                if seconds <= *bound {
+                    m.buckets[i] += 1;
+                }
+            }
+            m.duration_sum += seconds;
+            m.count += 1;
+        }
+        if let Some(size) = res.body().size_hint().exact() {
+            self.sent(route, size);
+            return res;
+        }
+        let recorder = self.clone();
+        res.map(|body| {
+            Body::wrap_stream(body.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    recorder.sent(route, chunk.len() as u64);
+                }
+            }))
+        })
+    }
+
+    // Records bytes sent in the response body of a route.
+    fn sent(&self, route: &'static str, bytes: u64) {
+        self.routes().entry(route).or_default().sent_bytes += bytes;
+    }
+
+    // A poisoned lock only means another request panicked while recording,
+    // which leaves the counts usable.
+    fn routes(&self) -> MutexGuard<'_, BTreeMap<&'static str, RouteMetrics>> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Records bytes received in request bodies.
+    pub fn received(&self, bytes: u64) {
+        self.received_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let routes = self.routes();
+
+        header(
+            &mut out,
+            "eocker_http_requests_total",
+            "counter",
+            "Requests responded to, by route and status.",
+        );
+        for (route, m) in routes.iter() {
+            for (status, n) in &m.responses {
+                let _ = writeln!(
+                    out,
+                    "eocker_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    route, status, n
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "eocker_http_request_duration_seconds",
+            "histogram",
+            "Time until responses were ready to be sent, by route.",
+        );
+        for (route, m) in routes.iter() {
+            for (bound, n) in DURATION_BUCKETS.iter().zip(&m.buckets) {
+                let _ = writeln!(
+                    out,
+                    "eocker_http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, bound, n
+                );
+            }
+            let _ = writeln!(
+                out,
+                "eocker_http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route, m.count
+            );
+            let _ = writeln!(
+                out,
+                "eocker_http_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route, m.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "eocker_http_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, m.count
+            );
+        }
+
+        header(
+            &mut out,
+            "eocker_http_response_bytes_total",
+            "counter",
+            "Bytes of response bodies, by route.",
+        );
+        for (route, m) in routes.iter() {
+            let _ = writeln!(
+                out,
+                "eocker_http_response_bytes_total{{route=\"{}\"}} {}",
+                route, m.sent_bytes
+            );
+        }
+        drop(routes);
+
+        header(
+            &mut out,
+            "eocker_http_request_bytes_total",
+            "counter",
+            "Bytes of blob and manifest request bodies.",
+        );
+        let _ = writeln!(
+            out,
+            "eocker_http_request_bytes_total {}",
+            self.received_bytes.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "eocker_uploads_active",
+            "gauge",
+            "Uploads that have been started and not yet finished.",
+        );
+        let _ = writeln!(out, "eocker_uploads_active {}", gauges.active_uploads);
+
+        header(
+            &mut out,
+            "eocker_store_objects",
+            "gauge",
+            "Distinct blobs and manifests stored, by kind.",
+        );
+        let _ = writeln!(
+            out,
+            "eocker_store_objects{{kind=\"blob\"}} {}",
+            gauges.blobs
+        );
+        let _ = writeln!(
+            out,
+            "eocker_store_objects{{kind=\"manifest\"}} {}",
+            gauges.manifests
+        );
+
+        header(
+            &mut out,
+            "eocker_store_bytes",
+            "gauge",
+            "Bytes of the distinct blobs and manifests stored.",
+        );
+        let _ = writeln!(out, "eocker_store_bytes {}", gauges.stored_bytes);
+
+        header(
+            &mut out,
+            "eocker_event_subscribers",
+            "gauge",
+            "Clients subscribed to events, by namespace.",
+        );
+        for (ns, n) in &gauges.subscribers {
+            let _ = writeln!(
+                out,
+                "eocker_event_subscribers{{namespace=\"{}\"}} {}",
+                escape(ns),
+                n
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Escapes a label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::hyper::body::{to_bytes, Bytes};
+
+    fn gauges() -> Gauges {
+        Gauges {
+            active_uploads: 0,
+            blobs: 0,
+            manifests: 0,
+            stored_bytes: 0,
+            subscribers: vec![],
+        }
+    }
+
+    fn sent_bytes(metrics: &Metrics, route: &str) -> String {
+        let prefix = format!("eocker_http_response_bytes_total{{route=\"{}\"}} ", route);
+        metrics
+            .render(&gauges())
+            .lines()
+            .find_map(|l| l.strip_prefix(&prefix).map(|n| n.to_string()))
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_of_streamed_bodies_as_they_are_sent() {
+        let metrics = new_metrics();
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from("abc")), Ok(Bytes::from("defg"))];
+        let res = Response::new(Body::wrap_stream(futures::stream::iter(chunks)));
+        let res = metrics.observe("pull_blob", res, Duration::from_millis(1));
+        assert_eq!(sent_bytes(&metrics, "pull_blob"), "0");
+        let body = to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "abcdefg");
+        assert_eq!(sent_bytes(&metrics, "pull_blob"), "7");
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_of_full_bodies_once() {
+        let metrics = new_metrics();
+        let res = metrics.observe(
+            "pull_manifest",
+            Response::new(Body::from("{}")),
+            Duration::from_millis(1),
+        );
+        assert_eq!(sent_bytes(&metrics, "pull_manifest"), "2");
+        to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(sent_bytes(&metrics, "pull_manifest"), "2");
+        let out = metrics.render(&gauges());
+        assert!(
+            out.contains("eocker_http_requests_total{route=\"pull_manifest\",status=\"200\"} 1")
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use super::config::{Rate, RateLimitConfig};
+
+// Budget is what a rate limit applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Manifests,
+    BlobBytes,
+}
+
+// Buckets are only dropped once they have refilled, so that clients cannot
+// reset their budget, and only once there are this many of them.
+const PRUNE_THRESHOLD: usize = 4096;
+
+// RateLimiter keeps a token bucket per client and budget. Buckets are refilled
+// at the rate of their budget up to its burst.
+pub struct RateLimiter {
+    manifests: Option<Rate>,
+    blob_bytes: Option<Rate>,
+    buckets: Mutex<HashMap<(Budget, String), Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub type Limiter = Arc<RateLimiter>;
+
+pub fn new_limiter(config: &RateLimitConfig) -> Limiter {
+    Arc::new(RateLimiter {
+        manifests: config.manifests,
+        blob_bytes: config.blob_bytes,
+        buckets: Mutex::new(HashMap::new()),
+    })
+}
+
+impl RateLimiter {
+    fn rate(&self, budget: Budget) -> Option<Rate> {
+        match budget {
+            Budget::Manifests => self.manifests,
+            Budget::BlobBytes => self.blob_bytes,
+        }
+    }
+
+    pub fn limits(&self, budget: Budget) -> bool {
+        self.rate(budget).is_some()
+    }
+
+    // Spends cost from the bucket of a client. A cost larger than the burst
+    // is allowed once the bucket is full and leaves it in debt, so that large
+    // blobs can still be pulled. Returns how long the client has to wait if
+    // the bucket does not hold enough.
+    pub async fn take(&self, budget: Budget, client: &str, cost: u64) -> Result<(), Duration> {
+        self.take_at(budget, client, cost, Instant::now()).await
+    }
+
+    async fn take_at(
+        &self,
+        budget: Budget,
+        client: &str,
+        cost: u64,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let rate = match self.rate(budget) {
+            None => return Ok(()),
+            Some(r) => r,
+        };
+        let mut buckets = self.buckets.lock().await;
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|(b, _), bucket| match self.rate(*b) {
+                Some(r) => refilled(bucket, r, now) < r.burst,
+                None => false,
+            });
+        }
+        let bucket = buckets
+            .entry((budget, client.to_string()))
+            .or_insert(Bucket {
+                tokens: rate.burst,
+                updated: now,
+            });
+        bucket.tokens = refilled(bucket, rate, now);
+        bucket.updated = now;
+        let cost = cost as f64;
+        let needed = cost.min(rate.burst);
+        if bucket.tokens >= needed {
+            bucket.tokens -= cost;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (needed - bucket.tokens) / rate.per_second,
+        ))
+    }
+}
+
+// Returns the tokens a bucket holds once it has been refilled up to now.
+fn refilled(bucket: &Bucket, rate: Rate, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * rate.per_second).min(rate.burst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(manifests: Option<Rate>, blob_bytes: Option<Rate>) -> Limiter {
+        new_limiter(&RateLimitConfig {
+            manifests,
+            blob_bytes,
+        })
+    }
+
+    fn rate(per_second: f64, burst: f64) -> Option<Rate> {
+        Some(Rate { per_second, burst })
+    }
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[tokio::test]
+    async fn spends_and_refills_buckets() {
+        let l = limiter(rate(2.0, 3.0), None);
+        let t = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(l.take_at(Budget::Manifests, "a", 1, t).await, Ok(()));
+        }
+        assert_eq!(
+            l.take_at(Budget::Manifests, "a", 1, t).await,
+            Err(secs(0.5))
+        );
+        // Other clients have buckets of their own.
+        assert_eq!(l.take_at(Budget::Manifests, "b", 1, t).await, Ok(()));
+        let later = t + secs(0.5);
+        assert_eq!(l.take_at(Budget::Manifests, "a", 1, later).await, Ok(()));
+        assert_eq!(
+            l.take_at(Budget::Manifests, "a", 1, later).await,
+            Err(secs(0.5))
+        );
+        // Buckets only refill up to their burst.
+        let much_later = later + secs(60.0);
+        for _ in 0..3 {
+            assert_eq!(
+                l.take_at(Budget::Manifests, "a", 1, much_later).await,
+                Ok(())
+            );
+        }
+        assert!(l
+            .take_at(Budget::Manifests, "a", 1, much_later)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn allows_costs_over_the_burst_into_debt() {
+        let l = limiter(None, rate(10.0, 100.0));
+        let t = Instant::now();
+        // A cost over the burst needs a full bucket, and leaves it in debt.
+        assert_eq!(l.take_at(Budget::BlobBytes, "a", 250, t).await, Ok(()));
+        assert_eq!(
+            l.take_at(Budget::BlobBytes, "a", 1, t).await,
+            Err(secs(15.1))
+        );
+        assert_eq!(
+            l.take_at(Budget::BlobBytes, "a", 250, t + secs(15.0)).await,
+            Err(secs(10.0))
+        );
+        assert_eq!(
+            l.take_at(Budget::BlobBytes, "a", 250, t + secs(25.0)).await,
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_limit_unset_budgets() {
+        let l = limiter(rate(1.0, 1.0), None);
+        assert!(!l.limits(Budget::BlobBytes));
+        let t = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(l.take_at(Budget::BlobBytes, "a", 1 << 40, t).await, Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn prunes_only_refilled_buckets() {
+        let l = limiter(rate(1.0, 1.0), None);
+        let t = Instant::now();
+        l.take_at(Budget::Manifests, "spent", 1, t).await.unwrap();
+        for i in 0..PRUNE_THRESHOLD - 1 {
+            let client = format!("client {}", i);
+            l.take_at(Budget::Manifests, &client, 1, t - secs(10.0))
+                .await
+                .unwrap();
+        }
+        assert_eq!(l.buckets.lock().await.len(), PRUNE_THRESHOLD);
+        // Every bucket but that of the client that just spent its budget has
+        // refilled, and is dropped.
+        let later = t + secs(0.5);
+        assert!(l
+            .take_at(Budget::Manifests, "spent", 1, later)
+            .await
+            .is_err());
+        let buckets = l.buckets.lock().await;
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key(&(Budget::Manifests, "spent".to_string())));
+    }
+}
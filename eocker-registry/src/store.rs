@@ -1,8 +1,24 @@
+use async_trait::async_trait;
 use bytes::Bytes;
+use eocker::digest::Hash;
+use eocker::types::MediaType;
+use eocker::Descriptor;
+use futures::Stream;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{fmt, io, ops::Range, pin::Pin, sync::Arc, time::SystemTime};
+
+mod filesystem;
+mod layout;
+mod memory;
+mod proxy;
+mod quota;
+
+pub use filesystem::FilesystemStorage;
+pub use layout::LayoutStorage;
+pub use memory::MemoryStorage;
+pub use proxy::ProxyStorage;
+pub use quota::QuotaStorage;
 
 #[derive(Debug, Deserialize)]
 pub struct PushQuery {
@@ -10,29 +26,716 @@ pub struct PushQuery {
     pub digest: String,
 }
 
-// TODO(hasheddan): consider using a RwLock
-pub type BlobStore = Arc<Mutex<HashMap<String, Bytes>>>;
+#[derive(Debug, Deserialize)]
+pub struct MountQuery {
+    pub mount: String,
+    pub from: String,
+}
 
-pub fn new_blob_store() -> BlobStore {
-    Arc::new(Mutex::new(HashMap::new()))
+#[derive(Debug, Deserialize)]
+pub struct ReferrersQuery {
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
 }
 
-// TODO(hasheddan): consider using a RwLock
-pub type UploadStore = Arc<Mutex<HashMap<String, Bytes>>>;
+#[derive(Debug, Deserialize)]
+pub struct GcQuery {
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+    // Whether manifests no tag references are removed as well.
+    #[serde(default)]
+    pub untagged: bool,
+}
 
-pub fn new_upload_store() -> UploadStore {
-    Arc::new(Mutex::new(HashMap::new()))
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub n: Option<usize>,
+    pub last: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagList {
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Catalog {
+    pub repositories: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Manifest {
+    pub digest: String,
     pub content_type: String,
     pub content: Bytes,
 }
 
-// TODO(hasheddan): consider using a RwLock
-pub type ManifestStore = Arc<Mutex<HashMap<String, HashMap<String, Manifest>>>>;
+// UploadSession describes an upload that has been started but not yet
+// committed.
+#[derive(Debug, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    pub repo: String,
+    pub created: SystemTime,
+    // Last time content was appended to the upload.
+    pub updated: SystemTime,
+    pub size: u64,
+}
+
+// Stored describes a blob link or manifest of a repository for garbage
+// collection.
+#[derive(Debug, Clone)]
+pub struct Stored {
+    pub repo: String,
+    pub digest: String,
+    // When the blob was linked or the manifest was stored. Content created
+    // recently may belong to a push that is still in progress.
+    pub created: SystemTime,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    // A chunk did not start at the end of the upload, which has the given
+    // size.
+    RangeInvalid(u64),
+    // Uploaded content could not be verified against the provided digest.
+    DigestInvalid(String),
+    // A repository name or tag cannot be represented by the storage backend.
+    NameInvalid(String),
+    // No upload with the id has been started, or it has been committed,
+    // cancelled or expired.
+    UploadUnknown(String),
+    // Storing the content would exceed a quota.
+    Denied(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::RangeInvalid(size) => write!(f, "chunk must start at {}", size),
+            StoreError::DigestInvalid(detail) => f.write_str(detail),
+            StoreError::NameInvalid(name) => write!(f, "invalid name {}", name),
+            StoreError::UploadUnknown(id) => write!(f, "unknown upload {}", id),
+            StoreError::Denied(detail) => f.write_str(detail),
+            StoreError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+// ByteStream is content streamed into or out of storage, so that blobs never
+// have to be held in memory as a whole.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+// Blob is the content of a blob, or of a range of it, along with the size of
+// the whole blob.
+pub struct Blob {
+    pub size: u64,
+    pub content: ByteStream,
+}
+
+// Storage abstracts where the registry keeps blobs, in progress uploads and
+// manifests.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // --- Blobs
+    // Blobs are only accessible from repositories they were pushed to, though
+    // backends may share their content across repositories.
+
+    // Returns a blob, or only the given range of it. Ranges are clamped to
+    // the size of the blob.
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>>;
+
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>>;
+
+    // Returns false if the blob does not exist.
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool>;
+
+    // Makes a blob of the source repository available from repo without it
+    // being uploaded again. Returns false if the source does not have the
+    // blob.
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool>;
+
+    // --- Uploads
+
+    // Starts an upload to a repository and returns its id.
+    async fn start_upload(&self, repo: &str) -> StoreResult<String>;
+
+    // Uploads belong to the repository they were started in, and are unknown
+    // to every other repository.
+
+    // Returns the session of an upload, or None if the upload is unknown.
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>>;
+
+    // Appends a chunk to an upload. The chunk must start at the current size
+    // of the upload, and a missing start is treated as 0. Returns the new
+    // size of the upload. Content is hashed as it is written so that
+    // committing does not have to read the upload back.
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64>;
+
+    // Appends a final chunk to an upload and commits it as a blob of the
+    // repository if the content matches the digest. The upload is removed
+    // once committed.
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()>;
+
+    // Discards an upload. Returns false if the upload is unknown.
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool>;
+
+    // Discards uploads that have not been appended to since the given time,
+    // returning their sessions. Uploads that are being written to are kept.
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>>;
+
+    // Returns how many uploads have been started and not yet committed,
+    // cancelled or expired.
+    async fn active_uploads(&self) -> StoreResult<usize>;
+
+    // --- Manifests
+
+    // Returns the manifest referenced by a tag or digest in a repository.
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>>;
+
+    // Stores a manifest by digest, additionally tagging it if the reference
+    // is not the digest itself.
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()>;
+
+    // Removes a tag, or a manifest along with every tag referencing it.
+    // Returns false if the reference is unknown.
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool>;
+
+    // Returns the tags of a repository, or None if the repository is unknown.
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>>;
+
+    async fn repositories(&self) -> StoreResult<Vec<String>>;
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool>;
+
+    // --- Referrers
+
+    // Records that a manifest of the repository refers to subject, listing
+    // it under the given descriptor.
+    async fn add_referrer(
+        &self,
+        repo: &str,
+        subject: &str,
+        referrer: Descriptor,
+    ) -> StoreResult<()>;
+
+    // Returns the descriptors of the manifests in the repository that refer
+    // to subject. Manifests that have been deleted are left out.
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>>;
+
+    // --- Garbage collection
+
+    // Returns the blob links of every repository.
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>>;
+
+    // Returns the manifests of every repository, whether tagged or not.
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>>;
+
+    // Removes the content of blobs that no repository links to and that was
+    // stored before the given time, returning their digests. With dry_run,
+    // the blobs are only returned.
+    async fn purge_blobs(&self, before: SystemTime, dry_run: bool) -> StoreResult<Vec<String>>;
+}
+
+pub type Store = Arc<dyn Storage>;
+
+pub fn new_memory_store() -> Store {
+    Arc::new(MemoryStorage::default())
+}
+
+pub fn new_filesystem_store(root: &std::path::Path) -> io::Result<Store> {
+    Ok(Arc::new(FilesystemStorage::open(root)?))
+}
+
+pub fn new_layout_store(root: &std::path::Path) -> io::Result<Store> {
+    Ok(Arc::new(LayoutStorage::open(root)?))
+}
+
+// Quotas is the storage that accounts for usage, kept apart from the Store
+// wrapping it so that usage can be reported.
+pub type Quotas = Arc<QuotaStorage>;
+
+pub async fn new_quota_store(
+    inner: Store,
+    quotas: crate::config::QuotaConfig,
+) -> StoreResult<Quotas> {
+    Ok(Arc::new(QuotaStorage::open(inner, quotas).await?))
+}
+
+pub fn new_proxy_store(
+    local: Store,
+    upstream: crate::upstream::Upstream,
+    tag_ttl: std::time::Duration,
+) -> Store {
+    Arc::new(ProxyStorage::new(local, upstream, tag_ttl))
+}
+
+// The fields of image manifests and indexes that determine how they are listed
+// as referrers.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReferrerFields {
+    artifact_type: Option<MediaType>,
+    config: Option<Descriptor>,
+    subject: Option<Descriptor>,
+    annotations: Option<HashMap<String, String>>,
+}
+
+// Returns the digest of the subject a manifest refers to, along with the
+// descriptor the manifest is listed under in the referrers of the subject.
+// The artifact type of image manifests without one is their config type.
+pub fn referrer(manifest: &Manifest) -> Option<(String, Descriptor)> {
+    let fields: ReferrerFields = serde_json::from_slice(&manifest.content).ok()?;
+    let subject = fields.subject?;
+    let config = fields.config;
+    let descriptor = Descriptor {
+        media_type: MediaType::from(manifest.content_type.as_str()),
+        size: manifest.content.len() as i64,
+        digest: manifest.digest.parse().ok()?,
+        urls: None,
+        annotations: fields.annotations,
+        platform: None,
+        artifact_type: fields
+            .artifact_type
+            .or_else(|| config.map(|c| c.media_type)),
+    };
+    Some((subject.digest.to_string(), descriptor))
+}
+
+// Parses a manifest of the given media type and returns the descriptors it
+// references, keyed by the type of object they point to.
+pub fn parse_manifest(
+    media_type: &MediaType,
+    content: &[u8],
+) -> Result<Vec<(&'static str, eocker::Descriptor)>, String> {
+    let (schema_version, embedded_type, refs) = match media_type {
+        MediaType::OCIImageIndex | MediaType::DockerManifestList => {
+            let i: eocker::IndexManifest =
+                serde_json::from_slice(content).map_err(|e| e.to_string())?;
+            let refs = i.manifests.into_iter().map(|d| ("Manifest", d)).collect();
+            (i.schema_version, i.media_type, refs)
+        }
+        MediaType::OCIManifestSchema1 | MediaType::DockerManifestSchema2 => {
+            let m: eocker::Manifest = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+            let mut refs: Vec<(&'static str, eocker::Descriptor)> =
+                m.layers.into_iter().map(|l| ("Blob", l)).collect();
+            refs.push(("Blob", m.config));
+            (m.schema_version, m.media_type, refs)
+        }
+        _ => return Err(format!("unsupported manifest media type {}", media_type)),
+    };
+    if schema_version != 2 {
+        return Err(format!("unsupported schemaVersion {}", schema_version));
+    }
+    match embedded_type {
+        Some(t) if t != *media_type => Err(format!("mediaType {} does not match Content-Type", t)),
+        _ => Ok(refs),
+    }
+}
+
+// Reports whether a digest is the reference itself rather than a tag.
+pub fn is_digest(reference: &str) -> bool {
+    reference.parse::<Hash>().is_ok()
+}
+
+// Reports whether name is a valid repository name.
+// [a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(\/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*
+pub fn valid_repository(name: &str) -> bool {
+    name.split('/').all(|component| {
+        let b = component.as_bytes();
+        let alnum = |c: &u8| c.is_ascii_lowercase() || c.is_ascii_digit();
+        if b.is_empty() || !alnum(&b[0]) || !alnum(&b[b.len() - 1]) {
+            return false;
+        }
+        let mut i = 0;
+        while i < b.len() {
+            if alnum(&b[i]) {
+                i += 1;
+                continue;
+            }
+            // Separators must be followed by an alphanumeric character.
+            let sep = match b[i] {
+                b'.' => 1,
+                b'_' if b.get(i + 1) == Some(&b'_') => 2,
+                b'_' => 1,
+                b'-' => b[i..].iter().take_while(|c| **c == b'-').count(),
+                _ => return false,
+            };
+            i += sep;
+            match b.get(i) {
+                Some(c) if alnum(c) => (),
+                _ => return false,
+            }
+        }
+        true
+    })
+}
+
+// Reports whether tag is a valid tag.
+// [a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}
+pub fn valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphanumeric() || c == '_' => (),
+        _ => return false,
+    }
+    tag.len() <= 128 && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, StreamExt};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    // TempDir is a directory that is removed along with its content when
+    // dropped.
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new() -> TempDir {
+            let path = std::env::temp_dir().join(format!("eocker-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        pub(super) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(super) fn chunk(content: &'static [u8]) -> ByteStream {
+        Box::pin(stream::iter(vec![Ok(Bytes::from_static(content))]))
+    }
+
+    pub(super) async fn read_blob(store: &Store, repo: &str, digest: &str) -> Option<Vec<u8>> {
+        let mut blob = store.get_blob(repo, digest, None).await.unwrap()?;
+        let mut content = vec![];
+        while let Some(b) = blob.content.next().await {
+            content.extend_from_slice(&b.unwrap());
+        }
+        Some(content)
+    }
+
+    // Pushes a blob to a repository and returns its digest.
+    pub(super) async fn push_blob(store: &Store, repo: &str, content: &'static [u8]) -> String {
+        let digest = Hash::of("sha256", content).unwrap();
+        let id = store.start_upload(repo).await.unwrap();
+        store
+            .commit_upload(repo, &id, &digest, chunk(content))
+            .await
+            .unwrap();
+        digest.to_string()
+    }
+
+    pub(super) fn image_manifest(config: &str, extra: &str) -> Manifest {
+        let content = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"{}"}},"layers":[]{}}}"#,
+            config, extra
+        );
+        Manifest {
+            digest: Hash::of("sha256", content.as_bytes()).unwrap().to_string(),
+            content_type: MediaType::OCIManifestSchema1.to_string(),
+            content: Bytes::from(content),
+        }
+    }
+
+    // Exercises the behavior every storage backend shares.
+    pub(super) async fn exercise(store: Store) {
+        // Uploads
+        let id = store.start_upload("a/b").await.unwrap();
+        assert_eq!(store.active_uploads().await.unwrap(), 1);
+        assert!(store.upload_session("c", &id).await.unwrap().is_none());
+        assert_eq!(
+            store
+                .append_upload("a/b", &id, None, chunk(b"hel"))
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(matches!(
+            store.append_upload("a/b", &id, Some(0), chunk(b"x")).await,
+            Err(StoreError::RangeInvalid(3))
+        ));
+        assert_eq!(
+            store
+                .upload_session("a/b", &id)
+                .await
+                .unwrap()
+                .unwrap()
+                .size,
+            3
+        );
+        let wrong = Hash::of("sha256", b"other").unwrap();
+        assert!(matches!(
+            store.commit_upload("a/b", &id, &wrong, chunk(b"")).await,
+            Err(StoreError::DigestInvalid(_))
+        ));
+        let id = store.start_upload("a/b").await.unwrap();
+        store
+            .append_upload("a/b", &id, Some(0), chunk(b"hel"))
+            .await
+            .unwrap();
+        let digest = Hash::of("sha256", b"hello").unwrap();
+        store
+            .commit_upload("a/b", &id, &digest, chunk(b"lo"))
+            .await
+            .unwrap();
+        assert!(store.upload_session("a/b", &id).await.unwrap().is_none());
+        let d = digest.to_string();
+
+        // Blobs
+        assert_eq!(read_blob(&store, "a/b", &d).await.unwrap(), b"hello");
+        assert_eq!(store.blob_size("a/b", &d).await.unwrap(), Some(5));
+        let mut range = store
+            .get_blob("a/b", &d, Some(1..3))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(range.size, 5);
+        assert_eq!(range.content.next().await.unwrap().unwrap(), "el");
+        assert!(read_blob(&store, "c", &d).await.is_none());
+        assert!(!store.mount_blob("c", "d", &d).await.unwrap());
+        assert!(store.mount_blob("c", "a/b", &d).await.unwrap());
+        assert_eq!(read_blob(&store, "c", &d).await.unwrap(), b"hello");
+
+        // Manifests
+        let config = push_blob(&store, "a/b", b"{}").await;
+        let m = image_manifest(&config, "");
+        store
+            .put_manifest("a/b", "latest", m.clone())
+            .await
+            .unwrap();
+        for reference in &["latest", m.digest.as_str()] {
+            let got = store.get_manifest("a/b", reference).await.unwrap().unwrap();
+            assert_eq!(
+                (got.digest, got.content),
+                (m.digest.clone(), m.content.clone())
+            );
+        }
+        assert_eq!(
+            store.tags("a/b").await.unwrap(),
+            Some(vec!["latest".to_string()])
+        );
+        assert!(store.tags("e").await.unwrap().is_none());
+        assert!(store.repository_exists("a/b").await.unwrap());
+        assert!(!store.repository_exists("e").await.unwrap());
+        assert!(store
+            .repositories()
+            .await
+            .unwrap()
+            .contains(&"a/b".to_string()));
+        assert!(store
+            .stored_manifests()
+            .await
+            .unwrap()
+            .iter()
+            .any(|s| s.repo == "a/b" && s.digest == m.digest));
+        assert!(store
+            .blob_links()
+            .await
+            .unwrap()
+            .iter()
+            .any(|s| s.repo == "c" && s.digest == d));
+
+        // Referrers
+        let r = image_manifest(
+            &config,
+            &format!(
+                r#","subject":{{"mediaType":"{}","size":{},"digest":"{}"}}"#,
+                m.content_type,
+                m.content.len(),
+                m.digest
+            ),
+        );
+        let (subject, descriptor) = referrer(&r).unwrap();
+        assert_eq!(subject, m.digest);
+        store
+            .put_manifest("a/b", &r.digest, r.clone())
+            .await
+            .unwrap();
+        store
+            .add_referrer("a/b", &subject, descriptor)
+            .await
+            .unwrap();
+        let referrers = store.referrers("a/b", &m.digest).await.unwrap();
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].digest.to_string(), r.digest);
+        store.delete_manifest("a/b", &r.digest).await.unwrap();
+        assert!(store.referrers("a/b", &m.digest).await.unwrap().is_empty());
+
+        // Deletion
+        assert!(store.delete_manifest("a/b", "latest").await.unwrap());
+        assert_eq!(store.tags("a/b").await.unwrap(), Some(vec![]));
+        assert!(store
+            .get_manifest("a/b", &m.digest)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store.delete_manifest("a/b", &m.digest).await.unwrap());
+        assert!(store
+            .get_manifest("a/b", &m.digest)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!store.delete_manifest("a/b", &m.digest).await.unwrap());
+        assert!(store.delete_blob("a/b", &d).await.unwrap());
+        assert!(!store.delete_blob("a/b", &d).await.unwrap());
+        assert!(read_blob(&store, "a/b", &d).await.is_none());
+        assert_eq!(read_blob(&store, "c", &d).await.unwrap(), b"hello");
+
+        // Cancelled and expired uploads
+        let id = store.start_upload("a/b").await.unwrap();
+        assert!(store.cancel_upload("a/b", &id).await.unwrap());
+        assert!(!store.cancel_upload("a/b", &id).await.unwrap());
+        let id = store.start_upload("a/b").await.unwrap();
+        let later = SystemTime::now() + Duration::from_secs(1);
+        let expired = store.expire_uploads(later).await.unwrap();
+        assert!(expired.iter().any(|s| s.id == id));
+        assert!(store.upload_session("a/b", &id).await.unwrap().is_none());
+        assert_eq!(store.active_uploads().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        exercise(new_memory_store()).await;
+    }
+
+    #[tokio::test]
+    async fn filesystem_storage() {
+        let dir = TempDir::new();
+        exercise(new_filesystem_store(dir.path()).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn layout_storage() {
+        let dir = TempDir::new();
+        exercise(new_layout_store(dir.path()).unwrap()).await;
+    }
+
+    #[test]
+    fn validates_repository_names() {
+        for name in &["a", "a/b", "a0.b_c__d---e/f", "library/ubuntu"] {
+            assert!(valid_repository(name), "{}", name);
+        }
+        for name in &[
+            "", "A", "a/", "/a", "a//b", "a.", "_a", "a___b", "a..b", "a:b",
+        ] {
+            assert!(!valid_repository(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn validates_tags() {
+        for tag in &["latest", "_x", "v1.2.3-rc_1", &"a".repeat(128)] {
+            assert!(valid_tag(tag), "{}", tag);
+        }
+        for tag in &["", ".x", "-x", "a/b", "a:b", &"a".repeat(129)] {
+            assert!(!valid_tag(tag), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn parses_manifests() {
+        let config = Hash::of("sha256", b"{}").unwrap().to_string();
+        let m = image_manifest(&config, "");
+        let refs = parse_manifest(&MediaType::OCIManifestSchema1, &m.content).unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].0, "Blob");
+        assert_eq!(refs[0].1.digest.to_string(), config);
+
+        let index = format!(
+            r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"{}","size":{},"digest":"{}"}}]}}"#,
+            m.content_type,
+            m.content.len(),
+            m.digest
+        );
+        let refs = parse_manifest(&MediaType::OCIImageIndex, index.as_bytes()).unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].0, "Manifest");
+
+        // The embedded media type must match the one the manifest is pushed
+        // as, and only version 2 is supported.
+        assert!(parse_manifest(&MediaType::DockerManifestSchema2, &m.content).is_err());
+        let v1 = String::from_utf8(m.content.to_vec())
+            .unwrap()
+            .replace("\"schemaVersion\":2", "\"schemaVersion\":1");
+        assert!(parse_manifest(&MediaType::OCIManifestSchema1, v1.as_bytes()).is_err());
+        assert!(parse_manifest(&MediaType::OCIManifestSchema1, b"{").is_err());
+        assert!(parse_manifest(&MediaType::OCIConfigJSON, &m.content).is_err());
+    }
+
+    #[test]
+    fn finds_referrers() {
+        let config = Hash::of("sha256", b"{}").unwrap().to_string();
+        let subject = image_manifest(&config, "");
+        assert!(referrer(&subject).is_none());
+        let r = image_manifest(
+            &config,
+            &format!(
+                r#","subject":{{"mediaType":"{}","size":1,"digest":"{}"}},"annotations":{{"k":"v"}}"#,
+                subject.content_type, subject.digest
+            ),
+        );
+        let (s, d) = referrer(&r).unwrap();
+        assert_eq!(s, subject.digest);
+        assert_eq!(d.digest.to_string(), r.digest);
+        assert_eq!(d.size, r.content.len() as i64);
+        // Without an artifact type, that of the config is used.
+        assert_eq!(
+            d.artifact_type.unwrap().to_string(),
+            "application/vnd.oci.image.config.v1+json"
+        );
+        assert_eq!(d.annotations.unwrap()["k"], "v");
 
-pub fn new_manifest_store() -> ManifestStore {
-    Arc::new(Mutex::new(HashMap::new()))
+        let typed = image_manifest(
+            &config,
+            &format!(
+                r#","artifactType":"application/x.sig","subject":{{"mediaType":"{}","size":1,"digest":"{}"}}"#,
+                subject.content_type, subject.digest
+            ),
+        );
+        let (_, d) = referrer(&typed).unwrap();
+        assert_eq!(d.artifact_type.unwrap().to_string(), "application/x.sig");
+    }
 }
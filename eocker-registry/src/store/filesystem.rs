@@ -0,0 +1,1143 @@
+use async_trait::async_trait;
+use bytes::BytesMut;
+use eocker::digest::{Hash, Hasher};
+use eocker::Descriptor;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
+use uuid::Uuid;
+
+use super::{
+    is_digest, valid_repository, valid_tag, Blob, ByteStream, Manifest, Storage, StoreError,
+    StoreResult, Stored, UploadSession,
+};
+
+// FilesystemStorage persists content to a directory with the following
+// layout:
+//
+//   blobs/<algorithm>/<first two hex characters>/<hex>
+//   uploads/<uuid>/{data,session}
+//   repositories/<name>/_blobs/<algorithm>/<hex>
+//   repositories/<name>/_manifests/<algorithm>/<hex>/{content,content-type}
+//   repositories/<name>/_tags/<tag>
+//   repositories/<name>/_referrers/<algorithm>/<hex>/<algorithm>/<hex>
+//   tmp/
+//
+// Blob content is shared by every repository, and a repository can only
+// access the blobs it holds a link to in _blobs/. The descriptors of manifests
+// that refer to a subject are kept in _referrers/ under the digest of the
+// subject. Blobs and manifests are content addressed. Everything is first
+// written to tmp/, synced and then renamed into place, so readers never
+// observe partially written content, even after a crash. A blob is only
+// linked once its content is in place, and links whose content is missing
+// are treated as absent.
+pub struct FilesystemStorage {
+    root: PathBuf,
+    uploads: FileUploads,
+    // Held for reading while blobs are moved into place and linked, and for
+    // writing while unlinked blobs are purged, so that a blob is never purged
+    // between the two.
+    purging: RwLock<()>,
+}
+
+const BLOBS: &str = "_blobs";
+const CONTENT: &str = "content";
+const CONTENT_TYPE: &str = "content-type";
+const MANIFESTS: &str = "_manifests";
+const REFERRERS: &str = "_referrers";
+const TAGS: &str = "_tags";
+
+impl FilesystemStorage {
+    // Opens storage rooted at the given directory, creating it if it does not
+    // exist and recovering from any writes interrupted by a previous exit.
+    pub fn open(root: &Path) -> io::Result<FilesystemStorage> {
+        let s = FilesystemStorage {
+            root: root.to_path_buf(),
+            uploads: FileUploads::open(root.join("uploads"))?,
+            purging: RwLock::new(()),
+        };
+        // Anything left in tmp/ was never committed.
+        match std::fs::remove_dir_all(s.root.join("tmp")) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        for dir in &["blobs", "repositories", "tmp"] {
+            std::fs::create_dir_all(s.root.join(dir))?;
+        }
+        let repos = find_repositories(&s.root.join("repositories"), &[BLOBS, MANIFESTS, TAGS])?;
+        for repo in repos {
+            s.recover_repository(&repo)?;
+        }
+        Ok(s)
+    }
+
+    // Removes incomplete manifests, tags that reference missing manifests
+    // and links to missing blobs.
+    fn recover_repository(&self, repo: &str) -> io::Result<()> {
+        let path = self.root.join("repositories").join(repo);
+        for (h, _) in read_digest_entries(&path.join(BLOBS))? {
+            let digest = h.to_string();
+            let exists = self
+                .blob_path(&digest)
+                .map(|b| b.is_file())
+                .unwrap_or(false);
+            if !exists {
+                log::warn!("removing link of {} to missing blob {}", repo, digest);
+                std::fs::remove_file(path.join(BLOBS).join(&h.algorithm).join(&h.hex))?;
+            }
+        }
+        for algorithm in read_dir_names(&path.join(MANIFESTS))? {
+            let dir = path.join(MANIFESTS).join(algorithm);
+            for hex in read_dir_names(&dir)? {
+                let m = dir.join(hex);
+                if !m.join(CONTENT).is_file() || !m.join(CONTENT_TYPE).is_file() {
+                    log::warn!("removing incomplete manifest {}", m.display());
+                    std::fs::remove_dir_all(&m)?;
+                }
+            }
+        }
+        for tag in read_dir_names(&path.join(TAGS))? {
+            let t = path.join(TAGS).join(&tag);
+            let digest = std::fs::read_to_string(&t)?;
+            let exists = self
+                .manifest_path(repo, &digest)
+                .map(|m| m.is_dir())
+                .unwrap_or(false);
+            if !exists {
+                log::warn!("removing tag {}:{} of missing manifest", repo, tag);
+                std::fs::remove_file(&t)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        let h = digest.parse::<Hash>().ok()?;
+        let prefix = h.hex.get(0..2)?.to_string();
+        Some(
+            self.root
+                .join("blobs")
+                .join(h.algorithm)
+                .join(prefix)
+                .join(h.hex),
+        )
+    }
+
+    fn repository_path(&self, repo: &str) -> Option<PathBuf> {
+        if !valid_repository(repo) {
+            return None;
+        }
+        Some(self.root.join("repositories").join(repo))
+    }
+
+    fn link_path(&self, repo: &str, digest: &str) -> Option<PathBuf> {
+        let h = digest.parse::<Hash>().ok()?;
+        Some(
+            self.repository_path(repo)?
+                .join(BLOBS)
+                .join(h.algorithm)
+                .join(h.hex),
+        )
+    }
+
+    // Returns the path of a blob if it has been pushed to the repository and
+    // its content exists.
+    async fn linked_blob_path(&self, repo: &str, digest: &str) -> io::Result<Option<PathBuf>> {
+        let (link, blob) = match (self.link_path(repo, digest), self.blob_path(digest)) {
+            (Some(l), Some(b)) => (l, b),
+            _ => return Ok(None),
+        };
+        if not_found_as_none(fs::metadata(link).await)?.is_none()
+            || not_found_as_none(fs::metadata(&blob).await)?.is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(blob))
+    }
+
+    fn manifest_path(&self, repo: &str, digest: &str) -> Option<PathBuf> {
+        let h = digest.parse::<Hash>().ok()?;
+        Some(
+            self.repository_path(repo)?
+                .join(MANIFESTS)
+                .join(h.algorithm)
+                .join(h.hex),
+        )
+    }
+
+    fn tag_path(&self, repo: &str, tag: &str) -> Option<PathBuf> {
+        if !valid_tag(tag) {
+            return None;
+        }
+        Some(self.repository_path(repo)?.join(TAGS).join(tag))
+    }
+
+    fn referrers_path(&self, repo: &str, subject: &str) -> Option<PathBuf> {
+        let h = subject.parse::<Hash>().ok()?;
+        Some(
+            self.repository_path(repo)?
+                .join(REFERRERS)
+                .join(h.algorithm)
+                .join(h.hex),
+        )
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(Uuid::new_v4().to_string())
+    }
+
+    // Resolves a tag or digest to the digest of a manifest.
+    async fn resolve(&self, repo: &str, reference: &str) -> StoreResult<Option<String>> {
+        if is_digest(reference) {
+            return Ok(Some(reference.to_string()));
+        }
+        let path = match self.tag_path(repo, reference) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        Ok(not_found_as_none(fs::read_to_string(path).await)?)
+    }
+
+    // Writes content to a temporary file and renames it over path.
+    async fn write_atomic(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let tmp = self.temp_path();
+        write_synced(&tmp, content).await?;
+        rename_synced(&tmp, path).await
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>> {
+        let path = match self.linked_blob_path(repo, digest).await? {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        Ok(open_blob(&path, range).await?)
+    }
+
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>> {
+        let path = match self.linked_blob_path(repo, digest).await? {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        Ok(not_found_as_none(fs::metadata(path).await)?.map(|m| m.len()))
+    }
+
+    // Only the link is removed, other repositories may still hold the blob.
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        let path = match self.link_path(repo, digest) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        Ok(not_found_as_none(fs::remove_file(path).await)?.is_some())
+    }
+
+    // Blobs are shared, so mounting only writes a link.
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool> {
+        let _linking = self.purging.read().await;
+        if self.linked_blob_path(from, digest).await?.is_none() {
+            return Ok(false);
+        }
+        let link = self
+            .link_path(repo, digest)
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        self.write_atomic(&link, digest.as_bytes()).await?;
+        Ok(true)
+    }
+
+    async fn start_upload(&self, repo: &str) -> StoreResult<String> {
+        self.uploads.start(repo).await
+    }
+
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        self.uploads.session(repo, id).await
+    }
+
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        self.uploads.append(repo, id, start, chunk).await
+    }
+
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()> {
+        let blob = self
+            .blob_path(&digest.to_string())
+            .ok_or_else(|| StoreError::DigestInvalid(digest.to_string()))?;
+        let link = self
+            .link_path(repo, &digest.to_string())
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        // The blob is linked once it is verified and in place, before it can
+        // be purged.
+        let _linking = self
+            .uploads
+            .commit(repo, id, digest, chunk, &blob, Some(&self.purging))
+            .await?;
+        self.write_atomic(&link, digest.to_string().as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        self.uploads.cancel(repo, id).await
+    }
+
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        self.uploads.expire(before).await
+    }
+
+    async fn active_uploads(&self) -> StoreResult<usize> {
+        Ok(self.uploads.active().await)
+    }
+
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        let digest = match self.resolve(repo, reference).await? {
+            None => return Ok(None),
+            Some(d) => d,
+        };
+        let path = match self.manifest_path(repo, &digest) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        let content_type =
+            match not_found_as_none(fs::read_to_string(path.join(CONTENT_TYPE)).await)? {
+                None => return Ok(None),
+                Some(t) => t,
+            };
+        let content = match not_found_as_none(fs::read(path.join(CONTENT)).await)? {
+            None => return Ok(None),
+            Some(c) => c,
+        };
+        Ok(Some(Manifest {
+            digest,
+            content_type,
+            content: content.into(),
+        }))
+    }
+
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()> {
+        let path = self
+            .manifest_path(repo, &manifest.digest)
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        if !is_dir(&path).await? {
+            let tmp = self.temp_path();
+            fs::create_dir(&tmp).await?;
+            write_synced(&tmp.join(CONTENT), &manifest.content).await?;
+            write_synced(&tmp.join(CONTENT_TYPE), manifest.content_type.as_bytes()).await?;
+            sync_dir(&tmp).await?;
+            if let Err(e) = rename_synced(&tmp, &path).await {
+                // Another push of the same manifest may have won the race.
+                fs::remove_dir_all(&tmp).await?;
+                if !is_dir(&path).await? {
+                    return Err(e.into());
+                }
+            }
+        }
+        if reference != manifest.digest {
+            let tag = self
+                .tag_path(repo, reference)
+                .ok_or_else(|| StoreError::NameInvalid(reference.to_string()))?;
+            self.write_atomic(&tag, manifest.digest.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool> {
+        if !is_digest(reference) {
+            let path = match self.tag_path(repo, reference) {
+                None => return Ok(false),
+                Some(p) => p,
+            };
+            return Ok(not_found_as_none(fs::remove_file(path).await)?.is_some());
+        }
+        let path = match self.manifest_path(repo, reference) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        if not_found_as_none(fs::remove_dir_all(path).await)?.is_none() {
+            return Ok(false);
+        }
+        // Tags are removed after the manifest. If we exit in between, dangling
+        // tags are cleaned up on the next startup.
+        let tags = self.repository_path(repo).unwrap().join(TAGS);
+        for tag in self.tags(repo).await?.unwrap_or_default() {
+            let t = tags.join(tag);
+            if not_found_as_none(fs::read_to_string(&t).await)?.as_deref() == Some(reference) {
+                not_found_as_none(fs::remove_file(&t).await)?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>> {
+        if !self.repository_exists(repo).await? {
+            return Ok(None);
+        }
+        let path = self.repository_path(repo).unwrap().join(TAGS);
+        Ok(Some(
+            tokio::task::spawn_blocking(move || read_dir_names(&path))
+                .await
+                .map_err(io::Error::other)??,
+        ))
+    }
+
+    async fn repositories(&self) -> StoreResult<Vec<String>> {
+        let path = self.root.join("repositories");
+        Ok(
+            tokio::task::spawn_blocking(move || list_repositories(&path))
+                .await
+                .map_err(io::Error::other)??,
+        )
+    }
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool> {
+        let path = match self.repository_path(repo) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        Ok(fs::metadata(path.join(MANIFESTS)).await.is_ok()
+            || fs::metadata(path.join(TAGS)).await.is_ok())
+    }
+
+    async fn add_referrer(
+        &self,
+        repo: &str,
+        subject: &str,
+        referrer: Descriptor,
+    ) -> StoreResult<()> {
+        let path = self
+            .referrers_path(repo, subject)
+            .ok_or_else(|| StoreError::DigestInvalid(subject.to_string()))?
+            .join(&referrer.digest.algorithm)
+            .join(&referrer.digest.hex);
+        let content = serde_json::to_vec(&referrer).map_err(io::Error::from)?;
+        self.write_atomic(&path, &content).await?;
+        Ok(())
+    }
+
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>> {
+        let path = match self.referrers_path(repo, subject) {
+            None => return Ok(vec![]),
+            Some(p) => p,
+        };
+        let p = path.clone();
+        let digests = tokio::task::spawn_blocking(move || read_digest_names(&p))
+            .await
+            .map_err(io::Error::other)??;
+        let mut referrers = vec![];
+        for h in digests {
+            let file = path.join(&h.algorithm).join(&h.hex);
+            let manifest = self.manifest_path(repo, &h.to_string()).unwrap();
+            // Referrers are removed once the manifest they describe has been
+            // deleted.
+            if fs::metadata(manifest).await.is_err() {
+                not_found_as_none(fs::remove_file(file).await)?;
+                continue;
+            }
+            let content = match not_found_as_none(fs::read(file).await)? {
+                None => continue,
+                Some(c) => c,
+            };
+            let descriptor = serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            referrers.push(descriptor);
+        }
+        Ok(referrers)
+    }
+
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>> {
+        let root = self.root.join("repositories");
+        Ok(
+            tokio::task::spawn_blocking(move || list_stored(&root, BLOBS))
+                .await
+                .map_err(io::Error::other)??,
+        )
+    }
+
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>> {
+        let root = self.root.join("repositories");
+        Ok(
+            tokio::task::spawn_blocking(move || list_stored(&root, MANIFESTS))
+                .await
+                .map_err(io::Error::other)??,
+        )
+    }
+
+    async fn purge_blobs(&self, before: SystemTime, dry_run: bool) -> StoreResult<Vec<String>> {
+        let root = self.root.clone();
+        let _purging = if dry_run {
+            None
+        } else {
+            Some(self.purging.write().await)
+        };
+        Ok(
+            tokio::task::spawn_blocking(move || purge_blobs(&root, before, dry_run))
+                .await
+                .map_err(io::Error::other)??,
+        )
+    }
+}
+
+// FileUploads keeps in progress uploads in a directory:
+//
+//   <uuid>/data
+//   <uuid>/session
+//
+// Sessions are loaded when the directory is opened, so uploads survive
+// restarts. Each upload is hashed as it is written, and the running hash is
+// kept for as long as it covers the whole upload, which is until the registry
+// exits or a write fails. Committing an upload without one falls back to
+// reading it back.
+pub(super) struct FileUploads {
+    dir: PathBuf,
+    active: RwLock<HashMap<String, Arc<Mutex<FileUpload>>>>,
+}
+
+struct FileUpload {
+    session: UploadSession,
+    hasher: Option<Hasher>,
+    // Set once the upload is committed or cancelled, for requests that were
+    // waiting on it.
+    closed: bool,
+}
+
+// The part of a session that is persisted. Everything else is derived from
+// the data file.
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    repo: String,
+    created: SystemTime,
+}
+
+const UPLOAD_DATA: &str = "data";
+const UPLOAD_SESSION: &str = "session";
+
+// Uploads are hashed with the canonical algorithm while they are written.
+const UPLOAD_ALGORITHM: &str = "sha256";
+
+impl FileUpload {
+    // Reports whether the upload can still be used from the repository.
+    fn is_open(&self, repo: &str) -> bool {
+        !self.closed && self.session.repo == repo
+    }
+}
+
+impl FileUploads {
+    // Opens the uploads directory, creating it if it does not exist and
+    // discarding anything that is not a readable upload.
+    pub(super) fn open(dir: PathBuf) -> io::Result<FileUploads> {
+        std::fs::create_dir_all(&dir)?;
+        let mut active = HashMap::new();
+        for id in read_dir_names(&dir)? {
+            let path = dir.join(&id);
+            match load_session(&path, &id) {
+                Some(session) => {
+                    let upload = FileUpload {
+                        session,
+                        hasher: None,
+                        closed: false,
+                    };
+                    active.insert(id, Arc::new(Mutex::new(upload)));
+                }
+                None => {
+                    log::warn!("removing unreadable upload {}", path.display());
+                    if path.is_dir() {
+                        std::fs::remove_dir_all(&path)?;
+                    } else {
+                        std::fs::remove_file(&path)?;
+                    }
+                }
+            }
+        }
+        Ok(FileUploads {
+            dir,
+            active: RwLock::new(active),
+        })
+    }
+
+    pub(super) async fn active(&self) -> usize {
+        self.active.read().await.len()
+    }
+
+    // Returns an upload. Holding its lock serializes writes to the upload.
+    async fn upload(&self, id: &str) -> StoreResult<Arc<Mutex<FileUpload>>> {
+        self.active
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| StoreError::UploadUnknown(id.to_string()))
+    }
+
+    pub(super) async fn start(&self, repo: &str) -> StoreResult<String> {
+        let id = Uuid::new_v4().to_string();
+        let path = self.dir.join(&id);
+        let created = SystemTime::now();
+        let session = serde_json::to_vec(&SessionFile {
+            repo: repo.to_string(),
+            created,
+        })
+        .map_err(io::Error::from)?;
+        fs::create_dir(&path).await?;
+        fs::write(path.join(UPLOAD_DATA), b"").await?;
+        fs::write(path.join(UPLOAD_SESSION), session).await?;
+        let upload = FileUpload {
+            session: UploadSession {
+                id: id.clone(),
+                repo: repo.to_string(),
+                created,
+                updated: created,
+                size: 0,
+            },
+            hasher: Hasher::new(UPLOAD_ALGORITHM).ok(),
+            closed: false,
+        };
+        self.active
+            .write()
+            .await
+            .insert(id.clone(), Arc::new(Mutex::new(upload)));
+        Ok(id)
+    }
+
+    pub(super) async fn session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        let upload = match self.upload(id).await {
+            Err(_) => return Ok(None),
+            Ok(u) => u,
+        };
+        let u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Ok(None);
+        }
+        Ok(Some(u.session.clone()))
+    }
+
+    pub(super) async fn append(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        let upload = self.upload(id).await?;
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Err(StoreError::UploadUnknown(id.to_string()));
+        }
+        let path = self.dir.join(id).join(UPLOAD_DATA);
+        let result = write_upload(&path, Some(start.unwrap_or(0)), &mut u.hasher, chunk).await;
+        u.session.updated = SystemTime::now();
+        u.session.size = result.as_ref().map(|s| *s).or_else(|_| file_size(&path))?;
+        result
+    }
+
+    // Appends a final chunk to an upload and renames it to dest if the
+    // content matches the digest. With purging, a read lock of it is taken
+    // before the rename and returned, so that the caller can link the blob
+    // before it is purged.
+    pub(super) async fn commit<'a>(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+        dest: &Path,
+        purging: Option<&'a RwLock<()>>,
+    ) -> StoreResult<Option<RwLockReadGuard<'a, ()>>> {
+        let upload = self.upload(id).await?;
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Err(StoreError::UploadUnknown(id.to_string()));
+        }
+        let path = self.dir.join(id).join(UPLOAD_DATA);
+        let previous = u.hasher.clone();
+        let size = u.session.size;
+        u.session.updated = SystemTime::now();
+        if let Err(e) = write_upload(&path, None, &mut u.hasher, chunk).await {
+            u.session.size = file_size(&path)?;
+            return Err(e);
+        }
+        let actual = match u.hasher.take() {
+            Some(h) if h.algorithm() == digest.algorithm => h.finalize(),
+            _ => hash_file(&path, &digest.algorithm).await?,
+        };
+        if actual != *digest {
+            // Leave the upload as it was before the final chunk.
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await?
+                .set_len(size)
+                .await?;
+            u.hasher = previous;
+            return Err(StoreError::DigestInvalid(format!(
+                "expected {} but content hashed to {}",
+                digest, actual
+            )));
+        }
+        fs::File::open(&path).await?.sync_all().await?;
+        let linking = match purging {
+            Some(p) => Some(p.read().await),
+            None => None,
+        };
+        rename_synced(&path, dest).await?;
+        u.closed = true;
+        self.active.write().await.remove(id);
+        not_found_as_none(fs::remove_dir_all(self.dir.join(id)).await)?;
+        Ok(linking)
+    }
+
+    pub(super) async fn cancel(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        let upload = match self.upload(id).await {
+            Err(_) => return Ok(false),
+            Ok(u) => u,
+        };
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Ok(false);
+        }
+        u.closed = true;
+        self.active.write().await.remove(id);
+        not_found_as_none(fs::remove_dir_all(self.dir.join(id)).await)?;
+        Ok(true)
+    }
+
+    pub(super) async fn expire(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        let mut expired = vec![];
+        self.active.write().await.retain(|_, upload| {
+            // Uploads that are locked are being written to.
+            let mut u = match upload.try_lock() {
+                Err(_) => return true,
+                Ok(u) => u,
+            };
+            if u.session.updated >= before {
+                return true;
+            }
+            u.closed = true;
+            expired.push(u.session.clone());
+            false
+        });
+        for session in &expired {
+            not_found_as_none(fs::remove_dir_all(self.dir.join(&session.id)).await)?;
+        }
+        Ok(expired)
+    }
+}
+
+// Reads the session of an upload directory left by a previous run.
+fn load_session(path: &Path, id: &str) -> Option<UploadSession> {
+    Uuid::parse_str(id).ok()?;
+    let file: SessionFile =
+        serde_json::from_slice(&std::fs::read(path.join(UPLOAD_SESSION)).ok()?).ok()?;
+    let data = std::fs::metadata(path.join(UPLOAD_DATA)).ok()?;
+    Some(UploadSession {
+        id: id.to_string(),
+        repo: file.repo,
+        created: file.created,
+        updated: data.modified().ok()?,
+        size: data.len(),
+    })
+}
+
+// Writes a file and waits for its content to reach the disk.
+pub(super) async fn write_synced(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut f = fs::File::create(path).await?;
+    f.write_all(content).await?;
+    f.sync_all().await
+}
+
+// Renames a file or directory into place, creating the directory it is moved
+// to, and waits for the rename to reach the disk.
+pub(super) async fn rename_synced(from: &Path, to: &Path) -> io::Result<()> {
+    let parent = match to.parent() {
+        None => return fs::rename(from, to).await,
+        Some(p) => p,
+    };
+    fs::create_dir_all(parent).await?;
+    fs::rename(from, to).await?;
+    sync_dir(parent).await
+}
+
+// Syncs a directory, so entries created in or renamed into it are durable.
+async fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path).await?.sync_all().await
+}
+
+async fn is_dir(path: &Path) -> io::Result<bool> {
+    Ok(not_found_as_none(fs::metadata(path).await)?
+        .map(|m| m.is_dir())
+        .unwrap_or(false))
+}
+
+fn file_size(path: &Path) -> io::Result<u64> {
+    Ok(std::fs::metadata(path)?.len())
+}
+
+// Streams a chunk onto the end of an upload file, returning its new size. If
+// start is given it must be the current size of the file.
+async fn write_upload(
+    path: &Path,
+    start: Option<u64>,
+    hasher: &mut Option<Hasher>,
+    mut chunk: ByteStream,
+) -> StoreResult<u64> {
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut size = f.metadata().await?.len();
+    if start.map(|s| s != size).unwrap_or(false) {
+        return Err(StoreError::RangeInvalid(size));
+    }
+    if size == 0 {
+        *hasher = Hasher::new(UPLOAD_ALGORITHM).ok();
+    }
+    while let Some(bytes) = chunk.next().await {
+        let written = match bytes {
+            Ok(b) => f.write_all(&b).await.map(|_| b),
+            Err(e) => Err(e),
+        };
+        let b = match written {
+            Ok(b) => b,
+            Err(e) => {
+                // The file may now hold part of the chunk.
+                *hasher = None;
+                return Err(e.into());
+            }
+        };
+        if let Some(h) = hasher.as_mut() {
+            h.update(&b);
+        }
+        size += b.len() as u64;
+    }
+    f.flush().await?;
+    Ok(size)
+}
+
+async fn hash_file(path: &Path, algorithm: &str) -> StoreResult<Hash> {
+    let mut hasher =
+        Hasher::new(algorithm).map_err(|e| StoreError::DigestInvalid(e.to_string()))?;
+    let mut content = file_stream(fs::File::open(path).await?);
+    while let Some(b) = content.next().await {
+        hasher.update(&b?);
+    }
+    Ok(hasher.finalize())
+}
+
+// Opens a file, or a range of it, as a blob.
+pub(super) async fn open_blob(path: &Path, range: Option<Range<u64>>) -> io::Result<Option<Blob>> {
+    let mut f = match not_found_as_none(fs::File::open(path).await)? {
+        None => return Ok(None),
+        Some(f) => f,
+    };
+    let size = f.metadata().await?.len();
+    let content = match range {
+        None => file_stream(f),
+        Some(r) => {
+            f.seek(SeekFrom::Start(r.start)).await?;
+            file_stream(f.take(r.end.saturating_sub(r.start)))
+        }
+    };
+    Ok(Some(Blob { size, content }))
+}
+
+// Reads a file in fixed size chunks.
+fn file_stream<R>(f: R) -> ByteStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    Box::pin(stream::try_unfold(f, |mut f| async move {
+        let mut buf = BytesMut::with_capacity(64 * 1024);
+        match f.read_buf(&mut buf).await? {
+            0 => Ok(None),
+            _ => Ok(Some((buf.freeze(), f))),
+        }
+    }))
+}
+
+pub(super) fn not_found_as_none<T>(r: io::Result<T>) -> io::Result<Option<T>> {
+    match r {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Returns the names of entries in a directory, or nothing if it does not
+// exist.
+pub(super) fn read_dir_names(path: &Path) -> io::Result<Vec<String>> {
+    let entries = match not_found_as_none(std::fs::read_dir(path))? {
+        None => return Ok(vec![]),
+        Some(e) => e,
+    };
+    let mut names = vec![];
+    for e in entries {
+        names.push(e?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+// Returns the digests named by the entries of a directory laid out as
+// <algorithm>/<hex>.
+fn read_digest_names(path: &Path) -> io::Result<Vec<Hash>> {
+    let mut digests = vec![];
+    for algorithm in read_dir_names(path)? {
+        for hex in read_dir_names(&path.join(&algorithm))? {
+            digests.push(Hash {
+                algorithm: algorithm.clone(),
+                hex,
+            });
+        }
+    }
+    Ok(digests)
+}
+
+// Returns the digests named by the entries of a directory laid out as
+// <algorithm>/<hex>, along with when each entry was last modified.
+pub(super) fn read_digest_entries(path: &Path) -> io::Result<Vec<(Hash, SystemTime)>> {
+    let mut entries = vec![];
+    for h in read_digest_names(path)? {
+        let modified =
+            match not_found_as_none(std::fs::metadata(path.join(&h.algorithm).join(&h.hex)))? {
+                None => continue,
+                Some(m) => m.modified()?,
+            };
+        entries.push((h, modified));
+    }
+    Ok(entries)
+}
+
+// Returns the blob links or manifests of every repository, which are kept in
+// the given directory of each.
+fn list_stored(root: &Path, dir: &str) -> io::Result<Vec<Stored>> {
+    let mut stored = vec![];
+    for repo in find_repositories(root, &[dir])? {
+        for (h, created) in read_digest_entries(&root.join(&repo).join(dir))? {
+            stored.push(Stored {
+                repo: repo.clone(),
+                digest: h.to_string(),
+                created,
+            });
+        }
+    }
+    Ok(stored)
+}
+
+// Removes blob content that no repository links to and that was written
+// before the given time, returning the digests of the blobs.
+fn purge_blobs(root: &Path, before: SystemTime, dry_run: bool) -> io::Result<Vec<String>> {
+    let linked: HashSet<String> = list_stored(&root.join("repositories"), BLOBS)?
+        .into_iter()
+        .map(|s| s.digest)
+        .collect();
+    let mut purged = vec![];
+    let blobs = root.join("blobs");
+    for algorithm in read_dir_names(&blobs)? {
+        for prefix in read_dir_names(&blobs.join(&algorithm))? {
+            let dir = blobs.join(&algorithm).join(&prefix);
+            for hex in read_dir_names(&dir)? {
+                let digest = format!("{}:{}", algorithm, hex);
+                let path = dir.join(&hex);
+                let modified = match not_found_as_none(std::fs::metadata(&path))? {
+                    None => continue,
+                    Some(m) => m.modified()?,
+                };
+                if linked.contains(&digest) || modified >= before {
+                    continue;
+                }
+                if !dry_run {
+                    not_found_as_none(std::fs::remove_file(&path))?;
+                }
+                purged.push(digest);
+            }
+        }
+    }
+    Ok(purged)
+}
+
+// Walks the repositories directory, returning the name of every directory
+// that holds manifests or tags. Repository names may contain slashes, so
+// repositories can be nested within each other.
+fn list_repositories(root: &Path) -> io::Result<Vec<String>> {
+    find_repositories(root, &[MANIFESTS, TAGS])
+}
+
+// Walks the repositories directory, returning the name of every directory
+// that holds one of the given directories.
+fn find_repositories(root: &Path, dirs: &[&str]) -> io::Result<Vec<String>> {
+    let mut repos = vec![];
+    let mut pending = vec![String::new()];
+    while let Some(name) = pending.pop() {
+        for entry in read_dir_names(&root.join(&name))? {
+            if dirs.contains(&entry.as_str()) {
+                if !repos.contains(&name) {
+                    repos.push(name.clone());
+                }
+            } else if !entry.starts_with('_') {
+                let child = if name.is_empty() {
+                    entry
+                } else {
+                    format!("{}/{}", name, entry)
+                };
+                if root.join(&child).is_dir() {
+                    pending.push(child);
+                }
+            }
+        }
+    }
+    Ok(repos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{chunk, image_manifest, push_blob, read_blob, TempDir};
+    use super::super::{new_filesystem_store, Store};
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_content_across_restarts() {
+        let dir = TempDir::new();
+        let store: Store = new_filesystem_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a/b", b"{}").await;
+        let m = image_manifest(&d, "");
+        store
+            .put_manifest("a/b", "latest", m.clone())
+            .await
+            .unwrap();
+        let id = store.start_upload("a/b").await.unwrap();
+        store
+            .append_upload("a/b", &id, None, chunk(b"hel"))
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = new_filesystem_store(dir.path()).unwrap();
+        assert_eq!(read_blob(&store, "a/b", &d).await.unwrap(), b"{}");
+        let got = store.get_manifest("a/b", "latest").await.unwrap().unwrap();
+        assert_eq!(got.digest, m.digest);
+        assert_eq!(got.content_type, m.content_type);
+        let session = store.upload_session("a/b", &id).await.unwrap().unwrap();
+        assert_eq!(session.size, 3);
+        let digest = Hash::of("sha256", b"hello").unwrap();
+        store
+            .commit_upload("a/b", &id, &digest, chunk(b"lo"))
+            .await
+            .unwrap();
+        let d = digest.to_string();
+        assert_eq!(read_blob(&store, "a/b", &d).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn removes_incomplete_manifests_when_opened() {
+        let dir = TempDir::new();
+        let store: Store = new_filesystem_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a", b"{}").await;
+        let m = image_manifest(&d, "");
+        store.put_manifest("a", "latest", m.clone()).await.unwrap();
+        drop(store);
+        // Leave the manifest as if the registry crashed while writing it.
+        let h: Hash = m.digest.parse().unwrap();
+        let path = dir
+            .path()
+            .join("repositories/a")
+            .join(MANIFESTS)
+            .join(&h.algorithm)
+            .join(&h.hex);
+        std::fs::remove_file(path.join(CONTENT_TYPE)).unwrap();
+        std::fs::write(dir.path().join("tmp/leftover"), b"x").unwrap();
+
+        let store = new_filesystem_store(dir.path()).unwrap();
+        assert!(!path.exists());
+        assert!(store.get_manifest("a", "latest").await.unwrap().is_none());
+        assert_eq!(store.tags("a").await.unwrap(), Some(vec![]));
+        assert!(!dir.path().join("tmp/leftover").exists());
+    }
+
+    #[tokio::test]
+    async fn links_blobs_only_once_they_are_committed() {
+        let dir = TempDir::new();
+        let store: Store = new_filesystem_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a", b"hello").await;
+        // Claiming the digest of another repository's blob does not link it.
+        let id = store.start_upload("b").await.unwrap();
+        let claimed: Hash = d.parse().unwrap();
+        assert!(matches!(
+            store
+                .commit_upload("b", &id, &claimed, chunk(b"other"))
+                .await,
+            Err(StoreError::DigestInvalid(_))
+        ));
+        assert!(read_blob(&store, "b", &d).await.is_none());
+        assert!(!store.mount_blob("c", "b", &d).await.unwrap());
+        assert!(!store
+            .blob_links()
+            .await
+            .unwrap()
+            .iter()
+            .any(|l| l.repo == "b"));
+    }
+
+    #[tokio::test]
+    async fn ignores_and_removes_links_to_missing_blobs() {
+        let dir = TempDir::new();
+        let store: Store = new_filesystem_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a", b"hello").await;
+        let h: Hash = d.parse().unwrap();
+        let blob = dir
+            .path()
+            .join("blobs")
+            .join(&h.algorithm)
+            .join(&h.hex[..2])
+            .join(&h.hex);
+        std::fs::remove_file(blob).unwrap();
+        assert!(read_blob(&store, "a", &d).await.is_none());
+        assert_eq!(store.blob_size("a", &d).await.unwrap(), None);
+        assert!(!store.mount_blob("b", "a", &d).await.unwrap());
+        drop(store);
+
+        let store = new_filesystem_store(dir.path()).unwrap();
+        assert!(store.blob_links().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shares_blob_content_across_repositories() {
+        let dir = TempDir::new();
+        let store: Store = new_filesystem_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a", b"shared").await;
+        push_blob(&store, "b", b"shared").await;
+        store.delete_blob("a", &d).await.unwrap();
+        assert_eq!(read_blob(&store, "b", &d).await.unwrap(), b"shared");
+        let later = SystemTime::now() + std::time::Duration::from_secs(1);
+        assert!(store.purge_blobs(later, false).await.unwrap().is_empty());
+        store.delete_blob("b", &d).await.unwrap();
+        assert_eq!(store.purge_blobs(later, false).await.unwrap(), vec![d]);
+    }
+}
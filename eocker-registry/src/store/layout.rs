@@ -0,0 +1,684 @@
+use async_trait::async_trait;
+use eocker::digest::Hash;
+use eocker::layout::{self, ImageLayout, INDEX_FILE, OCI_LAYOUT_FILE, REF_NAME_ANNOTATION};
+use eocker::types::MediaType;
+use eocker::{Descriptor, IndexManifest};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::filesystem::{
+    not_found_as_none, open_blob, read_digest_entries, read_dir_names, rename_synced, write_synced,
+    FileUploads,
+};
+use super::{
+    is_digest, referrer, valid_repository, valid_tag, Blob, ByteStream, Manifest, Storage,
+    StoreError, StoreResult, Stored, UploadSession,
+};
+
+// LayoutStorage persists every repository as an OCI image layout:
+//
+//   <name>/oci-layout
+//   <name>/index.json
+//   <name>/blobs/<algorithm>/<hex>
+//   .uploads/<uuid>/{data,session}
+//   .tmp/
+//
+// A repository directory can be read by any tool that understands image
+// layouts, such as skopeo with oci:<root>/<name>:<tag>, and copying a layout
+// under the root seeds the registry with its images. Tags are stored as
+// org.opencontainers.image.ref.name annotations in index.json, and manifests
+// that are only referenced by digest are listed without one. Unlike
+// FilesystemStorage, blobs are stored per repository so that each layout is
+// self contained. Repository names may not have a component named like a
+// file of a layout, or a repository could be nested inside another's blobs.
+pub struct LayoutStorage {
+    root: PathBuf,
+    uploads: FileUploads,
+    // Serializes read-modify-write updates of index.json files.
+    index: Mutex<()>,
+}
+
+const UPLOADS: &str = ".uploads";
+const TMP: &str = ".tmp";
+
+// Names that a component of a repository name may not have.
+const RESERVED: [&str; 3] = [layout::BLOBS_DIR, INDEX_FILE, OCI_LAYOUT_FILE];
+
+impl LayoutStorage {
+    // Opens storage rooted at the given directory, creating it if it does not
+    // exist.
+    pub fn open(root: &Path) -> io::Result<LayoutStorage> {
+        let s = LayoutStorage {
+            root: root.to_path_buf(),
+            uploads: FileUploads::open(root.join(UPLOADS))?,
+            index: Mutex::new(()),
+        };
+        // Anything left in .tmp/ was never committed.
+        match std::fs::remove_dir_all(s.root.join(TMP)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        std::fs::create_dir_all(s.root.join(TMP))?;
+        Ok(s)
+    }
+
+    fn repository_path(&self, repo: &str) -> Option<PathBuf> {
+        if !valid_layout_repository(repo) {
+            return None;
+        }
+        Some(self.root.join(repo))
+    }
+
+    fn blob_path(&self, repo: &str, digest: &str) -> Option<PathBuf> {
+        let h = digest.parse::<Hash>().ok()?;
+        Some(layout::blob_path(&self.repository_path(repo)?, &h))
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root.join(TMP).join(Uuid::new_v4().to_string())
+    }
+
+    // Writes content to a temporary file and renames it over path.
+    async fn write_atomic(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let tmp = self.temp_path();
+        write_synced(&tmp, content).await?;
+        rename_synced(&tmp, path).await
+    }
+
+    // Marks a repository directory as an image layout.
+    async fn create_layout(&self, path: &Path) -> io::Result<()> {
+        let file = path.join(OCI_LAYOUT_FILE);
+        if fs::metadata(&file).await.is_ok() {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&ImageLayout::default())?;
+        self.write_atomic(&file, &content).await
+    }
+
+    // Reads the index of a repository. Repositories without an index have
+    // never had a manifest pushed to them.
+    async fn read_index(&self, repo: &str) -> StoreResult<Option<IndexManifest>> {
+        let path = match self.repository_path(repo) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        let content = match not_found_as_none(fs::read(path.join(INDEX_FILE)).await)? {
+            None => return Ok(None),
+            Some(c) => c,
+        };
+        let index = serde_json::from_slice(&content)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Some(index))
+    }
+
+    async fn write_index(&self, path: &Path, index: &IndexManifest) -> StoreResult<()> {
+        let content = serde_json::to_vec(index).map_err(io::Error::from)?;
+        self.write_atomic(&path.join(INDEX_FILE), &content).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LayoutStorage {
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>> {
+        let path = match self.blob_path(repo, digest) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        Ok(open_blob(&path, range).await?)
+    }
+
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>> {
+        let path = match self.blob_path(repo, digest) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        Ok(not_found_as_none(fs::metadata(path).await)?.map(|m| m.len()))
+    }
+
+    // Manifests are blobs of the layout as well, but those listed in the index
+    // can only be removed by deleting the manifest.
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        let path = match self.blob_path(repo, digest) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        let _guard = self.index.lock().await;
+        let listed = self
+            .read_index(repo)
+            .await?
+            .map(|i| i.manifests.iter().any(|d| d.digest.to_string() == digest))
+            .unwrap_or(false);
+        if listed {
+            return Err(StoreError::Denied(format!(
+                "{} is a manifest of {}, delete the manifest instead",
+                digest, repo
+            )));
+        }
+        Ok(not_found_as_none(fs::remove_file(path).await)?.is_some())
+    }
+
+    // Each layout holds its own blobs, so the blob is copied into the
+    // repository. A hard link would share the modification time of the
+    // source, making the mount look older than it is to garbage collection.
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool> {
+        let source = match self.blob_path(from, digest) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        if not_found_as_none(fs::metadata(&source).await)?.is_none() {
+            return Ok(false);
+        }
+        let path = self
+            .repository_path(repo)
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        let blob = self
+            .blob_path(repo, digest)
+            .ok_or_else(|| StoreError::DigestInvalid(digest.to_string()))?;
+        self.create_layout(&path).await?;
+        if fs::metadata(&blob).await.is_ok() {
+            return Ok(true);
+        }
+        // Copy under a temporary name so the blob never appears partially
+        // written.
+        let tmp = self.temp_path();
+        fs::copy(&source, &tmp).await?;
+        fs::File::open(&tmp).await?.sync_all().await?;
+        rename_synced(&tmp, &blob).await?;
+        Ok(true)
+    }
+
+    async fn start_upload(&self, repo: &str) -> StoreResult<String> {
+        if self.repository_path(repo).is_none() {
+            return Err(StoreError::NameInvalid(repo.to_string()));
+        }
+        self.uploads.start(repo).await
+    }
+
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        self.uploads.session(repo, id).await
+    }
+
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        self.uploads.append(repo, id, start, chunk).await
+    }
+
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()> {
+        let path = self
+            .repository_path(repo)
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        let blob = layout::blob_path(&path, digest);
+        self.create_layout(&path).await?;
+        self.uploads
+            .commit(repo, id, digest, chunk, &blob, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        self.uploads.cancel(repo, id).await
+    }
+
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        self.uploads.expire(before).await
+    }
+
+    async fn active_uploads(&self) -> StoreResult<usize> {
+        Ok(self.uploads.active().await)
+    }
+
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        let index = match self.read_index(repo).await? {
+            None => return Ok(None),
+            Some(i) => i,
+        };
+        let listed = index.manifests.iter().find(|d| {
+            if is_digest(reference) {
+                d.digest.to_string() == reference
+            } else {
+                ref_name(d) == Some(reference)
+            }
+        });
+        let (digest, content_type) = match listed {
+            Some(d) => (d.digest.to_string(), Some(d.media_type.to_string())),
+            // Manifests referenced by an image index, such as those of each
+            // platform in a seeded layout, are only present as blobs.
+            None if is_digest(reference) => (reference.to_string(), None),
+            None => return Ok(None),
+        };
+        let path = match self.blob_path(repo, &digest) {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        let content = match not_found_as_none(fs::read(path).await)? {
+            None => return Ok(None),
+            Some(c) => c,
+        };
+        let content_type = match content_type.or_else(|| embedded_media_type(&content)) {
+            None => return Ok(None),
+            Some(t) => t,
+        };
+        Ok(Some(Manifest {
+            digest,
+            content_type,
+            content: content.into(),
+        }))
+    }
+
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()> {
+        let path = self
+            .repository_path(repo)
+            .ok_or_else(|| StoreError::NameInvalid(repo.to_string()))?;
+        let tag = if reference != manifest.digest {
+            if !valid_tag(reference) {
+                return Err(StoreError::NameInvalid(reference.to_string()));
+            }
+            Some(reference)
+        } else {
+            None
+        };
+        let digest = manifest
+            .digest
+            .parse::<Hash>()
+            .map_err(|e| StoreError::DigestInvalid(e.to_string()))?;
+        let media_type = MediaType::from(manifest.content_type.as_str());
+        let descriptor = Descriptor {
+            media_type,
+            size: manifest.content.len() as i64,
+            digest,
+            urls: None,
+            annotations: tag.map(|t| {
+                let mut a = HashMap::new();
+                a.insert(REF_NAME_ANNOTATION.to_string(), t.to_string());
+                a
+            }),
+            platform: None,
+            artifact_type: None,
+        };
+
+        let _guard = self.index.lock().await;
+        self.create_layout(&path).await?;
+        let blob = layout::blob_path(&path, &descriptor.digest);
+        if fs::metadata(&blob).await.is_err() {
+            self.write_atomic(&blob, &manifest.content).await?;
+        }
+        let mut index = self.read_index(repo).await?.unwrap_or(IndexManifest {
+            schema_version: 2,
+            media_type: Some(MediaType::OCIImageIndex),
+            manifests: vec![],
+            annotations: None,
+            artifact_type: None,
+            subject: None,
+        });
+        add_to_index(&mut index.manifests, descriptor);
+        self.write_index(&path, &index).await
+    }
+
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool> {
+        let _guard = self.index.lock().await;
+        let mut index = match self.read_index(repo).await? {
+            None => return Ok(false),
+            Some(i) => i,
+        };
+        let path = self.repository_path(repo).unwrap();
+        if !is_digest(reference) {
+            let i = match index
+                .manifests
+                .iter()
+                .position(|d| ref_name(d) == Some(reference))
+            {
+                None => return Ok(false),
+                Some(i) => i,
+            };
+            let untagged = index.manifests.remove(i);
+            keep_untagged(&mut index.manifests, untagged);
+            self.write_index(&path, &index).await?;
+            return Ok(true);
+        }
+        let listed = index.manifests.len();
+        index
+            .manifests
+            .retain(|d| d.digest.to_string() != reference);
+        if index.manifests.len() == listed {
+            return Ok(false);
+        }
+        self.write_index(&path, &index).await?;
+        // The blob is removed once the index no longer references it.
+        if let Some(blob) = self.blob_path(repo, reference) {
+            not_found_as_none(fs::remove_file(blob).await)?;
+        }
+        Ok(true)
+    }
+
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>> {
+        Ok(self.read_index(repo).await?.map(|i| {
+            i.manifests
+                .iter()
+                .filter_map(ref_name)
+                // Seeded layouts may use reference names that are not valid
+                // tags, which could never be pulled.
+                .filter(|t| valid_tag(t))
+                .map(String::from)
+                .collect()
+        }))
+    }
+
+    async fn repositories(&self) -> StoreResult<Vec<String>> {
+        let root = self.root.clone();
+        Ok(
+            tokio::task::spawn_blocking(move || list_layouts(&root, INDEX_FILE))
+                .await
+                .map_err(io::Error::other)??,
+        )
+    }
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool> {
+        let path = match self.repository_path(repo) {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        Ok(fs::metadata(path.join(INDEX_FILE)).await.is_ok())
+    }
+
+    // Every manifest is listed in index.json, so referrers are found by
+    // reading the manifests of the index rather than recorded separately.
+    async fn add_referrer(&self, _: &str, _: &str, _: Descriptor) -> StoreResult<()> {
+        Ok(())
+    }
+
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>> {
+        let index = match self.read_index(repo).await? {
+            None => return Ok(vec![]),
+            Some(i) => i,
+        };
+        let mut seen = HashSet::new();
+        let mut referrers = vec![];
+        for d in index.manifests {
+            let digest = d.digest.to_string();
+            if !seen.insert(digest.clone()) {
+                continue;
+            }
+            let m = match self.get_manifest(repo, &digest).await? {
+                None => continue,
+                Some(m) => m,
+            };
+            match referrer(&m) {
+                Some((s, descriptor)) if s == subject => referrers.push(descriptor),
+                _ => (),
+            }
+        }
+        Ok(referrers)
+    }
+
+    // Every blob of a layout, including its manifests, is linked to the
+    // repository.
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>> {
+        let root = self.root.clone();
+        Ok(tokio::task::spawn_blocking(move || list_blobs(&root))
+            .await
+            .map_err(io::Error::other)??)
+    }
+
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>> {
+        let mut stored = vec![];
+        for repo in self.repositories().await? {
+            let index = match self.read_index(&repo).await? {
+                None => continue,
+                Some(i) => i,
+            };
+            let mut seen = HashSet::new();
+            for d in index.manifests {
+                let digest = d.digest.to_string();
+                if !seen.insert(digest.clone()) {
+                    continue;
+                }
+                let path = layout::blob_path(&self.root.join(&repo), &d.digest);
+                let created = match not_found_as_none(fs::metadata(path).await)? {
+                    None => continue,
+                    Some(m) => m.modified()?,
+                };
+                stored.push(Stored {
+                    repo: repo.clone(),
+                    digest,
+                    created,
+                });
+            }
+        }
+        Ok(stored)
+    }
+
+    // Blobs are stored within each layout, so deleting the link of a blob
+    // already removes its content.
+    async fn purge_blobs(&self, _: SystemTime, _: bool) -> StoreResult<Vec<String>> {
+        Ok(vec![])
+    }
+}
+
+// Reports whether a repository can be stored as a layout without its
+// directory overlapping the files of another layout.
+fn valid_layout_repository(repo: &str) -> bool {
+    valid_repository(repo) && !repo.split('/').any(|c| RESERVED.contains(&c))
+}
+
+fn ref_name(d: &Descriptor) -> Option<&str> {
+    d.annotations
+        .as_ref()?
+        .get(REF_NAME_ANNOTATION)
+        .map(String::as_str)
+}
+
+// Adds a manifest to an index. A tagged manifest replaces the entry that held
+// the tag before and any untagged entry for the same digest.
+fn add_to_index(manifests: &mut Vec<Descriptor>, descriptor: Descriptor) {
+    let tag = ref_name(&descriptor).map(String::from);
+    if let Some(tag) = tag {
+        if let Some(i) = manifests
+            .iter()
+            .position(|d| ref_name(d) == Some(tag.as_str()))
+        {
+            let previous = manifests.remove(i);
+            keep_untagged(manifests, previous);
+        }
+        manifests.retain(|d| d.digest != descriptor.digest || ref_name(d).is_some());
+        manifests.push(descriptor);
+    } else if !manifests.iter().any(|d| d.digest == descriptor.digest) {
+        manifests.push(descriptor);
+    }
+}
+
+// Lists a manifest without its tag if no other entry references it, so that
+// it can still be pulled by digest once its last tag is moved or deleted.
+fn keep_untagged(manifests: &mut Vec<Descriptor>, mut descriptor: Descriptor) {
+    if manifests.iter().any(|d| d.digest == descriptor.digest) {
+        return;
+    }
+    if let Some(a) = descriptor.annotations.as_mut() {
+        a.remove(REF_NAME_ANNOTATION);
+        if a.is_empty() {
+            descriptor.annotations = None;
+        }
+    }
+    manifests.push(descriptor);
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Embedded {
+    media_type: Option<String>,
+}
+
+// Returns the media type a manifest declares in its content, if any.
+fn embedded_media_type(content: &[u8]) -> Option<String> {
+    let t = serde_json::from_slice::<Embedded>(content)
+        .ok()?
+        .media_type?;
+    match MediaType::from(t.as_str()) {
+        MediaType::OCIImageIndex
+        | MediaType::OCIManifestSchema1
+        | MediaType::DockerManifestSchema2
+        | MediaType::DockerManifestList => Some(t),
+        _ => None,
+    }
+}
+
+// Returns the blobs of every layout, including those that have blobs but no
+// manifests yet.
+fn list_blobs(root: &Path) -> io::Result<Vec<Stored>> {
+    let mut stored = vec![];
+    for repo in list_layouts(root, OCI_LAYOUT_FILE)? {
+        for (h, created) in read_digest_entries(&root.join(&repo).join(layout::BLOBS_DIR))? {
+            stored.push(Stored {
+                repo: repo.clone(),
+                digest: h.to_string(),
+                created,
+            });
+        }
+    }
+    Ok(stored)
+}
+
+// Walks the root, returning the name of every directory that holds the given
+// layout file. Directories starting with a dot hold uploads and temporary
+// files and are never valid repository names.
+fn list_layouts(root: &Path, file: &str) -> io::Result<Vec<String>> {
+    let mut repos = vec![];
+    let mut pending = vec![String::new()];
+    while let Some(name) = pending.pop() {
+        for entry in read_dir_names(&root.join(&name))? {
+            if entry == file {
+                if valid_layout_repository(&name) {
+                    repos.push(name.clone());
+                }
+                continue;
+            }
+            if entry.starts_with('.') {
+                continue;
+            }
+            let child = if name.is_empty() {
+                entry
+            } else {
+                format!("{}/{}", name, entry)
+            };
+            if root.join(&child).is_dir() {
+                pending.push(child);
+            }
+        }
+    }
+    Ok(repos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{image_manifest, push_blob, read_blob, TempDir};
+    use super::super::{new_layout_store, Store};
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_repositories_as_image_layouts() {
+        let dir = TempDir::new();
+        let store: Store = new_layout_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a/b", b"{}").await;
+        let m = image_manifest(&d, "");
+        store
+            .put_manifest("a/b", "latest", m.clone())
+            .await
+            .unwrap();
+
+        let repo = dir.path().join("a/b");
+        assert!(repo.join(OCI_LAYOUT_FILE).is_file());
+        let index: IndexManifest =
+            serde_json::from_slice(&std::fs::read(repo.join(INDEX_FILE)).unwrap()).unwrap();
+        assert_eq!(index.manifests.len(), 1);
+        assert_eq!(index.manifests[0].digest.to_string(), m.digest);
+        let annotations = index.manifests[0].annotations.as_ref().unwrap();
+        assert_eq!(annotations[REF_NAME_ANNOTATION], "latest");
+        let h: Hash = d.parse().unwrap();
+        let blob = repo.join(layout::BLOBS_DIR).join(h.algorithm).join(h.hex);
+        assert_eq!(std::fs::read(blob).unwrap(), b"{}");
+
+        // Layouts copied under the root are served.
+        let copy = dir.path().join("c");
+        std::fs::create_dir_all(copy.join(layout::BLOBS_DIR)).unwrap();
+        for file in &[OCI_LAYOUT_FILE, INDEX_FILE] {
+            std::fs::copy(repo.join(file), copy.join(file)).unwrap();
+        }
+        copy_dir(&repo.join(layout::BLOBS_DIR), &copy.join(layout::BLOBS_DIR));
+        let store = new_layout_store(dir.path()).unwrap();
+        let got = store.get_manifest("c", "latest").await.unwrap().unwrap();
+        assert_eq!(got.content, m.content);
+        assert_eq!(read_blob(&store, "c", &d).await.unwrap(), b"{}");
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_repositories_named_like_layout_files() {
+        let dir = TempDir::new();
+        let store: Store = new_layout_store(dir.path()).unwrap();
+        for repo in &["a/blobs", "blobs/a", "a/index.json", "a/oci-layout"] {
+            assert!(
+                matches!(
+                    store.start_upload(repo).await,
+                    Err(StoreError::NameInvalid(_))
+                ),
+                "{}",
+                repo
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_blobs_listed_as_manifests() {
+        let dir = TempDir::new();
+        let store: Store = new_layout_store(dir.path()).unwrap();
+        let d = push_blob(&store, "a", b"{}").await;
+        let m = image_manifest(&d, "");
+        store.put_manifest("a", "latest", m.clone()).await.unwrap();
+        assert!(matches!(
+            store.delete_blob("a", &m.digest).await,
+            Err(StoreError::Denied(_))
+        ));
+        assert!(store.delete_manifest("a", &m.digest).await.unwrap());
+        assert!(store.delete_blob("a", &d).await.unwrap());
+    }
+}
@@ -0,0 +1,574 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use eocker::digest::{Hash, Hasher};
+use eocker::Descriptor;
+use futures::{stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{Mutex, RwLock};
+use uuid::Uuid;
+
+use super::{
+    is_digest, Blob, ByteStream, Manifest, Storage, StoreError, StoreResult, Stored, UploadSession,
+};
+
+// MemoryStorage keeps all content in memory. Everything is lost when the
+// registry exits.
+//
+// Pulls only take read locks, so they proceed in parallel. Write locks are
+// held just long enough to update a map, never while content is received or
+// sent: uploads are written under their own lock, and each repository has its
+// own lock so that pushing manifests to one does not hold up the others. Locks
+// are taken in the order links, blobs.
+#[derive(Default)]
+pub struct MemoryStorage {
+    // Content of each blob, along with when it was stored.
+    blobs: RwLock<HashMap<String, (Arc<Chunks>, SystemTime)>>,
+    // Digests of the blobs that have been pushed to each repository, along
+    // with when they were linked.
+    links: RwLock<HashMap<String, HashMap<String, SystemTime>>>,
+    uploads: RwLock<HashMap<String, Arc<Mutex<Upload>>>>,
+    manifests: RwLock<HashMap<String, Arc<RwLock<Repository>>>>,
+}
+
+// Content is kept as the chunks it was received in so that appending never
+// copies what was received before.
+#[derive(Debug, Default)]
+struct Chunks {
+    size: u64,
+    chunks: Vec<Bytes>,
+}
+
+struct Upload {
+    session: UploadSession,
+    content: Chunks,
+    // Running hash of the content with the canonical algorithm.
+    hasher: Hasher,
+    // Set once the upload is committed or cancelled, for requests that were
+    // waiting on it.
+    closed: bool,
+}
+
+impl Upload {
+    // Reports whether the upload can still be used from the repository.
+    fn is_open(&self, repo: &str) -> bool {
+        !self.closed && self.session.repo == repo
+    }
+
+    async fn append(&mut self, mut chunk: ByteStream) -> StoreResult<()> {
+        self.session.updated = SystemTime::now();
+        while let Some(b) = chunk.next().await {
+            let b = b?;
+            self.hasher.update(&b);
+            self.content.size += b.len() as u64;
+            self.content.chunks.push(b);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Repository {
+    // Tags map to the digest of the manifest they reference.
+    tags: HashMap<String, String>,
+    // Manifests are addressed by digest.
+    manifests: HashMap<String, Manifest>,
+    // When each manifest was stored.
+    created: HashMap<String, SystemTime>,
+    // Descriptors of the manifests that refer to each subject.
+    referrers: HashMap<String, Vec<Descriptor>>,
+}
+
+impl MemoryStorage {
+    // Returns an upload. Holding its lock serializes writes to the upload.
+    async fn upload(&self, id: &str) -> StoreResult<Arc<Mutex<Upload>>> {
+        self.uploads
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| StoreError::UploadUnknown(id.to_string()))
+    }
+}
+
+impl MemoryStorage {
+    // Returns a blob if it has been pushed to the repository.
+    async fn linked_blob(&self, repo: &str, digest: &str) -> Option<Arc<Chunks>> {
+        let links = self.links.read().await;
+        if !links.get(repo)?.contains_key(digest) {
+            return None;
+        }
+        self.blobs.read().await.get(digest).map(|(b, _)| b.clone())
+    }
+
+    async fn repository(&self, repo: &str) -> Option<Arc<RwLock<Repository>>> {
+        self.manifests.read().await.get(repo).cloned()
+    }
+
+    // Returns a repository, creating it if nothing has been pushed to it yet.
+    async fn repository_or_default(&self, repo: &str) -> Arc<RwLock<Repository>> {
+        if let Some(r) = self.repository(repo).await {
+            return r;
+        }
+        self.manifests
+            .write()
+            .await
+            .entry(repo.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+impl Repository {
+    // Returns the manifest referenced by a tag or digest.
+    fn get(&self, reference: &str) -> Option<&Manifest> {
+        match self.tags.get(reference) {
+            Some(d) => self.manifests.get(d),
+            None => self.manifests.get(reference),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>> {
+        // Only the reference to the chunks is cloned while the store is
+        // locked.
+        let blob = match self.linked_blob(repo, digest).await {
+            None => return Ok(None),
+            Some(b) => b,
+        };
+        let range = range.unwrap_or(0..blob.size);
+        let size = blob.size;
+        let mut offset = 0;
+        let chunks = (0..blob.chunks.len()).filter_map(move |i| {
+            let b = &blob.chunks[i];
+            let (start, end) = (offset, offset + b.len() as u64);
+            offset = end;
+            // Slicing shares the chunk rather than copying it.
+            let from = range.start.clamp(start, end) - start;
+            let to = range.end.clamp(start, end) - start;
+            if from >= to {
+                return None;
+            }
+            Some(Ok(b.slice(from as usize..to as usize)))
+        });
+        Ok(Some(Blob {
+            size,
+            content: Box::pin(stream::iter(chunks)),
+        }))
+    }
+
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>> {
+        Ok(self.linked_blob(repo, digest).await.map(|b| b.size))
+    }
+
+    // Only the link is removed, other repositories may still hold the blob.
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        Ok(self
+            .links
+            .write()
+            .await
+            .get_mut(repo)
+            .map(|l| l.remove(digest).is_some())
+            .unwrap_or(false))
+    }
+
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool> {
+        let mut links = self.links.write().await;
+        if !links
+            .get(from)
+            .map(|l| l.contains_key(digest))
+            .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+        links
+            .entry(repo.to_string())
+            .or_default()
+            .insert(digest.to_string(), SystemTime::now());
+        Ok(true)
+    }
+
+    async fn start_upload(&self, repo: &str) -> StoreResult<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = SystemTime::now();
+        let upload = Upload {
+            session: UploadSession {
+                id: id.clone(),
+                repo: repo.to_string(),
+                created: now,
+                updated: now,
+                size: 0,
+            },
+            content: Chunks::default(),
+            // sha256 is always supported.
+            hasher: Hasher::new("sha256").unwrap(),
+            closed: false,
+        };
+        self.uploads
+            .write()
+            .await
+            .insert(id.clone(), Arc::new(Mutex::new(upload)));
+        Ok(id)
+    }
+
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        let upload = match self.upload(id).await {
+            Err(_) => return Ok(None),
+            Ok(u) => u,
+        };
+        let u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Ok(None);
+        }
+        Ok(Some(UploadSession {
+            size: u.content.size,
+            ..u.session.clone()
+        }))
+    }
+
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        let upload = self.upload(id).await?;
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Err(StoreError::UploadUnknown(id.to_string()));
+        }
+        if start.unwrap_or(0) != u.content.size {
+            return Err(StoreError::RangeInvalid(u.content.size));
+        }
+        u.append(chunk).await?;
+        Ok(u.content.size)
+    }
+
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()> {
+        let upload = self.upload(id).await?;
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Err(StoreError::UploadUnknown(id.to_string()));
+        }
+        let (size, chunks, hasher) = (u.content.size, u.content.chunks.len(), u.hasher.clone());
+        u.append(chunk).await?;
+        let actual = if u.hasher.algorithm() == digest.algorithm {
+            u.hasher.clone().finalize()
+        } else {
+            let mut h = Hasher::new(&digest.algorithm)
+                .map_err(|e| StoreError::DigestInvalid(e.to_string()))?;
+            u.content.chunks.iter().for_each(|b| h.update(b));
+            h.finalize()
+        };
+        if actual != *digest {
+            // Leave the upload as it was before the final chunk.
+            u.content.size = size;
+            u.content.chunks.truncate(chunks);
+            u.hasher = hasher;
+            return Err(StoreError::DigestInvalid(format!(
+                "expected {} but content hashed to {}",
+                digest, actual
+            )));
+        }
+        let content = std::mem::take(&mut u.content);
+        let now = SystemTime::now();
+        let mut links = self.links.write().await;
+        self.blobs
+            .write()
+            .await
+            .insert(digest.to_string(), (Arc::new(content), now));
+        links
+            .entry(repo.to_string())
+            .or_default()
+            .insert(digest.to_string(), now);
+        // Blob has been committed, chunks can be removed from upload store
+        u.closed = true;
+        self.uploads.write().await.remove(id);
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        let upload = match self.upload(id).await {
+            Err(_) => return Ok(false),
+            Ok(u) => u,
+        };
+        let mut u = upload.lock().await;
+        if !u.is_open(repo) {
+            return Ok(false);
+        }
+        u.closed = true;
+        self.uploads.write().await.remove(id);
+        Ok(true)
+    }
+
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        let mut expired = vec![];
+        self.uploads.write().await.retain(|_, upload| {
+            // Uploads that are locked are being written to.
+            let mut u = match upload.try_lock() {
+                Err(_) => return true,
+                Ok(u) => u,
+            };
+            if u.session.updated >= before {
+                return true;
+            }
+            u.closed = true;
+            expired.push(UploadSession {
+                size: u.content.size,
+                ..u.session.clone()
+            });
+            false
+        });
+        Ok(expired)
+    }
+
+    async fn active_uploads(&self) -> StoreResult<usize> {
+        Ok(self.uploads.read().await.len())
+    }
+
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        Ok(match self.repository(repo).await {
+            None => None,
+            Some(r) => r.read().await.get(reference).cloned(),
+        })
+    }
+
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()> {
+        let r = self.repository_or_default(repo).await;
+        let mut r = r.write().await;
+        if reference != manifest.digest {
+            r.tags
+                .insert(reference.to_string(), manifest.digest.clone());
+        }
+        r.created.insert(manifest.digest.clone(), SystemTime::now());
+        r.manifests.insert(manifest.digest.clone(), manifest);
+        Ok(())
+    }
+
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool> {
+        let r = match self.repository(repo).await {
+            None => return Ok(false),
+            Some(r) => r,
+        };
+        let mut r = r.write().await;
+        if !is_digest(reference) {
+            return Ok(r.tags.remove(reference).is_some());
+        }
+        if r.manifests.remove(reference).is_none() {
+            return Ok(false);
+        }
+        r.created.remove(reference);
+        r.tags.retain(|_, d| d != reference);
+        for referrers in r.referrers.values_mut() {
+            referrers.retain(|d| d.digest.to_string() != reference);
+        }
+        Ok(true)
+    }
+
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>> {
+        Ok(match self.repository(repo).await {
+            None => None,
+            Some(r) => Some(r.read().await.tags.keys().cloned().collect()),
+        })
+    }
+
+    async fn repositories(&self) -> StoreResult<Vec<String>> {
+        Ok(self.manifests.read().await.keys().cloned().collect())
+    }
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool> {
+        Ok(self.manifests.read().await.contains_key(repo))
+    }
+
+    async fn add_referrer(
+        &self,
+        repo: &str,
+        subject: &str,
+        referrer: Descriptor,
+    ) -> StoreResult<()> {
+        let r = self.repository_or_default(repo).await;
+        let mut r = r.write().await;
+        let referrers = r.referrers.entry(subject.to_string()).or_default();
+        referrers.retain(|d| d.digest != referrer.digest);
+        referrers.push(referrer);
+        Ok(())
+    }
+
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>> {
+        let r = match self.repository(repo).await {
+            None => return Ok(vec![]),
+            Some(r) => r,
+        };
+        let r = r.read().await;
+        Ok(r.referrers
+            .get(subject)
+            .map(|referrers| {
+                referrers
+                    .iter()
+                    .filter(|d| r.manifests.contains_key(&d.digest.to_string()))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>> {
+        let links = self.links.read().await;
+        Ok(links
+            .iter()
+            .flat_map(|(repo, l)| {
+                l.iter().map(move |(digest, created)| Stored {
+                    repo: repo.clone(),
+                    digest: digest.clone(),
+                    created: *created,
+                })
+            })
+            .collect())
+    }
+
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>> {
+        let repos: Vec<(String, Arc<RwLock<Repository>>)> = self
+            .manifests
+            .read()
+            .await
+            .iter()
+            .map(|(name, r)| (name.clone(), r.clone()))
+            .collect();
+        let mut stored = vec![];
+        for (repo, r) in repos {
+            let r = r.read().await;
+            stored.extend(r.created.iter().map(|(digest, created)| Stored {
+                repo: repo.clone(),
+                digest: digest.clone(),
+                created: *created,
+            }));
+        }
+        Ok(stored)
+    }
+
+    async fn purge_blobs(&self, before: SystemTime, dry_run: bool) -> StoreResult<Vec<String>> {
+        let links = self.links.read().await;
+        let mut blobs = self.blobs.write().await;
+        let linked: HashSet<&String> = links.values().flat_map(|l| l.keys()).collect();
+        let unlinked: Vec<String> = blobs
+            .iter()
+            .filter(|(d, (_, stored))| !linked.contains(d) && *stored < before)
+            .map(|(d, _)| d.clone())
+            .collect();
+        if !dry_run {
+            for d in &unlinked {
+                blobs.remove(d);
+            }
+        }
+        Ok(unlinked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{push_blob, read_blob};
+    use super::super::{new_memory_store, Store};
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // Returns how long pulls take while an upload is slowly written. With a
+    // lock, every operation holds it, as if the store had a single lock.
+    async fn pulls_during_upload(lock: Option<Arc<Mutex<()>>>) -> Duration {
+        let store = new_memory_store();
+        let d = push_blob(&store, "a", b"hello").await;
+        let id = store.start_upload("a").await.unwrap();
+        let written: ByteStream = Box::pin(stream::unfold(0, |i| async move {
+            if i == 10 {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Some((Ok(Bytes::from_static(b"x")), i + 1))
+        }));
+        let upload = tokio::spawn({
+            let (store, lock) = (store.clone(), lock.clone());
+            async move {
+                let _locked = match &lock {
+                    Some(l) => Some(l.lock().await),
+                    None => None,
+                };
+                store.append_upload("a", &id, None, written).await
+            }
+        });
+        // Let the upload start.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let started = Instant::now();
+        let pulls: Vec<_> = (0..8)
+            .map(|_| {
+                let (store, lock, d) = (store.clone(), lock.clone(), d.clone());
+                tokio::spawn(async move {
+                    let _locked = match &lock {
+                        Some(l) => Some(l.lock().await),
+                        None => None,
+                    };
+                    read_blob(&store, "a", &d).await.unwrap()
+                })
+            })
+            .collect();
+        for p in pulls {
+            assert_eq!(p.await.unwrap(), b"hello");
+        }
+        let elapsed = started.elapsed();
+        assert_eq!(upload.await.unwrap().unwrap(), 10);
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn serves_pulls_while_uploads_are_written() {
+        let single_lock = pulls_during_upload(Some(Arc::new(Mutex::new(())))).await;
+        let pulls = pulls_during_upload(None).await;
+        // With a single lock, pulls wait for the 200ms the upload takes.
+        assert!(
+            single_lock >= Duration::from_millis(150),
+            "{:?}",
+            single_lock
+        );
+        assert!(pulls * 4 < single_lock, "{:?} vs {:?}", pulls, single_lock);
+    }
+
+    #[tokio::test]
+    async fn purges_unlinked_blobs_stored_before_the_cutoff() {
+        let store: Store = new_memory_store();
+        let kept = push_blob(&store, "a", b"kept").await;
+        let old = push_blob(&store, "a", b"old").await;
+        store.delete_blob("a", &old).await.unwrap();
+        let cutoff = SystemTime::now();
+        let new = push_blob(&store, "a", b"new").await;
+        store.delete_blob("a", &new).await.unwrap();
+
+        assert_eq!(
+            store.purge_blobs(cutoff, true).await.unwrap(),
+            vec![old.clone()]
+        );
+        assert_eq!(store.purge_blobs(cutoff, false).await.unwrap(), vec![old]);
+        assert!(store.purge_blobs(cutoff, false).await.unwrap().is_empty());
+        let later = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(store.purge_blobs(later, false).await.unwrap(), vec![new]);
+        assert_eq!(read_blob(&store, "a", &kept).await.unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,481 @@
+use async_trait::async_trait;
+use eocker::digest::Hash;
+use eocker::Descriptor;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+use super::{
+    is_digest, referrer, Blob, ByteStream, Manifest, Storage, Store, StoreError, StoreResult,
+    Stored, UploadSession,
+};
+use crate::upstream::Upstream;
+
+// ProxyStorage mirrors an upstream registry. Manifests and blobs missing from
+// the local storage are fetched from the upstream and stored before they are
+// served. Tags are revalidated against the upstream once they were last
+// checked longer than the tag TTL ago, and the local copy keeps being served
+// if the upstream cannot be reached. Listings only include what has been
+// fetched or pushed to the mirror.
+pub struct ProxyStorage {
+    local: Store,
+    upstream: Upstream,
+    tag_ttl: Duration,
+    // When tags were last fetched or revalidated, keyed by repository and
+    // tag.
+    checked: Mutex<HashMap<(String, String), Instant>>,
+    // Locks held while content is fetched, so that concurrent misses only
+    // fetch it once.
+    fetching: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ProxyStorage {
+    pub fn new(local: Store, upstream: Upstream, tag_ttl: Duration) -> ProxyStorage {
+        ProxyStorage {
+            local,
+            upstream,
+            tag_ttl,
+            checked: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs a fetch after any other fetch of the same content has finished.
+    async fn exclusive<T>(&self, key: String, fetch: impl Future<Output = T>) -> T {
+        let lock = self
+            .fetching
+            .lock()
+            .await
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _fetching = lock.lock().await;
+            fetch.await
+        };
+        // Requests waiting on the fetch hold the lock as well, and must find
+        // it in place for later requests to wait on it too. It is only removed
+        // once no request other than this one holds it.
+        let mut fetching = self.fetching.lock().await;
+        if Arc::strong_count(&lock) == 2 {
+            fetching.remove(&key);
+        }
+        result
+    }
+
+    async fn fresh(&self, repo: &str, tag: &str) -> bool {
+        let checked = self.checked.lock().await;
+        match checked.get(&(repo.to_string(), tag.to_string())) {
+            Some(t) => t.elapsed() < self.tag_ttl,
+            None => false,
+        }
+    }
+
+    // Records that a tag was checked, forgetting tags that have gone stale
+    // since, so that tags are only remembered for as long as they are fresh.
+    async fn mark_checked(&self, repo: &str, tag: &str) {
+        let mut checked = self.checked.lock().await;
+        checked.retain(|_, t| t.elapsed() < self.tag_ttl);
+        checked.insert((repo.to_string(), tag.to_string()), Instant::now());
+    }
+
+    // Returns the local copy of a manifest if it does not have to be fetched,
+    // which is the case for manifests referenced by digest and for tags that
+    // are still fresh.
+    async fn cached_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        let m = self.local.get_manifest(repo, reference).await?;
+        if is_digest(reference) || self.fresh(repo, reference).await {
+            return Ok(m);
+        }
+        Ok(None)
+    }
+
+    async fn fetch_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        let local = self.local.get_manifest(repo, reference).await?;
+        // A tag that still references the same manifest is only revalidated.
+        if let Some(l) = &local {
+            match self.upstream.manifest_digest(repo, reference).await {
+                Ok(Some(d)) if d == l.digest => {
+                    self.mark_checked(repo, reference).await;
+                    return Ok(local);
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    log::warn!("could not revalidate {}:{}: {}", repo, reference, e);
+                    return Ok(local);
+                }
+            }
+        }
+        let m = match self.upstream.manifest(repo, reference).await {
+            Ok(Some(m)) => m,
+            // Tags removed from the upstream are still served from the mirror.
+            Ok(None) => {
+                if local.is_some() {
+                    self.mark_checked(repo, reference).await;
+                }
+                return Ok(local);
+            }
+            Err(e) if local.is_some() => {
+                log::warn!("could not revalidate {}:{}: {}", repo, reference, e);
+                return Ok(local);
+            }
+            Err(e) => return Err(StoreError::Io(e)),
+        };
+        let subject = referrer(&m);
+        self.local.put_manifest(repo, reference, m.clone()).await?;
+        if let Some((s, d)) = subject {
+            self.local.add_referrer(repo, &s, d).await?;
+        }
+        if !is_digest(reference) {
+            self.mark_checked(repo, reference).await;
+        }
+        Ok(Some(m))
+    }
+
+    // Fetches a blob into the local storage unless it is already stored there.
+    // Returns false if neither has it.
+    async fn fetch_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        if self.local.blob_size(repo, digest).await?.is_some() {
+            return Ok(true);
+        }
+        let key = format!("{}/blobs/{}", repo, digest);
+        self.exclusive(key, async {
+            // Another request may have fetched the blob in the meantime.
+            if self.local.blob_size(repo, digest).await?.is_some() {
+                return Ok(true);
+            }
+            let hash = digest
+                .parse::<Hash>()
+                .map_err(|e| StoreError::DigestInvalid(e.to_string()))?;
+            let content = match self.upstream.blob(repo, digest).await {
+                Ok(Some(c)) => c,
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(StoreError::Io(e)),
+            };
+            // The content is verified against the digest as it is committed.
+            let id = self.local.start_upload(repo).await?;
+            if let Err(e) = self.local.commit_upload(repo, &id, &hash, content).await {
+                let _ = self.local.cancel_upload(repo, &id).await;
+                return Err(e);
+            }
+            Ok(true)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Storage for ProxyStorage {
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>> {
+        if !self.fetch_blob(repo, digest).await? {
+            return Ok(None);
+        }
+        self.local.get_blob(repo, digest, range).await
+    }
+
+    // Blobs are only fetched once they are read, so the size of a blob that
+    // is not stored locally is that reported by the upstream.
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>> {
+        if let Some(size) = self.local.blob_size(repo, digest).await? {
+            return Ok(Some(size));
+        }
+        self.upstream
+            .blob_size(repo, digest)
+            .await
+            .map_err(StoreError::Io)
+    }
+
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        self.local.delete_blob(repo, digest).await
+    }
+
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool> {
+        self.local.mount_blob(repo, from, digest).await
+    }
+
+    async fn start_upload(&self, repo: &str) -> StoreResult<String> {
+        self.local.start_upload(repo).await
+    }
+
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        self.local.upload_session(repo, id).await
+    }
+
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        self.local.append_upload(repo, id, start, chunk).await
+    }
+
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()> {
+        self.local.commit_upload(repo, id, digest, chunk).await
+    }
+
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        self.local.cancel_upload(repo, id).await
+    }
+
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        self.local.expire_uploads(before).await
+    }
+
+    async fn active_uploads(&self) -> StoreResult<usize> {
+        self.local.active_uploads().await
+    }
+
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        if let Some(m) = self.cached_manifest(repo, reference).await? {
+            return Ok(Some(m));
+        }
+        let key = format!("{}/manifests/{}", repo, reference);
+        self.exclusive(key, async {
+            // Another request may have fetched the manifest in the meantime.
+            if let Some(m) = self.cached_manifest(repo, reference).await? {
+                return Ok(Some(m));
+            }
+            self.fetch_manifest(repo, reference).await
+        })
+        .await
+    }
+
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()> {
+        self.local.put_manifest(repo, reference, manifest).await
+    }
+
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool> {
+        self.local.delete_manifest(repo, reference).await
+    }
+
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>> {
+        self.local.tags(repo).await
+    }
+
+    async fn repositories(&self) -> StoreResult<Vec<String>> {
+        self.local.repositories().await
+    }
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool> {
+        self.local.repository_exists(repo).await
+    }
+
+    async fn add_referrer(
+        &self,
+        repo: &str,
+        subject: &str,
+        referrer: Descriptor,
+    ) -> StoreResult<()> {
+        self.local.add_referrer(repo, subject, referrer).await
+    }
+
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>> {
+        self.local.referrers(repo, subject).await
+    }
+
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>> {
+        self.local.blob_links().await
+    }
+
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>> {
+        self.local.stored_manifests().await
+    }
+
+    async fn purge_blobs(&self, before: SystemTime, dry_run: bool) -> StoreResult<Vec<String>> {
+        self.local.purge_blobs(before, dry_run).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, UpstreamConfig};
+    use crate::upstream::MAX_MANIFEST_SIZE;
+    use crate::{filters, store};
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const REPO: &str = "library/app";
+    const MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    // Serves a registry backed by memory storage in process, returning its
+    // storage and URL.
+    async fn serve_upstream() -> (Store, String) {
+        let (local, routes) = filters::test_registry(Config::default()).await;
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (local, format!("http://{}", addr))
+    }
+
+    fn proxy(url: &str, tag_ttl: Duration) -> ProxyStorage {
+        let upstream = Upstream::new(&UpstreamConfig {
+            url: url.to_string(),
+            username: None,
+            password: None,
+            ca: None,
+            tag_ttl,
+        })
+        .unwrap();
+        ProxyStorage::new(store::new_memory_store(), upstream, tag_ttl)
+    }
+
+    async fn push_blob(store: &Store, content: &'static [u8]) -> String {
+        let digest = Hash::of("sha256", content).unwrap();
+        let id = store.start_upload(REPO).await.unwrap();
+        let chunk: ByteStream =
+            Box::pin(stream::once(async move { Ok(Bytes::from_static(content)) }));
+        store
+            .commit_upload(REPO, &id, &digest, chunk)
+            .await
+            .unwrap();
+        digest.to_string()
+    }
+
+    async fn push_manifest(store: &Store, tag: &str, content: String) -> Manifest {
+        let m = Manifest {
+            digest: Hash::of("sha256", content.as_bytes()).unwrap().to_string(),
+            content_type: MANIFEST_TYPE.to_string(),
+            content: content.into(),
+        };
+        store.put_manifest(REPO, tag, m.clone()).await.unwrap();
+        m
+    }
+
+    fn image(config: &str, created: &str) -> String {
+        format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"{}"}},"layers":[],"annotations":{{"created":"{}"}}}}"#,
+            MANIFEST_TYPE, config, created
+        )
+    }
+
+    async fn read(blob: Blob) -> Vec<u8> {
+        let mut content = vec![];
+        let mut s = blob.content;
+        while let Some(b) = s.next().await {
+            content.extend_from_slice(&b.unwrap());
+        }
+        content
+    }
+
+    #[tokio::test]
+    async fn mirrors_manifests_and_blobs() {
+        let (upstream, url) = serve_upstream().await;
+        let config = push_blob(&upstream, b"{}").await;
+        let m = push_manifest(&upstream, "latest", image(&config, "1")).await;
+        let p = proxy(&url, Duration::from_secs(300));
+
+        let fetched = p.get_manifest(REPO, "latest").await.unwrap().unwrap();
+        assert_eq!(fetched.digest, m.digest);
+        assert_eq!(fetched.content, m.content);
+        assert!(p
+            .local
+            .get_manifest(REPO, "latest")
+            .await
+            .unwrap()
+            .is_some());
+
+        // The size of a blob is that reported by the upstream until it is
+        // read.
+        assert_eq!(p.blob_size(REPO, &config).await.unwrap(), Some(2));
+        assert!(p.local.blob_size(REPO, &config).await.unwrap().is_none());
+        let blob = p.get_blob(REPO, &config, None).await.unwrap().unwrap();
+        assert_eq!(read(blob).await, b"{}");
+        assert_eq!(p.local.blob_size(REPO, &config).await.unwrap(), Some(2));
+
+        let missing = Hash::of("sha256", b"missing").unwrap().to_string();
+        assert!(p.get_manifest(REPO, "missing").await.unwrap().is_none());
+        assert!(p.get_blob(REPO, &missing, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_tags() {
+        let (upstream, url) = serve_upstream().await;
+        let config = push_blob(&upstream, b"{}").await;
+        let first = push_manifest(&upstream, "latest", image(&config, "1")).await;
+        let p = proxy(&url, Duration::from_secs(0));
+        let fetched = p.get_manifest(REPO, "latest").await.unwrap().unwrap();
+        assert_eq!(fetched.digest, first.digest);
+
+        let second = push_manifest(&upstream, "latest", image(&config, "2")).await;
+        let fetched = p.get_manifest(REPO, "latest").await.unwrap().unwrap();
+        assert_eq!(fetched.digest, second.digest);
+
+        // Tags removed from the upstream keep being served from the mirror.
+        assert!(upstream.delete_manifest(REPO, "latest").await.unwrap());
+        let fetched = p.get_manifest(REPO, "latest").await.unwrap().unwrap();
+        assert_eq!(fetched.digest, second.digest);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_manifests() {
+        let (upstream, url) = serve_upstream().await;
+        let padding = "a".repeat(MAX_MANIFEST_SIZE);
+        push_manifest(&upstream, "big", format!(r#"{{"padding":"{}"}}"#, padding)).await;
+        let p = proxy(&url, Duration::from_secs(300));
+        match p.get_manifest(REPO, "big").await {
+            Err(StoreError::Io(e)) => assert!(e.to_string().contains("larger than")),
+            _ => panic!("oversized manifest was not refused"),
+        }
+        assert!(p.local.get_manifest(REPO, "big").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fetches_run_one_at_a_time() {
+        let p = Arc::new(proxy("http://127.0.0.1:1", Duration::from_secs(300)));
+        let running = Arc::new(AtomicUsize::new(0));
+        // Requests keep arriving while others wait, after the first fetch
+        // has finished.
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let (p, running) = (p.clone(), running.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(i * 3)).await;
+                    p.exclusive("key".to_string(), async {
+                        assert_eq!(running.fetch_add(1, Ordering::SeqCst), 0);
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert!(p.fetching.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgets_stale_tags() {
+        let p = proxy("http://127.0.0.1:1", Duration::from_millis(10));
+        p.mark_checked(REPO, "a").await;
+        p.mark_checked(REPO, "b").await;
+        assert!(p.fresh(REPO, "a").await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        p.mark_checked(REPO, "c").await;
+        assert!(!p.fresh(REPO, "a").await);
+        assert_eq!(p.checked.lock().await.len(), 1);
+    }
+}
@@ -0,0 +1,598 @@
+use async_trait::async_trait;
+use eocker::digest::Hash;
+use eocker::Descriptor;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+use super::{
+    is_digest, Blob, ByteStream, Manifest, Storage, Store, StoreError, StoreResult, Stored,
+    UploadSession,
+};
+use crate::config::QuotaConfig;
+
+// QuotaStorage accounts for the bytes of the blobs and manifests stored in
+// each repository, and refuses content that would exceed the quotas. Content
+// counts towards every repository it is linked to or stored in, but only once
+// towards the total however many repositories share it.
+pub struct QuotaStorage {
+    inner: Store,
+    quotas: QuotaConfig,
+    usage: Mutex<Usage>,
+    // Locks of the blobs being committed, mounted or deleted, keyed by
+    // <repository>@<digest>, so that a blob linked by one request is not unlinked by
+    // another that finds it would exceed a quota.
+    linking: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Blob,
+    Manifest,
+}
+
+#[derive(Default)]
+struct Usage {
+    // Size of every blob and manifest accounted for.
+    sizes: HashMap<String, u64>,
+    // How many repositories hold each blob or manifest.
+    holders: HashMap<String, usize>,
+    repositories: HashMap<String, RepositoryUsage>,
+    total: u64,
+}
+
+#[derive(Default)]
+struct RepositoryUsage {
+    blobs: HashSet<String>,
+    manifests: HashSet<String>,
+    bytes: u64,
+}
+
+impl RepositoryUsage {
+    fn holds(&self, digest: &str) -> bool {
+        self.blobs.contains(digest) || self.manifests.contains(digest)
+    }
+
+    fn set(&mut self, kind: Kind) -> &mut HashSet<String> {
+        match kind {
+            Kind::Blob => &mut self.blobs,
+            Kind::Manifest => &mut self.manifests,
+        }
+    }
+}
+
+impl Usage {
+    // Returns by how many bytes the repository and the total would grow if
+    // the repository held the content.
+    fn growth(&self, repo: &str, digest: &str, size: u64) -> (u64, u64) {
+        if self
+            .repositories
+            .get(repo)
+            .map(|r| r.holds(digest))
+            .unwrap_or(false)
+        {
+            return (0, 0);
+        }
+        let total = if self.holders.contains_key(digest) {
+            0
+        } else {
+            size
+        };
+        (size, total)
+    }
+
+    // Accounts for content held by a repository. Returns false if it was
+    // already accounted for.
+    fn add(&mut self, repo: &str, kind: Kind, digest: &str, size: u64) -> bool {
+        let r = self.repositories.entry(repo.to_string()).or_default();
+        let held = r.holds(digest);
+        if !r.set(kind).insert(digest.to_string()) {
+            return false;
+        }
+        if held {
+            return true;
+        }
+        r.bytes += size;
+        let holders = self.holders.entry(digest.to_string()).or_default();
+        *holders += 1;
+        if *holders == 1 {
+            self.sizes.insert(digest.to_string(), size);
+            self.total += size;
+        }
+        true
+    }
+
+    fn remove(&mut self, repo: &str, kind: Kind, digest: &str) {
+        let r = match self.repositories.get_mut(repo) {
+            None => return,
+            Some(r) => r,
+        };
+        if !r.set(kind).remove(digest) || r.holds(digest) {
+            return;
+        }
+        let size = self.sizes.get(digest).copied().unwrap_or_default();
+        r.bytes -= size;
+        if r.blobs.is_empty() && r.manifests.is_empty() {
+            self.repositories.remove(repo);
+        }
+        if let Some(holders) = self.holders.get_mut(digest) {
+            *holders -= 1;
+            if *holders == 0 {
+                self.holders.remove(digest);
+                self.sizes.remove(digest);
+                self.total -= size;
+            }
+        }
+    }
+}
+
+// UsageReport is the usage of the registry and its repositories, along with
+// their quotas.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    pub total_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub repositories: Vec<RepositoryReport>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryReport {
+    pub name: String,
+    pub bytes: u64,
+    pub quota_bytes: Option<u64>,
+}
+
+impl QuotaStorage {
+    // Wraps storage, accounting for the content it already holds.
+    pub async fn open(inner: Store, quotas: QuotaConfig) -> StoreResult<QuotaStorage> {
+        let mut usage = Usage::default();
+        for l in inner.blob_links().await? {
+            if let Some(size) = inner.blob_size(&l.repo, &l.digest).await? {
+                usage.add(&l.repo, Kind::Blob, &l.digest, size);
+            }
+        }
+        for m in inner.stored_manifests().await? {
+            if let Some(manifest) = inner.get_manifest(&m.repo, &m.digest).await? {
+                let size = manifest.content.len() as u64;
+                usage.add(&m.repo, Kind::Manifest, &m.digest, size);
+            }
+        }
+        Ok(QuotaStorage {
+            inner,
+            quotas,
+            usage: Mutex::new(usage),
+            linking: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Runs a change to a blob of a repository after any other change to it
+    // has finished.
+    async fn exclusive<T>(&self, repo: &str, digest: &str, change: impl Future<Output = T>) -> T {
+        let key = format!("{}@{}", repo, digest);
+        let lock = self
+            .linking
+            .lock()
+            .await
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _linking = lock.lock().await;
+            change.await
+        };
+        // The lock is only removed once no other request holds it.
+        let mut linking = self.linking.lock().await;
+        if Arc::strong_count(&lock) == 2 {
+            linking.remove(&key);
+        }
+        result
+    }
+
+    pub async fn usage(&self) -> UsageReport {
+        let usage = self.usage.lock().await;
+        let mut repositories: Vec<RepositoryReport> = usage
+            .repositories
+            .iter()
+            .map(|(name, r)| RepositoryReport {
+                name: name.clone(),
+                bytes: r.bytes,
+                quota_bytes: self.quotas.of(name),
+            })
+            .collect();
+        repositories.sort_by(|a, b| a.name.cmp(&b.name));
+        UsageReport {
+            total_bytes: usage.total,
+            quota_bytes: self.quotas.total,
+            repositories,
+        }
+    }
+
+    // Returns how many distinct blobs and manifests are stored, and their
+    // bytes.
+    pub async fn objects(&self) -> (usize, usize, u64) {
+        let usage = self.usage.lock().await;
+        let mut blobs = HashSet::new();
+        let mut manifests = HashSet::new();
+        for r in usage.repositories.values() {
+            blobs.extend(r.blobs.iter());
+            manifests.extend(r.manifests.iter());
+        }
+        (blobs.len(), manifests.len(), usage.total)
+    }
+
+    // Checks that a repository may hold content without exceeding a quota.
+    fn check(&self, usage: &Usage, repo: &str, digest: &str, size: u64) -> StoreResult<()> {
+        let (grown, total) = usage.growth(repo, digest, size);
+        if let Some(quota) = self.quotas.of(repo) {
+            let bytes = usage.repositories.get(repo).map(|r| r.bytes);
+            if bytes.unwrap_or_default() + grown > quota {
+                return Err(StoreError::Denied(format!(
+                    "{} would exceed its quota of {} bytes",
+                    repo, quota
+                )));
+            }
+        }
+        if let Some(quota) = self.quotas.total {
+            if usage.total + total > quota {
+                return Err(StoreError::Denied(format!(
+                    "the registry would exceed its quota of {} bytes",
+                    quota
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Accounts for content before it is stored, so that concurrent requests
+    // cannot exceed a quota together. Returns whether the content was not
+    // already accounted for, in which case it has to be released if storing
+    // it fails.
+    async fn reserve(&self, repo: &str, kind: Kind, digest: &str, size: u64) -> StoreResult<bool> {
+        let mut usage = self.usage.lock().await;
+        self.check(&usage, repo, digest, size)?;
+        Ok(usage.add(repo, kind, digest, size))
+    }
+
+    async fn release(&self, repo: &str, kind: Kind, digest: &str) {
+        self.usage.lock().await.remove(repo, kind, digest);
+    }
+}
+
+#[async_trait]
+impl Storage for QuotaStorage {
+    async fn get_blob(
+        &self,
+        repo: &str,
+        digest: &str,
+        range: Option<Range<u64>>,
+    ) -> StoreResult<Option<Blob>> {
+        self.inner.get_blob(repo, digest, range).await
+    }
+
+    async fn blob_size(&self, repo: &str, digest: &str) -> StoreResult<Option<u64>> {
+        self.inner.blob_size(repo, digest).await
+    }
+
+    async fn delete_blob(&self, repo: &str, digest: &str) -> StoreResult<bool> {
+        self.exclusive(repo, digest, async {
+            let deleted = self.inner.delete_blob(repo, digest).await?;
+            if deleted {
+                self.release(repo, Kind::Blob, digest).await;
+            }
+            Ok(deleted)
+        })
+        .await
+    }
+
+    async fn mount_blob(&self, repo: &str, from: &str, digest: &str) -> StoreResult<bool> {
+        self.exclusive(repo, digest, async {
+            let size = match self.inner.blob_size(from, digest).await? {
+                None => return Ok(false),
+                Some(s) => s,
+            };
+            let reserved = self.reserve(repo, Kind::Blob, digest, size).await?;
+            let mounted = self.inner.mount_blob(repo, from, digest).await;
+            if reserved && !matches!(mounted, Ok(true)) {
+                self.release(repo, Kind::Blob, digest).await;
+            }
+            mounted
+        })
+        .await
+    }
+
+    async fn start_upload(&self, repo: &str) -> StoreResult<String> {
+        self.inner.start_upload(repo).await
+    }
+
+    async fn upload_session(&self, repo: &str, id: &str) -> StoreResult<Option<UploadSession>> {
+        self.inner.upload_session(repo, id).await
+    }
+
+    async fn append_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        start: Option<u64>,
+        chunk: ByteStream,
+    ) -> StoreResult<u64> {
+        self.inner.append_upload(repo, id, start, chunk).await
+    }
+
+    // The size of a blob is only known once its last chunk is committed, so
+    // a blob that turns out to exceed a quota is unlinked again, unless the
+    // repository held it before. Uploads that already exceed one are refused
+    // without committing them.
+    async fn commit_upload(
+        &self,
+        repo: &str,
+        id: &str,
+        digest: &Hash,
+        chunk: ByteStream,
+    ) -> StoreResult<()> {
+        let digest_str = &digest.to_string();
+        self.exclusive(repo, digest_str, async {
+            if let Some(s) = self.inner.upload_session(repo, id).await? {
+                self.check(&*self.usage.lock().await, repo, digest_str, s.size)?;
+            }
+            let held = self.inner.blob_size(repo, digest_str).await?.is_some();
+            self.inner.commit_upload(repo, id, digest, chunk).await?;
+            let size = match self.inner.blob_size(repo, digest_str).await? {
+                None => return Ok(()),
+                Some(s) => s,
+            };
+            if let Err(e) = self.reserve(repo, Kind::Blob, digest_str, size).await {
+                if !held {
+                    self.inner.delete_blob(repo, digest_str).await?;
+                }
+                return Err(e);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn cancel_upload(&self, repo: &str, id: &str) -> StoreResult<bool> {
+        self.inner.cancel_upload(repo, id).await
+    }
+
+    async fn expire_uploads(&self, before: SystemTime) -> StoreResult<Vec<UploadSession>> {
+        self.inner.expire_uploads(before).await
+    }
+
+    async fn active_uploads(&self) -> StoreResult<usize> {
+        self.inner.active_uploads().await
+    }
+
+    async fn get_manifest(&self, repo: &str, reference: &str) -> StoreResult<Option<Manifest>> {
+        self.inner.get_manifest(repo, reference).await
+    }
+
+    async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: Manifest,
+    ) -> StoreResult<()> {
+        let digest = manifest.digest.clone();
+        let size = manifest.content.len() as u64;
+        let reserved = self.reserve(repo, Kind::Manifest, &digest, size).await?;
+        let put = self.inner.put_manifest(repo, reference, manifest).await;
+        if reserved && put.is_err() {
+            self.release(repo, Kind::Manifest, &digest).await;
+        }
+        put
+    }
+
+    async fn delete_manifest(&self, repo: &str, reference: &str) -> StoreResult<bool> {
+        let deleted = self.inner.delete_manifest(repo, reference).await?;
+        // Deleting a tag leaves the manifest stored.
+        if deleted && is_digest(reference) {
+            self.release(repo, Kind::Manifest, reference).await;
+            // Backends that store manifests as blobs remove both.
+            if self.inner.blob_size(repo, reference).await?.is_none() {
+                self.release(repo, Kind::Blob, reference).await;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn tags(&self, repo: &str) -> StoreResult<Option<Vec<String>>> {
+        self.inner.tags(repo).await
+    }
+
+    async fn repositories(&self) -> StoreResult<Vec<String>> {
+        self.inner.repositories().await
+    }
+
+    async fn repository_exists(&self, repo: &str) -> StoreResult<bool> {
+        self.inner.repository_exists(repo).await
+    }
+
+    async fn add_referrer(
+        &self,
+        repo: &str,
+        subject: &str,
+        referrer: Descriptor,
+    ) -> StoreResult<()> {
+        self.inner.add_referrer(repo, subject, referrer).await
+    }
+
+    async fn referrers(&self, repo: &str, subject: &str) -> StoreResult<Vec<Descriptor>> {
+        self.inner.referrers(repo, subject).await
+    }
+
+    async fn blob_links(&self) -> StoreResult<Vec<Stored>> {
+        self.inner.blob_links().await
+    }
+
+    async fn stored_manifests(&self) -> StoreResult<Vec<Stored>> {
+        self.inner.stored_manifests().await
+    }
+
+    async fn purge_blobs(&self, before: SystemTime, dry_run: bool) -> StoreResult<Vec<String>> {
+        self.inner.purge_blobs(before, dry_run).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{chunk, image_manifest, push_blob, read_blob};
+    use super::super::{new_memory_store, new_quota_store, Quotas};
+    use super::*;
+
+    async fn quota_store(inner: Store, total: Option<u64>, repository: Option<u64>) -> Quotas {
+        let mut repositories = HashMap::new();
+        repositories.insert("big".to_string(), 100);
+        let quotas = QuotaConfig {
+            total,
+            repository,
+            repositories,
+        };
+        new_quota_store(inner, quotas).await.unwrap()
+    }
+
+    fn bytes_of(usage: &UsageReport) -> Vec<(&str, u64)> {
+        usage
+            .repositories
+            .iter()
+            .map(|r| (r.name.as_str(), r.bytes))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn accounts_for_shared_content_once_in_total() {
+        let quotas = quota_store(new_memory_store(), None, None).await;
+        let store: Store = quotas.clone();
+        let d = push_blob(&store, "a", b"hello").await;
+        push_blob(&store, "b", b"hello").await;
+        assert!(store.mount_blob("c", "a", &d).await.unwrap());
+        let m = image_manifest(&d, "");
+        store.put_manifest("a", "latest", m.clone()).await.unwrap();
+        let size = m.content.len() as u64;
+
+        let usage = quotas.usage().await;
+        assert_eq!(usage.total_bytes, 5 + size);
+        assert_eq!(bytes_of(&usage), vec![("a", 5 + size), ("b", 5), ("c", 5)]);
+        assert_eq!(quotas.objects().await, (1, 1, 5 + size));
+
+        store.delete_blob("a", &d).await.unwrap();
+        store.delete_blob("b", &d).await.unwrap();
+        assert_eq!(quotas.usage().await.total_bytes, 5 + size);
+        store.delete_blob("c", &d).await.unwrap();
+        store.delete_manifest("a", &m.digest).await.unwrap();
+        let usage = quotas.usage().await;
+        assert_eq!(usage.total_bytes, 0);
+        assert!(usage.repositories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accounts_for_content_stored_before_it_was_opened() {
+        let inner = new_memory_store();
+        let d = push_blob(&inner, "a", b"hello").await;
+        inner
+            .put_manifest("a", "latest", image_manifest(&d, ""))
+            .await
+            .unwrap();
+        push_blob(&inner, "b", b"hello").await;
+        let quotas = quota_store(inner, None, None).await;
+        let usage = quotas.usage().await;
+        let size = image_manifest(&d, "").content.len() as u64;
+        assert_eq!(usage.total_bytes, 5 + size);
+        assert_eq!(bytes_of(&usage), vec![("a", 5 + size), ("b", 5)]);
+    }
+
+    #[tokio::test]
+    async fn denies_content_over_the_repository_quota() {
+        let quotas = quota_store(new_memory_store(), None, Some(8)).await;
+        let store: Store = quotas.clone();
+        let d = push_blob(&store, "a", b"hello").await;
+        // Pushing the blob again takes no more space.
+        push_blob(&store, "a", b"hello").await;
+
+        let digest = Hash::of("sha256", b"world").unwrap();
+        let id = store.start_upload("a").await.unwrap();
+        assert!(matches!(
+            store
+                .commit_upload("a", &id, &digest, chunk(b"world"))
+                .await,
+            Err(StoreError::Denied(_))
+        ));
+        assert!(read_blob(&store, "a", &digest.to_string()).await.is_none());
+        assert!(read_blob(&store, "a", &d).await.is_some());
+        // Uploads that already exceed the quota are refused before they are
+        // committed.
+        let id = store.start_upload("a").await.unwrap();
+        store
+            .append_upload("a", &id, None, chunk(b"world"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.commit_upload("a", &id, &digest, chunk(b"")).await,
+            Err(StoreError::Denied(_))
+        ));
+        assert!(store.upload_session("a", &id).await.unwrap().is_some());
+
+        let m = image_manifest(&d, "");
+        assert!(matches!(
+            store.put_manifest("a", "latest", m).await,
+            Err(StoreError::Denied(_))
+        ));
+        // Repositories with a quota of their own are not limited by the
+        // default one.
+        let world = push_blob(&store, "big", b"world").await;
+        assert!(matches!(
+            store.mount_blob("a", "big", &world).await,
+            Err(StoreError::Denied(_))
+        ));
+        assert!(read_blob(&store, "a", &world).await.is_none());
+        assert_eq!(bytes_of(&quotas.usage().await), vec![("a", 5), ("big", 5)]);
+    }
+
+    #[tokio::test]
+    async fn denies_content_over_the_total_quota() {
+        let quotas = quota_store(new_memory_store(), Some(8), None).await;
+        let store: Store = quotas.clone();
+        let d = push_blob(&store, "a", b"hello").await;
+        // Shared content does not count towards the total again.
+        assert!(store.mount_blob("b", "a", &d).await.unwrap());
+        push_blob(&store, "c", b"hello").await;
+
+        let digest = Hash::of("sha256", b"world").unwrap();
+        let id = store.start_upload("b").await.unwrap();
+        let committed = store.commit_upload("b", &id, &digest, chunk(b"world"));
+        match committed.await {
+            Err(StoreError::Denied(detail)) => {
+                assert_eq!(detail, "the registry would exceed its quota of 8 bytes")
+            }
+            _ => panic!("expected the blob to be denied"),
+        }
+        let usage = quotas.usage().await;
+        assert_eq!(usage.total_bytes, 5);
+        assert_eq!(usage.quota_bytes, Some(8));
+    }
+
+    #[tokio::test]
+    async fn recommits_blobs_the_repository_holds() {
+        let quotas = quota_store(new_memory_store(), None, Some(8)).await;
+        let store: Store = quotas.clone();
+        let d = push_blob(&store, "a", b"hello").await;
+        let digest: Hash = d.parse().unwrap();
+        let pushes = (0..4).map(|_| async {
+            let id = store.start_upload("a").await.unwrap();
+            store
+                .commit_upload("a", &id, &digest, chunk(b"hello"))
+                .await
+        });
+        for pushed in futures::future::join_all(pushes).await {
+            pushed.unwrap();
+        }
+        assert_eq!(read_blob(&store, "a", &d).await.unwrap(), b"hello");
+        assert_eq!(bytes_of(&quotas.usage().await), vec![("a", 5)]);
+    }
+}
@@ -0,0 +1,482 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::BytesMut;
+use eocker::digest::Hash;
+use eocker::types::MediaType;
+use futures::StreamExt;
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::{Client, HttpConnector};
+use hyper::header::{
+    HeaderName, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, USER_AGENT,
+    WWW_AUTHENTICATE,
+};
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use super::config::UpstreamConfig;
+use super::store::{ByteStream, Manifest};
+
+// Bundles of trusted certificates where common distributions install them.
+const CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+const MAX_REDIRECTS: usize = 5;
+
+// Manifests larger than this are refused, as registries are not required to
+// accept them either.
+pub(crate) const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+
+// Upstream is a client of the registry that is mirrored. It authenticates
+// with the configured credentials, or anonymously, exchanging them for
+// bearer tokens if the upstream asks for them.
+pub struct Upstream {
+    // URL of the upstream, without a trailing slash.
+    url: String,
+    base: Uri,
+    credentials: Option<(String, String)>,
+    client: Client<HttpsConnector>,
+    // Bearer tokens, keyed by the scope they were issued for.
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl Upstream {
+    pub fn new(c: &UpstreamConfig) -> Result<Upstream, String> {
+        let url = c.url.trim_end_matches('/').to_string();
+        let base: Uri = url
+            .parse()
+            .map_err(|e| format!("invalid upstream {}: {}", c.url, e))?;
+        let https = match base.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(format!("upstream {} is not an http or https URL", c.url)),
+        };
+        if base.host().is_none() {
+            return Err(format!("upstream {} has no host", c.url));
+        }
+        let roots = load_roots(c.ca.as_deref())?;
+        if https && roots.is_empty() {
+            return Err("no trusted certificates found, set upstream-ca".to_string());
+        }
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let connector = HttpsConnector {
+            http,
+            tls: TlsConnector::from(Arc::new(config)),
+        };
+        Ok(Upstream {
+            url,
+            base,
+            credentials: c.username.clone().zip(c.password.clone()),
+            client: Client::builder().build(connector),
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Fetches a manifest, verifying that its content matches the digest it
+    // was referenced by or, for tags, the digest the upstream reports.
+    // Returns None if the upstream does not have it.
+    pub async fn manifest(&self, repo: &str, reference: &str) -> io::Result<Option<Manifest>> {
+        let res = self.get(Method::GET, repo, "manifests", reference).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let res = success(res)?;
+        let content_type = header(&res, &CONTENT_TYPE)
+            .unwrap_or_else(|| MediaType::OCIManifestSchema1.to_string());
+        let expected = if reference.contains(':') {
+            Some(reference.to_string())
+        } else {
+            header(&res, &docker_content_digest())
+        };
+        // The limit is enforced as the manifest is read, so an upstream cannot
+        // make the registry buffer more than it.
+        let mut body = res.into_body();
+        let mut content = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(other)?;
+            if content.len() + chunk.len() > MAX_MANIFEST_SIZE {
+                return Err(other(format!(
+                    "manifest {} is larger than {} bytes",
+                    reference, MAX_MANIFEST_SIZE
+                )));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        let content = content.freeze();
+        let digest = match expected {
+            None => Hash::of("sha256", &content).map_err(other)?,
+            Some(d) => {
+                let expected = d.parse::<Hash>().map_err(other)?;
+                let actual = Hash::of(&expected.algorithm, &content).map_err(other)?;
+                if actual != expected {
+                    return Err(invalid(format!(
+                        "expected {} but upstream manifest hashed to {}",
+                        expected, actual
+                    )));
+                }
+                actual
+            }
+        };
+        Ok(Some(Manifest {
+            digest: digest.to_string(),
+            content_type,
+            content,
+        }))
+    }
+
+    // Returns the digest of the manifest a reference resolves to without
+    // fetching the manifest, or None if the upstream does not report it.
+    pub async fn manifest_digest(&self, repo: &str, reference: &str) -> io::Result<Option<String>> {
+        let res = self.get(Method::HEAD, repo, "manifests", reference).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(header(&success(res)?, &docker_content_digest()))
+    }
+
+    // Returns the size of a blob, or None if the upstream does not have it.
+    pub async fn blob_size(&self, repo: &str, digest: &str) -> io::Result<Option<u64>> {
+        let res = self.get(Method::HEAD, repo, "blobs", digest).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let size = header(&success(res)?, &CONTENT_LENGTH).and_then(|s| s.parse().ok());
+        size.map(Some)
+            .ok_or_else(|| other(format!("upstream did not report the size of {}", digest)))
+    }
+
+    // Streams the content of a blob, or returns None if the upstream does not
+    // have it. The content is verified as it is stored, not here.
+    pub async fn blob(&self, repo: &str, digest: &str) -> io::Result<Option<ByteStream>> {
+        let res = self.get(Method::GET, repo, "blobs", digest).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let content = success(res)?.into_body().map(|c| c.map_err(other));
+        Ok(Some(Box::pin(content)))
+    }
+
+    // Requests a manifest or blob of a repository, following redirects and
+    // authenticating if the upstream challenges the request.
+    async fn get(
+        &self,
+        method: Method,
+        repo: &str,
+        kind: &str,
+        reference: &str,
+    ) -> io::Result<Response<Body>> {
+        let mut uri: Uri = format!("{}/v2/{}/{}/{}", self.url, repo, kind, reference)
+            .parse()
+            .map_err(invalid)?;
+        let scope = format!("repository:{}:pull", repo);
+        let mut authenticated = false;
+        let mut redirects = 0;
+        loop {
+            let mut req = Request::builder().method(method.clone()).uri(uri.clone());
+            if kind == "manifests" {
+                req = req.header(ACCEPT, manifest_types());
+            }
+            // Credentials are only sent to the upstream itself, not to the
+            // storage it may redirect blob requests to.
+            if uri.authority() == self.base.authority() {
+                if let Some(t) = self.tokens.lock().await.get(&scope) {
+                    req = req.header(AUTHORIZATION, format!("Bearer {}", t));
+                } else if let Some(c) = self.basic_credentials() {
+                    req = req.header(AUTHORIZATION, c);
+                }
+            }
+            let res = self.send(req.body(Body::empty()).map_err(other)?).await?;
+            if res.status() == StatusCode::UNAUTHORIZED && !authenticated {
+                authenticated = true;
+                let token = match header(&res, &WWW_AUTHENTICATE) {
+                    Some(c) => self.fetch_token(&c).await?,
+                    None => None,
+                };
+                if let Some(t) = token {
+                    self.tokens.lock().await.insert(scope.clone(), t);
+                    continue;
+                }
+            }
+            if !res.status().is_redirection() {
+                return Ok(res);
+            }
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(other(format!("too many redirects for {}", reference)));
+            }
+            let location = header(&res, &LOCATION)
+                .ok_or_else(|| other("upstream redirected without a location"))?;
+            uri = resolve(&uri, &location)?;
+        }
+    }
+
+    // Fetches a bearer token as asked to by a challenge, or returns None if
+    // the challenge is not for one.
+    async fn fetch_token(&self, challenge: &str) -> io::Result<Option<String>> {
+        let params = match parse_challenge(challenge) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| other("upstream challenge has no realm"))?;
+        let query: Vec<String> = ["service", "scope"]
+            .iter()
+            .filter_map(|k| {
+                params
+                    .get(*k)
+                    .map(|v| format!("{}={}", k, urlencoding::encode(v)))
+            })
+            .collect();
+        let uri = if query.is_empty() {
+            realm.clone()
+        } else {
+            format!("{}?{}", realm, query.join("&"))
+        };
+        let mut req = Request::get(uri.parse::<Uri>().map_err(invalid)?);
+        if let Some(c) = self.basic_credentials() {
+            req = req.header(AUTHORIZATION, c);
+        }
+        let res = success(self.send(req.body(Body::empty()).map_err(other)?).await?)?;
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(other)?;
+        let token: TokenResponse = serde_json::from_slice(&body).map_err(invalid)?;
+        token
+            .token
+            .or(token.access_token)
+            .map(Some)
+            .ok_or_else(|| invalid("token response has no token"))
+    }
+
+    fn basic_credentials(&self) -> Option<String> {
+        self.credentials
+            .as_ref()
+            .map(|(u, p)| format!("Basic {}", STANDARD.encode(format!("{}:{}", u, p))))
+    }
+
+    async fn send(&self, mut req: Request<Body>) -> io::Result<Response<Body>> {
+        req.headers_mut()
+            .insert(USER_AGENT, "eocker-registry".parse().map_err(invalid)?);
+        self.client.request(req).await.map_err(other)
+    }
+}
+
+// HttpsConnector connects to the upstream, and to the storage it redirects
+// to, over TLS for https URLs and plain TCP otherwise.
+#[derive(Clone)]
+struct HttpsConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = Stream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Stream>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.http.poll_ready(cx).map_err(other)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let (mut http, tls) = (self.http.clone(), self.tls.clone());
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| invalid("request has no host"))?
+                .to_string();
+            let https = uri.scheme_str() == Some("https");
+            let tcp = http.call(uri).await.map_err(other)?;
+            if !https {
+                return Ok(Stream::Plain(tcp));
+            }
+            let name = ServerName::try_from(host).map_err(invalid)?;
+            Ok(Stream::Tls(Box::new(tls.connect(name, tcp).await?)))
+        })
+    }
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            Stream::Plain(s) => s.connected(),
+            Stream::Tls(s) => s.get_ref().0.connected(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// Loads the certificates trusted for the upstream from a bundle, or from the
+// first bundle of the system that exists.
+fn load_roots(ca: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    let path = match ca {
+        Some(p) => p,
+        None => match CA_BUNDLES.iter().map(Path::new).find(|p| p.exists()) {
+            Some(p) => p,
+            None => return Ok(roots),
+        },
+    };
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| format!("invalid certificates in {}: {}", path.display(), e))?;
+    roots.add_parsable_certificates(certs);
+    Ok(roots)
+}
+
+// Media types of the manifests requested from the upstream. Clients are
+// served the ones they accept from the local copy.
+fn manifest_types() -> String {
+    [
+        MediaType::OCIImageIndex,
+        MediaType::OCIManifestSchema1,
+        MediaType::DockerManifestList,
+        MediaType::DockerManifestSchema2,
+    ]
+    .iter()
+    .map(MediaType::as_str)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+fn docker_content_digest() -> HeaderName {
+    HeaderName::from_static("docker-content-digest")
+}
+
+fn header(res: &Response<Body>, name: &HeaderName) -> Option<String> {
+    res.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+// Returns the response if it is successful, or an error describing it.
+fn success(res: Response<Body>) -> io::Result<Response<Body>> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    Err(other(format!("upstream responded with {}", res.status())))
+}
+
+// Resolves the location of a redirect, which may be relative to the request.
+fn resolve(uri: &Uri, location: &str) -> io::Result<Uri> {
+    if location.starts_with('/') {
+        let authority = uri
+            .authority()
+            .ok_or_else(|| invalid("request has no host"))?;
+        let scheme = uri.scheme_str().unwrap_or("http");
+        return format!("{}://{}{}", scheme, authority, location)
+            .parse()
+            .map_err(invalid);
+    }
+    location.parse().map_err(invalid)
+}
+
+// Parses the parameters of a Bearer challenge, such as
+// Bearer realm="https://auth.example.com/token",service="registry".
+fn parse_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let (scheme, rest) = challenge.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut params = HashMap::new();
+    let mut rest = rest.trim_start();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().to_lowercase();
+        // Quoted values may contain commas, as scopes do.
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(v) => {
+                let end = v.find('"')?;
+                (&v[..end], &v[end + 1..])
+            }
+            None => value.split_at(value.find(',').unwrap_or(value.len())),
+        };
+        params.insert(key, value.to_string());
+        rest = tail.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    Some(params)
+}
+
+fn other<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::other(e)
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
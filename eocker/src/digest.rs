@@ -1,27 +1,90 @@
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
+use std::{fmt, str::FromStr};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Hash {
     pub algorithm: String,
     pub hex: String,
 }
 
+impl Hash {
+    // Computes the digest of content using the given algorithm.
+    pub fn of(algorithm: &str, content: &[u8]) -> Result<Hash, DigestError> {
+        let mut h = Hasher::new(algorithm)?;
+        h.update(content);
+        Ok(h.finalize())
+    }
+
+    // Reports whether content hashes to this digest.
+    pub fn verify(&self, content: &[u8]) -> Result<bool, DigestError> {
+        Ok(Hash::of(&self.algorithm, content)? == *self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, hex) = s
+            .split_once(":")
+            .ok_or_else(|| DigestError(format!("could not split digest {}", s)))?;
+        // algorithm-component ::= [a-z0-9]+
+        // algorithm-separator ::= [+._-]
+        let valid_algorithm = !algorithm.is_empty()
+            && algorithm.split(['+', '.', '_', '-']).all(|c| {
+                !c.is_empty()
+                    && c.chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            });
+        if !valid_algorithm {
+            return Err(DigestError(format!(
+                "invalid digest algorithm {}",
+                algorithm
+            )));
+        }
+        // encoded ::= [a-zA-Z0-9=_-]+
+        let valid_encoded = !hex.is_empty()
+            && hex
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | '_' | '-'));
+        if !valid_encoded {
+            return Err(DigestError(format!("invalid encoded digest {}", hex)));
+        }
+        // Registered algorithms must use lowercase hex of a fixed length.
+        let len = match algorithm {
+            "sha256" => Some(64),
+            "sha512" => Some(128),
+            _ => None,
+        };
+        if let Some(len) = len {
+            if hex.len() != len || !hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+                return Err(DigestError(format!(
+                    "{} digest must be {} lowercase hex characters",
+                    algorithm, len
+                )));
+            }
+        }
+        Ok(Hash {
+            algorithm: algorithm.to_string(),
+            hex: hex.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
 impl<'de> Deserialize<'de> for Hash {
     fn deserialize<D>(deserializer: D) -> Result<Hash, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s: String = String::deserialize(deserializer)?;
-        let sp = s
-            .split_once(":")
-            .ok_or_else(|| Error::custom("could not split digest"))?;
-        let algorithm = sp.0.to_string();
-        let hex = sp.1.to_string();
-        // ... checks for validity of digest segments ...
-        Ok(Hash {
-            algorithm: algorithm,
-            hex: hex,
-        })
+        s.parse().map_err(Error::custom)
     }
 }
 
@@ -30,6 +93,113 @@ impl Serialize for Hash {
     where
         S: Serializer,
     {
-        serializer.serialize_str(format!("{}:{}", self.algorithm, self.hex).as_str())
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+// Hasher incrementally computes a digest for one of the supported algorithms.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: &str) -> Result<Hasher, DigestError> {
+        match algorithm {
+            "sha256" => Ok(Hasher::Sha256(Sha256::new())),
+            "sha512" => Ok(Hasher::Sha512(Sha512::new())),
+            _ => Err(DigestError(format!(
+                "unsupported digest algorithm {}",
+                algorithm
+            ))),
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Hasher::Sha256(_) => "sha256",
+            Hasher::Sha512(_) => "sha512",
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        let algorithm = self.algorithm().to_string();
+        let hex = match self {
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("{:x}", h.finalize()),
+        };
+        Hash { algorithm, hex }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DigestError(String);
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DigestError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn parses_digests() {
+        let h: Hash = SHA256.parse().unwrap();
+        assert_eq!(h.algorithm, "sha256");
+        assert_eq!(h.to_string(), SHA256);
+        assert_eq!(h, Hash::of("sha256", b"hello").unwrap());
+        assert!(h.verify(b"hello").unwrap());
+        assert!(!h.verify(b"hello!").unwrap());
+
+        let sha512 = format!("sha512:{}", "a".repeat(128));
+        assert!(sha512.parse::<Hash>().is_ok());
+        // Unregistered algorithms only need a valid encoding.
+        let h: Hash = "multihash+base58:QmRZxt2b1FVZPNqd8hsiykDL3TdBDeTSPX9Kv46HmX4Gx8"
+            .parse()
+            .unwrap();
+        assert_eq!(h.algorithm, "multihash+base58");
+    }
+
+    #[test]
+    fn rejects_invalid_digests() {
+        let hex = &SHA256["sha256:".len()..];
+        for d in &[
+            "",
+            "sha256",
+            hex,
+            ":abc",
+            "sha256:",
+            "SHA256:abc",
+            "sha256+:abc",
+            "sha256:abc",
+            &format!("sha256:{}", hex.to_uppercase()),
+            &format!("sha256:{}0", hex),
+            &format!("sha512:{}", hex),
+            "other:a/b",
+        ] {
+            assert!(d.parse::<Hash>().is_err(), "{}", d);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_algorithms() {
+        assert!(Hash::of("md5", b"hello").is_err());
+        let h: Hash = "other:abc".parse().unwrap();
+        assert!(h.verify(b"hello").is_err());
     }
 }
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::digest::Hash;
+use crate::IndexManifest;
+
+// Files and annotations defined by the OCI image layout specification.
+// https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+pub const IMAGE_LAYOUT_VERSION: &str = "1.0.0";
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+pub const INDEX_FILE: &str = "index.json";
+pub const BLOBS_DIR: &str = "blobs";
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageLayout {
+    pub image_layout_version: String,
+}
+
+impl Default for ImageLayout {
+    fn default() -> Self {
+        ImageLayout {
+            image_layout_version: IMAGE_LAYOUT_VERSION.to_string(),
+        }
+    }
+}
+
+// Returns the path of a blob in the image layout rooted at root.
+pub fn blob_path(root: &Path, digest: &Hash) -> PathBuf {
+    root.join(BLOBS_DIR)
+        .join(&digest.algorithm)
+        .join(&digest.hex)
+}
+
+// Reads the index of the image layout rooted at root.
+pub fn read_index(root: &Path) -> io::Result<IndexManifest> {
+    let content = fs::read(root.join(INDEX_FILE))?;
+    Ok(serde_json::from_slice(&content)?)
+}
+
+// Reads a blob from the image layout rooted at root, verifying its content
+// against the digest.
+pub fn read_blob(root: &Path, digest: &Hash) -> io::Result<Vec<u8>> {
+    let content = fs::read(blob_path(root, digest))?;
+    let valid = digest
+        .verify(&content)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("blob content does not match {}", digest),
+        ));
+    }
+    Ok(content)
+}
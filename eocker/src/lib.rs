@@ -1,10 +1,11 @@
-use sha2::{Digest, Sha256};
 use flate2::{write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{collections::HashMap, error::Error, io::Write, time};
 use types::MediaType;
 
 pub mod digest;
+pub mod layout;
 pub mod types;
 
 #[derive(Debug)]
@@ -28,7 +29,7 @@ impl Image {
         let serial = serde_json::to_string(&config)?;
         let raw_config = serial.as_bytes();
         let mut c = Sha256::new();
-        c.update(&raw_config);
+        c.update(raw_config);
         let manifest = Manifest {
             schema_version: 2,
             media_type: Some(MediaType::DockerManifestSchema2),
@@ -42,13 +43,16 @@ impl Image {
                 urls: None,
                 annotations: None,
                 platform: None,
+                artifact_type: None,
             },
             layers: vec![layer.descriptor.clone()],
             annotations: None,
+            artifact_type: None,
+            subject: None,
         };
         Ok(Image {
-            manifest: manifest,
-            config: config,
+            manifest,
+            config,
             layers: vec![layer],
         })
     }
@@ -101,6 +105,7 @@ impl Layer {
                 urls: None,
                 annotations: None,
                 platform: None,
+                artifact_type: None,
             },
             content: tar_gz,
             diff_id: digest::Hash {
@@ -120,6 +125,11 @@ pub struct Manifest {
     pub config: Descriptor,
     pub layers: Vec<Descriptor>,
     pub annotations: Option<HashMap<String, String>>,
+    // The type of artifact the manifest describes, if it is not an image.
+    pub artifact_type: Option<MediaType>,
+    // The manifest this manifest refers to, such as the image an SBOM or
+    // signature describes.
+    pub subject: Option<Descriptor>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -130,6 +140,8 @@ pub struct IndexManifest {
     pub media_type: Option<MediaType>,
     pub manifests: Vec<Descriptor>,
     pub annotations: Option<HashMap<String, String>>,
+    pub artifact_type: Option<MediaType>,
+    pub subject: Option<Descriptor>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -142,6 +154,7 @@ pub struct Descriptor {
     pub urls: Option<Vec<String>>,
     pub annotations: Option<HashMap<String, String>>,
     pub platform: Option<Platform>,
+    pub artifact_type: Option<MediaType>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -1,8 +1,5 @@
-use serde::{
-    de::{value, IntoDeserializer},
-    Deserialize, Serialize,
-};
-use std::convert::TryFrom;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 
 pub trait Media {
     fn is_distributable(&self) -> bool;
@@ -10,75 +7,98 @@ pub trait Media {
     fn is_index(&self) -> bool;
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum MediaType {
-    #[serde(rename = "application/vnd.oci.descriptor.v1+json")]
-    OCIContentDescriptor,
-    #[serde(rename = "application/vnd.oci.image.index.v1+json")]
-    OCIImageIndex,
-    #[serde(rename = "application/vnd.oci.image.manifest.v1+json")]
-    OCIManifestSchema1,
-    #[serde(rename = "application/vnd.oci.image.config.v1+json")]
-    OCIConfigJSON,
-    #[serde(rename = "application/vnd.oci.image.layer.v1.tar+gzip")]
-    OCILayer,
-    #[serde(rename = "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip")]
-    OCIRestrictedLayer,
-    #[serde(rename = "application/vnd.oci.image.layer.v1.tar")]
-    OCIUncompressedLayer,
-    #[serde(rename = "application/vnd.oci.image.layer.nondistributable.v1.tar")]
-    OCIUncompressedRestrictedLayer,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v1+json")]
-    DockerManifestSchema1,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v1+prettyjws")]
-    DockerManifestSchema1Signed,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v2+json")]
-    DockerManifestSchema2,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.list.v2+json")]
-    DockerManifestList,
-    #[serde(rename = "application/vnd.docker.image.rootfs.diff.tar.gzip")]
-    DockerLayer,
-    #[serde(rename = "application/vnd.docker.container.image.v1+json")]
-    DockerConfigJSON,
-    #[serde(rename = "application/vnd.docker.plugin.v1+json")]
-    DockerPluginConfig,
-    #[serde(rename = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip")]
-    DockerForeignLayer,
-    #[serde(rename = "application/vnd.docker.image.rootfs.diff.tar")]
-    DockerUncompressedLayer,
+// Declares the media types known to eocker along with their strings. Any
+// other media type is kept as MediaType::Other so that manifests with
+// arbitrary artifact and layer types can still be read.
+macro_rules! media_types {
+    ($($variant:ident => $name:literal,)*) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum MediaType {
+            $($variant,)*
+            Other(String),
+        }
+
+        impl MediaType {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(MediaType::$variant => $name,)*
+                    MediaType::Other(s) => s,
+                }
+            }
+        }
+
+        impl From<&str> for MediaType {
+            fn from(s: &str) -> Self {
+                match s {
+                    $($name => MediaType::$variant,)*
+                    _ => MediaType::Other(s.to_string()),
+                }
+            }
+        }
+    };
 }
 
-impl TryFrom<&str> for MediaType {
-    type Error = value::Error;
+media_types! {
+    OCIContentDescriptor => "application/vnd.oci.descriptor.v1+json",
+    OCIImageIndex => "application/vnd.oci.image.index.v1+json",
+    OCIManifestSchema1 => "application/vnd.oci.image.manifest.v1+json",
+    OCIConfigJSON => "application/vnd.oci.image.config.v1+json",
+    OCIEmptyJSON => "application/vnd.oci.empty.v1+json",
+    OCILayer => "application/vnd.oci.image.layer.v1.tar+gzip",
+    OCIRestrictedLayer => "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    OCIUncompressedLayer => "application/vnd.oci.image.layer.v1.tar",
+    OCIUncompressedRestrictedLayer => "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    DockerManifestSchema1 => "application/vnd.docker.distribution.manifest.v1+json",
+    DockerManifestSchema1Signed => "application/vnd.docker.distribution.manifest.v1+prettyjws",
+    DockerManifestSchema2 => "application/vnd.docker.distribution.manifest.v2+json",
+    DockerManifestList => "application/vnd.docker.distribution.manifest.list.v2+json",
+    DockerLayer => "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    DockerConfigJSON => "application/vnd.docker.container.image.v1+json",
+    DockerPluginConfig => "application/vnd.docker.plugin.v1+json",
+    DockerForeignLayer => "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    DockerUncompressedLayer => "application/vnd.docker.image.rootfs.diff.tar",
+}
 
-    fn try_from(s: &str) -> Result<Self, Self::Error> {
-        Self::deserialize(s.into_deserializer())
+impl Serialize for MediaType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for MediaType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(MediaType::from(s.as_str()))
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
 impl Media for MediaType {
     fn is_distributable(&self) -> bool {
-        match *self {
-            MediaType::DockerForeignLayer => true,
-            MediaType::OCIUncompressedRestrictedLayer => true,
-            MediaType::OCIRestrictedLayer => true,
-            _ => false,
-        }
+        !matches!(
+            *self,
+            MediaType::DockerForeignLayer
+                | MediaType::OCIUncompressedRestrictedLayer
+                | MediaType::OCIRestrictedLayer
+        )
     }
 
     fn is_image(&self) -> bool {
-        match *self {
-            MediaType::DockerManifestSchema1 => true,
-            MediaType::DockerManifestSchema2 => true,
-            _ => false,
-        }
+        matches!(
+            *self,
+            MediaType::DockerManifestSchema1 | MediaType::DockerManifestSchema2
+        )
     }
 
     fn is_index(&self) -> bool {
-        match *self {
-            MediaType::DockerManifestList => true,
-            MediaType::OCIImageIndex => true,
-            _ => false,
-        }
+        matches!(
+            *self,
+            MediaType::DockerManifestList | MediaType::OCIImageIndex
+        )
     }
 }